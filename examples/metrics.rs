@@ -7,20 +7,24 @@ use opentelemetry_sdk::{
 use trillium_opentelemetry::Metrics;
 use trillium_router::{router, RouterConnExt};
 
-fn set_up_collector() {
+fn set_up_collector() -> SdkMeterProvider {
     let exporter = MetricExporter::builder().with_tonic().build().unwrap();
     let reader = PeriodicReader::builder(exporter, Tokio).build();
     let meter_provider = SdkMeterProvider::builder().with_reader(reader).build();
-    set_meter_provider(meter_provider);
+    set_meter_provider(meter_provider.clone());
+    meter_provider
 }
 
 #[tokio::main]
 pub async fn main() {
-    set_up_collector();
+    let meter_provider = set_up_collector();
 
     trillium_tokio::run_async((
         Metrics::new("example-app").with_route(|conn| conn.route().map(|r| r.to_string().into())),
         router().get("/some/:path", "ok"),
     ))
     .await;
+
+    // the periodic reader only exports on an interval, so flush the final batch
+    meter_provider.shutdown().unwrap();
 }
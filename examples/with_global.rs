@@ -20,7 +20,7 @@ pub async fn main() {
     let exporter = MetricExporter::builder().with_tonic().build().unwrap();
     let reader = PeriodicReader::builder(exporter, Tokio).build();
     let meter_provider = SdkMeterProvider::builder().with_reader(reader).build();
-    set_meter_provider(meter_provider);
+    set_meter_provider(meter_provider.clone());
 
     let exporter = SpanExporter::builder().with_tonic().build().unwrap();
     let tracer_provider = TracerProvider::builder()
@@ -30,7 +30,7 @@ pub async fn main() {
         )]))
         .with_batch_exporter(exporter, Tokio)
         .build();
-    set_tracer_provider(tracer_provider);
+    set_tracer_provider(tracer_provider.clone());
 
     trillium_tokio::run_async((
         instrument()
@@ -43,4 +43,9 @@ pub async fn main() {
         ),
     ))
     .await;
+
+    // the batch exporter and periodic reader only export on an interval, so flush the final batch.
+    // the handlers hold a tracer, so replacing the global tracer provider would not flush it
+    tracer_provider.shutdown().unwrap();
+    meter_provider.shutdown().unwrap();
 }
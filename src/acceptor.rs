@@ -0,0 +1,46 @@
+use opentelemetry::metrics::{Counter, Meter};
+use trillium_server_common::{async_trait, Acceptor, Transport};
+
+/// A tls [`Acceptor`] wrapper that counts failed handshakes as `tls.handshake.errors`.
+///
+/// Connections that fail the tls handshake never become a [`Conn`](trillium::Conn), so they are
+/// not visible to the [`Metrics`](crate::Metrics) handler. Wrap the server's tls acceptor with this
+/// and pass it to the server config's `with_acceptor` to count them. Error details are not
+/// recorded, as they are not guaranteed to be low-cardinality.
+#[derive(Clone, Debug)]
+pub struct InstrumentAcceptor<A> {
+    acceptor: A,
+    handshake_errors: Counter<u64>,
+}
+
+impl<A> InstrumentAcceptor<A> {
+    /// Wraps the provided acceptor, recording handshake errors to the provided [`Meter`]
+    pub fn new(acceptor: A, meter: &Meter) -> Self {
+        Self {
+            acceptor,
+            handshake_errors: meter
+                .u64_counter("tls.handshake.errors")
+                .with_description("Counts inbound connections that failed the tls handshake.")
+                .with_unit("{connection}")
+                .build(),
+        }
+    }
+}
+
+#[async_trait]
+impl<A, Input> Acceptor<Input> for InstrumentAcceptor<A>
+where
+    A: Acceptor<Input>,
+    Input: Transport,
+{
+    type Output = A::Output;
+    type Error = A::Error;
+
+    async fn accept(&self, input: Input) -> Result<Self::Output, Self::Error> {
+        let result = self.acceptor.accept(input).await;
+        if result.is_err() {
+            self.handshake_errors.add(1, &[]);
+        }
+        result
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+/// The cpu time consumed by this process so far, summed across user and system time, or `None`
+/// on platforms where it is unavailable.
+///
+/// This is a blocking read of `/proc/self/stat`, which is served from memory by the kernel and
+/// does not touch a disk, so it is not moved off of the async executor.
+pub(crate) fn process_cpu_time() -> Option<Duration> {
+    // the kernel reports these in USER_HZ, which is 100 on each of the architectures below.
+    // reading it with sysconf would need unsafe code
+    const TICKS_PER_SECOND: u64 = 100;
+
+    // the file is read on every platform where this compiles, so unsupported platforms are
+    // excluded here rather than with a second, negated cfg attribute
+    if !cfg!(all(
+        target_os = "linux",
+        any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv32",
+            target_arch = "riscv64",
+            target_arch = "powerpc",
+            target_arch = "powerpc64",
+            target_arch = "s390x",
+            target_arch = "mips",
+            target_arch = "mips64",
+            target_arch = "loongarch64",
+            target_arch = "sparc64",
+        )
+    )) {
+        return None;
+    }
+
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // the executable name is parenthesized and may contain spaces, so fields are counted from
+    // the closing paren: utime and stime are the 14th and 15th fields
+    let mut fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_millis(
+        (utime + stime) * 1000 / TICKS_PER_SECOND,
+    ))
+}
@@ -0,0 +1,14 @@
+use std::borrow::Cow;
+use trillium::{Conn, Status};
+
+/// the error type registered for the response status of this conn, if any
+pub(crate) fn status_error_type(
+    conn: &Conn,
+    status_error_types: &[(Status, &'static str)],
+) -> Option<Cow<'static, str>> {
+    let status = conn.status()?;
+    status_error_types
+        .iter()
+        .find(|(s, _)| *s == status)
+        .map(|(_, error_type)| Cow::Borrowed(*error_type))
+}
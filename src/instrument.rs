@@ -1,17 +1,25 @@
-use crate::{Metrics, Trace};
+use crate::{
+    metrics::versioned_meter, DurationUnit, Metrics, Observation, StateAttributes, Trace,
+    UserAgentInfo,
+};
 use opentelemetry::{
     global::{BoxedTracer, ObjectSafeTracer},
-    InstrumentationScope,
+    Key, KeyValue,
+};
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
-use std::{borrow::Cow, sync::Arc};
-use trillium::{Conn, HeaderName};
+use trillium::{Conn, HeaderName, Method, Status};
 use trillium_macros::Handler;
 
 /// a handler to send both traces and metrics in accordances with [semantic conventions for
 /// http](https://opentelemetry.io/docs/specs/semconv/http/).
 ///
-/// This is composed of a [`Trace`] handler and [`Metrics`] handler.
-#[derive(Debug, Handler)]
+/// This is composed of a [`Trace`] handler and [`Metrics`] handler. Clones share the same tracer
+/// and metrics instruments.
+#[derive(Debug, Clone, Handler)]
 pub struct Instrument((Trace<BoxedTracer>, Metrics));
 
 /// construct an [`Instrument`] with the provided meter and tracer
@@ -52,10 +60,21 @@ impl Instrument {
         self
     }
 
+    /// Uses the route matched by trillium-router as the `http.route` for both trace spans and
+    /// metrics. See [`Trace::with_router_route`].
+    #[cfg(feature = "router")]
+    pub fn with_router_route(self) -> Self {
+        use trillium_router::RouterConnExt;
+        self.with_route(|conn| conn.route().map(|r| r.to_string().into()))
+    }
+
     /// Provides an optional low-cardinality error type specification to the metrics collector.
     ///
     /// The implementation of this is application specific, but will often look like checking the
     /// [`Conn::state`] for an error enum and mapping that to a low-cardinality `&'static str`.
+    ///
+    /// As with [`Trace::with_error_type`], this closure is called from `before_send`, so it
+    /// observes error state set by later handlers in `before_send`.
     pub fn with_error_type<F>(mut self, error_type: F) -> Self
     where
         F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
@@ -66,6 +85,125 @@ impl Instrument {
         self
     }
 
+    /// Records the type name of an error state type as the `error.type` on both trace spans and
+    /// metrics. See [`Trace::with_error_type_name`].
+    pub fn with_error_type_name<E: Send + Sync + 'static>(self) -> Self {
+        self.with_error_type(|conn| {
+            conn.state::<E>()
+                .map(|_| Cow::Borrowed(std::any::type_name::<E>()))
+        })
+    }
+
+    /// Records `error_type` as the `error.type` for responses with the given status on both trace
+    /// spans and metrics. See [`Trace::with_status_error_type`].
+    pub fn with_status_error_type(mut self, status: Status, error_type: &'static str) -> Self {
+        self.0 .0 = self.0 .0.with_status_error_type(status, error_type);
+        self.0 .1 = self.0 .1.with_status_error_type(status, error_type);
+        self
+    }
+
+    /// Never record the status code as the `error.type` for server errors on either trace spans or
+    /// metrics. See [`Trace::without_default_error_type`].
+    pub fn without_default_error_type(mut self) -> Self {
+        self.0 .0.disable_default_error_type = true;
+        self.0 .1.disable_default_error_type = true;
+        self
+    }
+
+    /// Overrides the attribute key used for the error type on both trace spans and metrics, which
+    /// is `error.type` by default. See [`Trace::with_error_type_key`].
+    pub fn with_error_type_key(mut self, error_type_key: &'static str) -> Self {
+        self.0 .0.error_type_key = error_type_key;
+        self.0 .1.error_type_key = error_type_key;
+        self
+    }
+
+    /// Provides resolved route parameters to include in the trace spans as `url.path.param.{name}`
+    /// attributes.
+    ///
+    /// **WARNING** Path parameters are usually high-cardinality. These are only recorded on spans,
+    /// never on metrics. See [`Trace::with_route_params`].
+    pub fn with_route_params<F>(mut self, route_params: F) -> Self
+    where
+        F: for<'a> Fn(&'a Conn) -> Vec<(Cow<'a, str>, Cow<'a, str>)> + Send + Sync + 'static,
+    {
+        self.0 .0.route_params = Some(Arc::new(route_params));
+        self
+    }
+
+    /// Provides a clock to use for span start and end timestamps instead of [`SystemTime::now`].
+    /// See [`Trace::with_clock`].
+    ///
+    /// Metrics durations are always measured with a monotonic clock and are unaffected by this.
+    pub fn with_clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> SystemTime + Send + Sync + 'static,
+    {
+        self.0 .0.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Include attributes from a [`StateAttributes`] conn state type in the trace spans. See
+    /// [`Trace::with_state_attributes`].
+    pub fn with_state_attributes<S: StateAttributes>(mut self) -> Self {
+        self.0 .0 = self.0 .0.with_state_attributes::<S>();
+        self
+    }
+
+    /// Provides a user agent parser to record low-cardinality `user_agent.name` and
+    /// `user_agent.os.name` attributes on both trace spans and metrics.
+    ///
+    /// The parser must map the client-controlled `User-Agent` header to a bounded set of values.
+    pub fn with_user_agent_parser<F>(mut self, user_agent_parser: F) -> Self
+    where
+        F: Fn(&str) -> UserAgentInfo + Send + Sync + 'static,
+    {
+        let user_agent_parser = Arc::new(user_agent_parser);
+        self.0 .0.user_agent_parser = Some(user_agent_parser.clone());
+        self.0 .1.user_agent_parser = Some(user_agent_parser);
+        self
+    }
+
+    /// Records a boolean attribute in the trace spans, as determined by the provided closure. See
+    /// [`Trace::with_boolean_attribute`].
+    pub fn with_boolean_attribute<F>(mut self, key: impl Into<Key>, attribute: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_boolean_attribute(key, attribute);
+        self
+    }
+
+    /// Provides a predicate to skip both tracing and metrics for some requests.
+    ///
+    /// When the predicate returns true for a conn, no span is created and no metrics are recorded
+    /// for that request. This is the same as applying the predicate to both
+    /// [`Trace::with_filter`] and [`Metrics::with_filter`].
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::instrument()
+    ///     .with_filter(|conn| conn.path() == "/health");
+    /// ```
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        let filter = Arc::new(filter);
+        self.0 .0.filter = Some(filter.clone());
+        self.0 .1.filter = Some(filter);
+        self
+    }
+
+    /// Provides a predicate that detects requests that exceeded a server-imposed timeout. See
+    /// [`Trace::with_timeout_detection`].
+    pub fn with_timeout_detection<F>(mut self, timeout: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_timeout_detection(timeout);
+        self
+    }
+
     /// Provides a callback for `server.address` and `server.port` attributes to be used in metrics
     /// attributes. This has no effect on tracing span attributes, where `server.address` and
     /// `server.port` are always enabled.
@@ -86,6 +224,96 @@ impl Instrument {
         self
     }
 
+    /// Provides a callback to segment metrics by virtual host, recorded as the `server.address`
+    /// metrics attribute. This has no effect on tracing span attributes.
+    ///
+    /// The callback should collapse unknown hosts into a sentinel value to cap cardinality. See
+    /// [`Metrics::with_host_dimension`].
+    pub fn with_metrics_host_dimension<F>(mut self, host: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.0 .1.host = Some(Arc::new(host));
+        self
+    }
+
+    /// Enable the `http.server.not_found` counter for requests that did not match a route. See
+    /// [`Metrics::with_not_found_counter`].
+    pub fn with_metrics_not_found_counter(mut self) -> Self {
+        self.0 .1 = self.0 .1.with_not_found_counter();
+        self
+    }
+
+    /// Enable the `http.server.request.count` counter. See [`Metrics::with_request_counter`].
+    pub fn with_metrics_request_counter(mut self) -> Self {
+        self.0 .1 = self.0 .1.with_request_counter();
+        self
+    }
+
+    /// Set the unit of the `http.server.request.duration` histogram. See
+    /// [`Metrics::with_duration_unit`].
+    pub fn with_metrics_duration_unit(mut self, duration_unit: DurationUnit) -> Self {
+        self.0 .1 = self.0 .1.with_duration_unit(duration_unit);
+        self
+    }
+
+    /// Build the metrics instruments and record zero-value data points when the server starts.
+    /// See [`Metrics::register_eagerly`].
+    pub fn register_metrics_eagerly(mut self) -> Self {
+        self.0 .1 = self.0 .1.register_eagerly();
+        self
+    }
+
+    /// Enable the `http.server.response.start.duration` time to first byte histogram. See
+    /// [`Metrics::with_ttfb_histogram`].
+    pub fn with_metrics_ttfb_histogram(mut self) -> Self {
+        self.0 .1 = self.0 .1.with_ttfb_histogram();
+        self
+    }
+
+    /// Enable the `trillium.handler.start.duration` histogram. See
+    /// [`Metrics::with_handler_start_duration`].
+    pub fn with_metrics_handler_start_duration(mut self) -> Self {
+        self.0 .1 = self.0 .1.with_handler_start_duration();
+        self
+    }
+
+    /// Copies the named attributes from the provided resource onto every metrics observation. See
+    /// [`Metrics::with_resource_attributes_as_dimensions`].
+    #[cfg(feature = "sdk")]
+    pub fn with_metrics_resource_attributes_as_dimensions(
+        mut self,
+        resource: &opentelemetry_sdk::Resource,
+        keys: impl IntoIterator<Item = &'static str>,
+    ) -> Self {
+        self.0 .1 = self
+            .0
+             .1
+            .with_resource_attributes_as_dimensions(resource, keys);
+        self
+    }
+
+    /// Omit the `network.protocol.version` attribute from metrics, while still recording it on the
+    /// trace spans.
+    pub fn without_metrics_protocol_version(mut self) -> Self {
+        self.0 .1.disable_protocol_version = true;
+        self
+    }
+
+    /// Disable the `http.server.request.body.size` histogram. See
+    /// [`Metrics::without_request_size_histogram`].
+    pub fn without_metrics_request_size_histogram(mut self) -> Self {
+        self.0 .1 = self.0 .1.without_request_size_histogram();
+        self
+    }
+
+    /// Disable the `http.server.response.body.size` histogram. See
+    /// [`Metrics::without_response_size_histogram`].
+    pub fn without_metrics_response_size_histogram(mut self) -> Self {
+        self.0 .1 = self.0 .1.without_response_size_histogram();
+        self
+    }
+
     /// Specify a list of request headers to include in the trace spans
     pub fn with_headers(
         mut self,
@@ -95,6 +323,231 @@ impl Instrument {
         self
     }
 
+    /// Provides an internally generated request id to record as the `trillium.request.id` span
+    /// attribute. See [`Trace::with_request_id`].
+    pub fn with_request_id<F>(mut self, request_id: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_request_id(request_id);
+        self
+    }
+
+    /// Record the value of a correlation id request header as the `trillium.correlation_id` span
+    /// attribute. See [`Trace::with_correlation_id_header`].
+    pub fn with_correlation_id_header(mut self, header: impl Into<HeaderName<'static>>) -> Self {
+        self.0 .0.correlation_id_header = Some(header.into());
+        self
+    }
+
+    /// Build request spans in an empty context instead of the current context. See
+    /// [`Trace::with_root_context`].
+    pub fn with_root_context(mut self) -> Self {
+        self.0 .0.enable_root_context = true;
+        self
+    }
+
+    /// Limit the number of attributes recorded on each request span. See
+    /// [`Trace::with_max_attributes`].
+    pub fn with_max_attributes(mut self, max_attributes: usize) -> Self {
+        self.0 .0.max_attributes = Some(max_attributes);
+        self
+    }
+
+    /// Record request span attributes sorted by key. See [`Trace::with_sorted_attributes`].
+    pub fn with_sorted_attributes(mut self) -> Self {
+        self.0 .0.enable_sorted_attributes = true;
+        self
+    }
+
+    /// Continue the trace identified by a request header containing only a hex trace id. See
+    /// [`Trace::with_parent_trace_id_header`].
+    pub fn with_parent_trace_id_header(mut self, header: impl Into<HeaderName<'static>>) -> Self {
+        self.0 .0.parent_trace_id_header = Some(header.into());
+        self
+    }
+
+    /// Generate a correlation id for requests that don't have one, and echo the correlation id in
+    /// the response headers. See [`Trace::with_correlation_id_generation`].
+    pub fn with_correlation_id_generation(mut self) -> Self {
+        self.0 .0.enable_correlation_id_generation = true;
+        self
+    }
+
+    /// Provides the span name for requests that have no route. See
+    /// [`Trace::with_fallback_span_name`].
+    pub fn with_fallback_span_name<F>(mut self, fallback_span_name: F) -> Self
+    where
+        F: Fn(&Conn) -> Cow<'static, str> + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_fallback_span_name(fallback_span_name);
+        self
+    }
+
+    /// Provides an operation name to use verbatim as the span name. See
+    /// [`Trace::with_operation_name`].
+    pub fn with_operation_name<F>(mut self, operation_name: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_operation_name(operation_name);
+        self
+    }
+
+    /// Provides a sample ratio for each resolved route. See [`Trace::with_route_sampling`].
+    pub fn with_route_sampling<F>(mut self, route_sampling: F) -> Self
+    where
+        F: Fn(&str) -> Option<f64> + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_route_sampling(route_sampling);
+        self
+    }
+
+    /// Provides the scheme of the original request, for servers behind a proxy that terminates
+    /// tls, recorded as `url.scheme` on both trace spans and metrics. See [`Trace::with_scheme`].
+    pub fn with_scheme<F>(mut self, scheme: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<&'static str> + Send + Sync + 'static,
+    {
+        let scheme = Arc::new(scheme);
+        self.0 .0.scheme = Some(scheme.clone());
+        self.0 .1.scheme = Some(scheme);
+        self
+    }
+
+    /// Provides a description for the error span status of server error responses. See
+    /// [`Trace::with_error_description`].
+    pub fn with_error_description<F>(mut self, error_description: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_error_description(error_description);
+        self
+    }
+
+    /// Provides the source of the `client.address` span attribute. See
+    /// [`Trace::with_client_address`].
+    pub fn with_client_address<F>(mut self, client_address: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.0 .0 = self.0 .0.with_client_address(client_address);
+        self
+    }
+
+    /// Record whether the request span was sampled as the boolean `trillium.trace.sampled` metrics
+    /// attribute.
+    ///
+    /// This makes it possible to compare trace coverage with request volume. Requests with no
+    /// request span, such as those that are not yet sampled with [`Trace::with_tail_sampling`],
+    /// are recorded as not sampled.
+    pub fn with_metrics_sampled_dimension(mut self) -> Self {
+        self.0 .1.enable_sampled_dimension = true;
+        self
+    }
+
+    /// Provides a low-cardinality logical module name recorded as the `code.namespace` metrics
+    /// attribute. See [`Metrics::with_module`].
+    pub fn with_metrics_module<F>(mut self, module: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.0 .1 = self.0 .1.with_module(module);
+        self
+    }
+
+    /// Caps the number of distinct values recorded for a metrics attribute. See
+    /// [`Metrics::with_cardinality_limit`].
+    pub fn with_metrics_cardinality_limit(mut self, key: impl Into<Key>, limit: usize) -> Self {
+        self.0 .1 = self.0 .1.with_cardinality_limit(key, limit);
+        self
+    }
+
+    /// Enable recording whether each request reads or writes as the `http.request.method_class`
+    /// metrics attribute. See [`Metrics::with_method_class_dimension`].
+    pub fn with_metrics_method_class_dimension(mut self) -> Self {
+        self.0 .1.enable_method_class = true;
+        self
+    }
+
+    /// Enable recording the primary `Accept-Language` as the `trillium.locale` metrics attribute.
+    /// See [`Metrics::with_locale_dimension`].
+    pub fn with_metrics_locale_dimension(mut self) -> Self {
+        self.0 .1.enable_locale = true;
+        self
+    }
+
+    /// Enable recording the request media type as the `trillium.request.content_type` metrics
+    /// attribute. See [`Metrics::with_request_content_type`].
+    pub fn with_metrics_request_content_type(mut self) -> Self {
+        self.0 .1.enable_request_content_type = true;
+        self
+    }
+
+    /// Provides a low-cardinality api version recorded as the `http.api.version` metrics attribute.
+    /// See [`Metrics::with_api_version`].
+    pub fn with_metrics_api_version<F>(mut self, api_version: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.0 .1 = self.0 .1.with_api_version(api_version);
+        self
+    }
+
+    /// Provides additional metrics attributes for each request. See
+    /// [`Metrics::with_extra_attributes`].
+    pub fn with_metrics_extra_attributes<F>(mut self, extra_attributes: F) -> Self
+    where
+        F: Fn(&Conn) -> Vec<KeyValue> + Send + Sync + 'static,
+    {
+        self.0 .1 = self.0 .1.with_extra_attributes(extra_attributes);
+        self
+    }
+
+    /// Provides a predicate to record only the request method and response status in the metrics
+    /// for some requests. See [`Metrics::with_minimal_attributes_for`].
+    pub fn with_metrics_minimal_attributes_for<F>(mut self, minimal_attributes: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.0 .1 = self.0 .1.with_minimal_attributes_for(minimal_attributes);
+        self
+    }
+
+    /// Only record metrics for requests with one of the provided methods. Trace spans are recorded
+    /// for every method. See [`Metrics::with_methods`].
+    pub fn with_metrics_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.0 .1 = self.0 .1.with_methods(methods);
+        self
+    }
+
+    /// Provides the request and response body sizes to record in the body size histograms. See
+    /// [`Metrics::with_body_sizes`].
+    pub fn with_metrics_body_sizes<F>(mut self, body_sizes: F) -> Self
+    where
+        F: Fn(&Conn) -> (Option<u64>, Option<u64>) + Send + Sync + 'static,
+    {
+        self.0 .1 = self.0 .1.with_body_sizes(body_sizes);
+        self
+    }
+
+    /// Provides an observer that is called with every metrics measurement as it is recorded. See
+    /// [`Metrics::with_observer`].
+    pub fn with_metrics_observer<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&Observation<'_>) + Send + Sync + 'static,
+    {
+        self.0 .1 = self.0 .1.with_observer(observer);
+        self
+    }
+
+    /// Enable recording the size of streaming response bodies of unknown length. See
+    /// [`Metrics::with_counted_response_body`].
+    pub fn with_metrics_counted_response_body(mut self) -> Self {
+        self.0 .1.enable_counted_response_body = true;
+        self
+    }
+
     /// Enable population of the local socket address and port in the trace spans.
     ///
     /// This populates the `network.local.address` and `network.local.port` attributes.
@@ -102,6 +555,136 @@ impl Instrument {
         self.0 .0.enable_local_address_and_port = true;
         self
     }
+
+    /// Never record the `url.query` attribute in the trace spans. See [`Trace::without_url_query`].
+    pub fn without_url_query(mut self) -> Self {
+        self.0 .0.disable_url_query = true;
+        self
+    }
+
+    /// Enable recording the elapsed handler time as the `http.server.duration_ms` span attribute.
+    /// See [`Trace::with_duration_attribute`].
+    pub fn with_duration_attribute(mut self) -> Self {
+        self.0 .0.enable_duration_attribute = true;
+        self
+    }
+
+    /// Round the `http.server.duration_ms` span attribute to the nearest multiple of
+    /// `granularity`. See [`Trace::with_duration_attribute_granularity`].
+    pub fn with_duration_attribute_granularity(mut self, granularity: Duration) -> Self {
+        self.0 .0 = self.0 .0.with_duration_attribute_granularity(granularity);
+        self
+    }
+
+    /// Record the negotiated protocol, such as `websocket` after an upgrade, as
+    /// `network.protocol.name` on both trace spans and metrics. See
+    /// [`Trace::with_upgrade_protocol_name`].
+    pub fn with_upgrade_protocol_name(mut self) -> Self {
+        self.0 .0.enable_upgrade_protocol_name = true;
+        self.0 .1.enable_upgrade_protocol_name = true;
+        self
+    }
+
+    /// Enable recording the protocols requested in the `Upgrade` request header as the
+    /// `http.request.header.upgrade` span attribute. See [`Trace::with_upgrade_header`].
+    pub fn with_upgrade_header(mut self) -> Self {
+        self.0 .0.enable_upgrade_header = true;
+        self
+    }
+
+    /// Enable recording the scheme of the `Authorization` request header as the
+    /// `http.request.auth.scheme` span attribute. See [`Trace::with_auth_scheme`].
+    pub fn with_auth_scheme(mut self) -> Self {
+        self.0 .0.enable_auth_scheme = true;
+        self
+    }
+
+    /// Enable recording the number of response headers and their total size as span attributes.
+    /// See [`Trace::with_response_header_stats`].
+    pub fn with_response_header_stats(mut self) -> Self {
+        self.0 .0.enable_response_header_stats = true;
+        self
+    }
+
+    /// Enable recording which listener received the request as the `trillium.listener` attribute on
+    /// both trace spans and metrics. See [`Trace::with_listener`].
+    pub fn with_listener(mut self) -> Self {
+        self.0 .0.enable_listener = true;
+        self.0 .1.enable_listener = true;
+        self
+    }
+
+    /// Add a span event when the route was only resolved in `before_send`. See
+    /// [`Trace::with_late_route_event`].
+    pub fn with_late_route_event(mut self) -> Self {
+        self.0 .0.enable_late_route_event = true;
+        self
+    }
+
+    /// Only export spans for requests that are slow or fail. See [`Trace::with_tail_sampling`].
+    pub fn with_tail_sampling(mut self, threshold: Duration) -> Self {
+        self.0 .0.tail_sampling_threshold = Some(threshold);
+        self
+    }
+
+    /// Collapse requests under a static file directory into a single metrics `http.route`. See
+    /// [`Metrics::with_static_route`].
+    pub fn with_metrics_static_route(mut self, prefix: impl Into<Cow<'static, str>>) -> Self {
+        self.0 .1 = self.0 .1.with_static_route(prefix);
+        self
+    }
+
+    /// Provides the trace id and span id for each request span. See [`Trace::with_id_generator`].
+    #[cfg(feature = "testing")]
+    pub fn with_id_generator<F>(mut self, id_generator: F) -> Self
+    where
+        F: Fn() -> (opentelemetry::trace::TraceId, opentelemetry::trace::SpanId)
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.0 .0 = self.0 .0.with_id_generator(id_generator);
+        self
+    }
+
+    /// Record the process cpu time consumed while each request was in flight. See
+    /// [`Trace::with_cpu_time`].
+    #[cfg(feature = "cpu-time")]
+    pub fn with_cpu_time(mut self) -> Self {
+        self.0 .0.enable_cpu_time = true;
+        self
+    }
+
+    /// Emit the attributes that Datadog's OpenTelemetry ingestion uses to name spans. See
+    /// [`Trace::datadog_compat`].
+    pub fn datadog_compat(mut self) -> Self {
+        self.0 .0.enable_datadog_compat = true;
+        self
+    }
+
+    /// End the request span when the response is ready to send, instead of after the response body
+    /// has been sent. See [`Trace::end_before_body_send`].
+    pub fn end_before_body_send(mut self) -> Self {
+        self.0 .0.enable_end_before_body_send = true;
+        self
+    }
+
+    /// Emit span names and attributes that match Zipkin's http conventions. See
+    /// [`Trace::zipkin_compat`].
+    pub fn zipkin_compat(mut self) -> Self {
+        self.0 .0.enable_zipkin_compat = true;
+        self
+    }
+
+    /// Enable recording whether redirect responses send the client to another origin in the trace
+    /// spans.
+    ///
+    /// This populates the `http.response.redirect.cross_origin` attribute. See
+    /// [`Trace::with_redirect_cross_origin`].
+    pub fn with_redirect_cross_origin(mut self) -> Self {
+        self.0 .0.enable_redirect_cross_origin = true;
+        self
+    }
 }
 
 /// The primary entrypoint if using [`opentelemetry::global`].
@@ -109,12 +692,7 @@ impl Instrument {
 /// constructs a versioned meter and tracer with the name `"trillium-opentelemetry"`.
 pub fn instrument_global() -> Instrument {
     instrument(
-        opentelemetry::global::meter_provider().meter_with_scope(
-            InstrumentationScope::builder("trillium-opentelemetry")
-                .with_version(env!("CARGO_PKG_VERSION"))
-                .with_schema_url("https://opentelemetry.io/schemas/1.29.0")
-                .build(),
-        ),
+        versioned_meter("trillium-opentelemetry"),
         opentelemetry::global::tracer("trillium-opentelemetry"),
     )
 }
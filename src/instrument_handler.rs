@@ -1,19 +1,26 @@
 use crate::trace::TraceContext;
 use opentelemetry::{
     global::BoxedTracer,
-    trace::{FutureExt, TraceContextExt, Tracer},
-    Context,
+    trace::{FutureExt, SpanBuilder, TraceContextExt, Tracer},
+    Context, KeyValue,
 };
+use std::{panic::Location, sync::Arc};
 use trillium::{async_trait, Conn, Handler, Info, Upgrade};
 
 /// Trillium handler that instruments handlers with spans.
 ///
+/// Each span (or event, in [flat mode](InstrumentHandler::flat_mode)) records how deeply nested
+/// the handler is as `trillium.handler.depth`, starting at 1 for the outermost `InstrumentHandler`.
+///
 /// **IMPORTANT** This handler expects [`crate::Trace`] or [`crate::Instrument`] to have been run on
 /// the conn prior to running this handler.
 #[derive(Debug, Clone)]
 pub struct InstrumentHandler<H, T> {
     handler: H,
     tracer: T,
+    flat: bool,
+    subtree_attributes: Vec<KeyValue>,
+    location: Option<&'static Location<'static>>,
 }
 
 #[async_trait]
@@ -25,29 +32,30 @@ where
 {
     async fn init(&mut self, info: &mut Info) {
         let name = self.handler.name();
+        let span = self.tracer.build(
+            SpanBuilder::from_name(format!("{name}::init")).with_attributes(
+                self.location_attributes()
+                    .chain(self.subtree_attributes.iter().cloned())
+                    .collect::<Vec<_>>(),
+            ),
+        );
         self.handler
             .init(info)
-            .with_context(Context::current_with_span(
-                self.tracer.start(format!("{name}::init")),
-            ))
+            .with_context(Context::current_with_span(span))
             .await
     }
 
     async fn run(&self, mut conn: Conn) -> Conn {
         let name = self.handler.name();
         match conn.take_state() {
-            Some(TraceContext { context }) => {
-                let child = self
-                    .tracer
-                    .start_with_context(format!("{name}::run"), &context);
-                let child_context = Context::current_with_span(child);
+            Some(trace_context) => {
+                let child = self.nested(format!("{name}::run"), &trace_context);
+                let child_context = child.context.clone();
                 self.handler
-                    .run(conn.with_state(TraceContext {
-                        context: child_context.clone(),
-                    }))
+                    .run(conn.with_state(child))
                     .with_context(child_context)
                     .await
-                    .with_state(TraceContext { context })
+                    .with_state(trace_context)
             }
 
             None => self.handler.run(conn).await,
@@ -57,19 +65,14 @@ where
     async fn before_send(&self, mut conn: Conn) -> Conn {
         let name = self.handler.name();
         match conn.take_state() {
-            Some(TraceContext { context }) => {
-                let child = self
-                    .tracer
-                    .start_with_context(format!("{name}::before_send"), &context);
-
-                let child_context = Context::current_with_span(child);
+            Some(trace_context) => {
+                let child = self.nested(format!("{name}::before_send"), &trace_context);
+                let child_context = child.context.clone();
                 self.handler
-                    .before_send(conn.with_state(TraceContext {
-                        context: child_context.clone(),
-                    }))
+                    .before_send(conn.with_state(child))
                     .with_context(child_context)
                     .await
-                    .with_state(TraceContext { context })
+                    .with_state(trace_context)
             }
 
             None => self.handler.before_send(conn).await,
@@ -83,15 +86,10 @@ where
     async fn upgrade(&self, upgrade: Upgrade) {
         let name = self.handler.name();
         match upgrade.state().get() {
-            Some(TraceContext { context }) => {
-                let child = self
-                    .tracer
-                    .start_with_context(format!("{name}::upgrade"), context);
-
-                self.handler
-                    .upgrade(upgrade)
-                    .with_context(Context::current_with_span(child))
-                    .await
+            Some(trace_context) => {
+                let TraceContext { context, .. } =
+                    self.child(format!("{name}::upgrade"), trace_context);
+                self.handler.upgrade(upgrade).with_context(context).await
             }
 
             None => self.handler.upgrade(upgrade).await,
@@ -112,6 +110,32 @@ where
     InstrumentHandler::new(handler, tracer)
 }
 
+/// decorate a handler with a specific tracer, recording the provided source location on its spans
+///
+/// Pass [`Location::caller()`] to record where the handler is mounted. See
+/// [`InstrumentHandler::with_location`].
+///
+/// **IMPORTANT** This handler expects [`crate::Trace`] or [`crate::Instrument`] to have been run on
+/// the conn prior to running this handler.
+///
+/// ```
+/// use std::panic::Location;
+/// let tracer = opentelemetry::global::tracer("example");
+/// trillium_opentelemetry::instrument_handler_at("ok", tracer, Location::caller());
+/// ```
+pub fn instrument_handler_at<H, T>(
+    handler: H,
+    tracer: T,
+    location: &'static Location<'static>,
+) -> InstrumentHandler<H, T>
+where
+    H: Handler,
+    T: Tracer + Send + Sync + 'static,
+    T::Span: Send + Sync + 'static,
+{
+    InstrumentHandler::new(handler, tracer).with_location(location)
+}
+
 impl<H, T> InstrumentHandler<H, T>
 where
     H: Handler,
@@ -123,7 +147,115 @@ where
     /// **IMPORTANT** This handler expects [`crate::Trace`] or [`crate::Instrument`] to have been run on
     /// the conn prior to running this handler.
     pub fn new(handler: H, tracer: T) -> Self {
-        Self { handler, tracer }
+        Self {
+            handler,
+            tracer,
+            flat: false,
+            subtree_attributes: vec![],
+            location: None,
+        }
+    }
+
+    /// Record a source location on every span and event created by this handler as the
+    /// `code.filepath` and `code.lineno` attributes.
+    ///
+    /// This is useful for finding the code that served a request. Handlers don't know where they
+    /// were defined, so pass [`Location::caller()`] from a `#[track_caller]` function or from the
+    /// code that mounts the handler. Unlike [`InstrumentHandler::with_subtree_attributes`], the
+    /// location is not inherited by nested `InstrumentHandler`s.
+    pub fn with_location(mut self, location: &'static Location<'static>) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    fn location_attributes(&self) -> impl Iterator<Item = KeyValue> {
+        self.location.into_iter().flat_map(|location| {
+            [
+                KeyValue::new("code.filepath", location.file()),
+                KeyValue::new("code.lineno", i64::from(location.line())),
+            ]
+        })
+    }
+
+    /// Add attributes to every span created within this handler, including the spans of nested
+    /// `InstrumentHandler`s and operation spans started with
+    /// [`TraceConnExt::new_operation_span`](crate::TraceConnExt::new_operation_span).
+    ///
+    /// This is useful when subtrees of the handler chain represent different logical services,
+    /// such as the routers of a modular monolith. Attributes from nested `InstrumentHandler`s are
+    /// added after those of their parents, except on `{name}::init` spans, which are created before
+    /// any request and only carry the handler's own attributes. These attributes are not added to
+    /// the request span.
+    pub fn with_subtree_attributes(mut self, attributes: Vec<KeyValue>) -> Self {
+        self.subtree_attributes = attributes;
+        self
+    }
+
+    fn inherited_attributes(&self, parent: &TraceContext) -> Arc<[KeyValue]> {
+        if self.subtree_attributes.is_empty() {
+            Arc::clone(&parent.subtree_attributes)
+        } else {
+            parent
+                .subtree_attributes
+                .iter()
+                .chain(&self.subtree_attributes)
+                .cloned()
+                .collect()
+        }
+    }
+
+    /// the trace context for the wrapped handler's `run` or `before_send`. this is a child span of
+    /// the parent, or in flat mode an event on the parent's span, in which case nested handlers
+    /// share the parent's span but are one level deeper and inherit this handler's subtree
+    /// attributes
+    fn nested(&self, name: String, parent: &TraceContext) -> TraceContext {
+        if !self.flat {
+            return self.child(name, parent);
+        }
+
+        let subtree_attributes = self.inherited_attributes(parent);
+        let attributes = std::iter::once(KeyValue::new("trillium.handler.depth", parent.depth + 1))
+            .chain(self.location_attributes())
+            .chain(subtree_attributes.iter().cloned())
+            .collect();
+        parent.context.span().add_event(name, attributes);
+
+        TraceContext {
+            context: parent.context.clone(),
+            depth: parent.depth + 1,
+            subtree_attributes,
+        }
+    }
+
+    fn child(&self, name: String, parent: &TraceContext) -> TraceContext {
+        let depth = parent.depth + 1;
+        let subtree_attributes = self.inherited_attributes(parent);
+        let attributes = std::iter::once(KeyValue::new("trillium.handler.depth", depth))
+            .chain(self.location_attributes())
+            .chain(subtree_attributes.iter().cloned())
+            .collect::<Vec<_>>();
+        let span = self.tracer.build_with_context(
+            SpanBuilder::from_name(name).with_attributes(attributes),
+            &parent.context,
+        );
+
+        TraceContext {
+            context: parent.context.with_span(span),
+            depth,
+            subtree_attributes,
+        }
+    }
+
+    /// Record `{name}::run` and `{name}::before_send` as events on the request span instead of
+    /// creating a child span for each.
+    ///
+    /// This reduces span volume for deeply nested handler stacks. `{name}::init` happens outside of
+    /// any request and `{name}::upgrade` happens after the request span has ended, so those are
+    /// still recorded as spans. Nested `InstrumentHandler`s and operation spans still count this
+    /// handler in their `trillium.handler.depth` and inherit its subtree attributes.
+    pub fn flat_mode(mut self) -> Self {
+        self.flat = true;
+        self
     }
 }
 
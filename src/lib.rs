@@ -11,24 +11,52 @@
 )]
 pub use opentelemetry;
 
+#[cfg(feature = "acceptor")]
+mod acceptor;
 #[cfg(all(feature = "trace", feature = "metrics"))]
 mod instrument;
 #[cfg(feature = "metrics")]
 mod metrics;
+mod noop;
 #[cfg(feature = "trace")]
 mod trace;
 
 #[cfg(feature = "trace")]
 mod instrument_handler;
 
+#[cfg(feature = "views")]
+mod views;
+
+#[cfg(feature = "cpu-time")]
+mod cpu_time;
+#[cfg(any(feature = "trace", feature = "metrics"))]
+mod error_type;
+#[cfg(any(feature = "trace", feature = "metrics"))]
+mod protocol;
+#[cfg(any(feature = "trace", feature = "metrics"))]
+mod state;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(any(feature = "trace", feature = "metrics"))]
+mod user_agent;
+
+#[cfg(feature = "acceptor")]
+pub use acceptor::InstrumentAcceptor;
 #[cfg(all(feature = "trace", feature = "metrics"))]
 pub use instrument::{instrument, Instrument};
 #[cfg(feature = "trace")]
-pub use instrument_handler::{instrument_handler, InstrumentHandler};
+pub use instrument_handler::{instrument_handler, instrument_handler_at, InstrumentHandler};
 #[cfg(feature = "metrics")]
-pub use metrics::{metrics, Metrics};
+pub use metrics::{metrics, DurationUnit, Metrics, MetricsConnExt, Observation};
+pub use noop::NoOp;
+#[cfg(any(feature = "trace", feature = "metrics"))]
+pub use state::FallbackRoute;
 #[cfg(feature = "trace")]
-pub use trace::{trace, Trace};
+pub use trace::{trace, AccessLogFields, SpanStatusOverride, StateAttributes, Trace, TraceConnExt};
+#[cfg(any(feature = "trace", feature = "metrics"))]
+pub use user_agent::UserAgentInfo;
+#[cfg(feature = "views")]
+pub use views::exponential_size_histograms;
 
 /// instrumentation using [`opentelemetry::global`]
 pub mod global {
@@ -48,15 +76,6 @@ pub mod global {
     #[cfg(feature = "metrics")]
     /// configure a [`Metrics`](crate::metrics::Metrics) against the global meter provider
     pub fn metrics() -> super::Metrics {
-        use opentelemetry::InstrumentationScope;
-
-        opentelemetry::global::meter_provider()
-            .meter_with_scope(
-                InstrumentationScope::builder("trillium-opentelemetry")
-                    .with_version(env!("CARGO_PKG_VERSION"))
-                    .with_schema_url("https://opentelemetry.io/schemas/1.29.0")
-                    .build(),
-            )
-            .into()
+        super::Metrics::from("trillium-opentelemetry")
     }
 }
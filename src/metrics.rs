@@ -1,38 +1,104 @@
-use opentelemetry::{
-    global,
-    metrics::{Histogram, Meter},
-    KeyValue,
+#[cfg(feature = "trace")]
+use crate::TraceConnExt;
+use crate::{
+    error_type::status_error_type,
+    protocol::{protocol_name, scheme, SchemeFn},
+    state::resolve_route,
+    user_agent::{self, UserAgentInfo, UserAgentParserFn},
 };
+use cardinality::CardinalityLimit;
+use counted_body::count_response_body;
+use first_byte::time_first_byte;
+use histograms::{ActiveRequest, HistogramConfig, Histograms, Instruments, Measurements};
+use opentelemetry::{global, metrics::Meter, InstrumentationScope, Key, KeyValue};
 use opentelemetry_semantic_conventions as semconv;
 use std::{
     borrow::Cow,
     fmt::{self, Debug, Formatter},
-    sync::Arc,
-    time::Instant,
+    sync::{atomic::Ordering, Arc},
 };
-use trillium::{async_trait, Conn, Handler, KnownHeaderName, Status};
+use trillium::{async_trait, Conn, Handler, Info, KnownHeaderName, Method, Status};
+
+mod cardinality;
+mod counted_body;
+mod first_byte;
+mod histograms;
+mod observation;
+
+pub use observation::Observation;
 
 type StringExtractionFn = dyn Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static;
 type StringAndPortExtractionFn =
     dyn Fn(&Conn) -> Option<(Cow<'static, str>, u16)> + Send + Sync + 'static;
+type PredicateFn = dyn Fn(&Conn) -> bool + Send + Sync + 'static;
+type HistogramSelectorFn = dyn Fn(&Conn) -> Option<&'static str> + Send + Sync + 'static;
+type BodySizesFn = dyn Fn(&Conn) -> (Option<u64>, Option<u64>) + Send + Sync + 'static;
+type ObserverFn = dyn Fn(&Observation<'_>) + Send + Sync + 'static;
+type ExtraAttributesFn = dyn Fn(&Conn) -> Vec<KeyValue> + Send + Sync + 'static;
 
 /// Trillium handler that instruments http.server.request.duration, http.server.request.body.size,
-/// and http.server.response.body.size as per [semantic conventions for http][http-metrics].
+/// http.server.response.body.size and http.server.active_requests as per [semantic conventions for
+/// http][http-metrics].
+///
+/// Instruments are registered with the meter when the handler is initialized, or when the first
+/// request is recorded if the handler is run without being initialized. Clones share the same
+/// instruments.
+///
+/// All durations are measured with a monotonic clock from the time trillium started reading the
+/// request, so they are unaffected by system clock adjustments.
+///
+/// The request body size is read from the `Content-Length` request header and the response body
+/// size is the length of the response body, when known. Requests and responses with a chunked body
+/// of unknown length are not recorded in the body size histograms unless
+/// [`Metrics::with_counted_response_body`] is enabled for responses, or the handler provides the
+/// size with [`MetricsConnExt::record_response_size`]. Responses to `HEAD` requests
+/// and `204` and `304` responses are recorded with a body size of zero, since trillium does not
+/// send their body even if a `Content-Length` is set. Trillium does not currently
+/// support http trailers, so sizes cannot be read from trailers either.
 ///
 /// [http-metrics]: https://opentelemetry.io/docs/specs/semconv/http/http-metrics/
 #[derive(Clone)]
 pub struct Metrics {
     pub(crate) route: Option<Arc<StringExtractionFn>>,
     pub(crate) error_type: Option<Arc<StringExtractionFn>>,
+    pub(crate) error_type_key: &'static str,
+    pub(crate) disable_default_error_type: bool,
+    pub(crate) status_error_types: Vec<(Status, &'static str)>,
+    pub(crate) module: Option<Arc<StringExtractionFn>>,
+    pub(crate) api_version: Option<Arc<StringExtractionFn>>,
+    pub(crate) extra_attributes: Option<Arc<ExtraAttributesFn>>,
     pub(crate) server_address_and_port: Option<Arc<StringAndPortExtractionFn>>,
-    duration_histogram: Histogram<f64>,
-    request_size_histogram: Histogram<u64>,
-    response_size_histogram: Histogram<u64>,
+    pub(crate) filter: Option<Arc<PredicateFn>>,
+    pub(crate) methods: Option<Vec<Method>>,
+    pub(crate) minimal_attributes: Option<Arc<PredicateFn>>,
+    pub(crate) host: Option<Arc<StringExtractionFn>>,
+    pub(crate) scheme: Option<Arc<SchemeFn>>,
+    pub(crate) user_agent_parser: Option<Arc<UserAgentParserFn>>,
+    pub(crate) disable_protocol_version: bool,
+    pub(crate) enable_listener: bool,
+    pub(crate) enable_method_class: bool,
+    pub(crate) enable_locale: bool,
+    pub(crate) enable_request_content_type: bool,
+    pub(crate) enable_counted_response_body: bool,
+    pub(crate) enable_upgrade_protocol_name: bool,
+    #[cfg(feature = "trace")]
+    pub(crate) enable_sampled_dimension: bool,
+    pub(crate) static_routes: Vec<(Cow<'static, str>, Cow<'static, str>)>,
+    resource_attributes: Vec<KeyValue>,
+    cardinality_limits: Vec<CardinalityLimit>,
+    histogram_selector: Option<Arc<HistogramSelectorFn>>,
+    observer: Option<Arc<ObserverFn>>,
+    body_sizes: Option<Arc<BodySizesFn>>,
+    meter: Meter,
+    histogram_config: HistogramConfig,
+    histograms: Histograms,
+    listener: Option<String>,
 }
 
 impl Debug for Metrics {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Metrics")
+        let mut debug_struct = f.debug_struct("Metrics");
+        debug_struct
             .field(
                 "route",
                 &match self.route {
@@ -47,9 +113,125 @@ impl Debug for Metrics {
                     _ => "None",
                 },
             )
-            .field("duration_histogram", &self.duration_histogram)
-            .field("request_size_histogram", &self.request_size_histogram)
-            .field("response_size_histogram", &self.response_size_histogram)
+            .field("error_type_key", &self.error_type_key)
+            .field(
+                "disable_default_error_type",
+                &self.disable_default_error_type,
+            )
+            .field("status_error_types", &self.status_error_types)
+            .field(
+                "module",
+                &match self.module {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "api_version",
+                &match self.api_version {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "extra_attributes",
+                &match self.extra_attributes {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "server_address_and_port",
+                &match self.server_address_and_port {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "filter",
+                &match self.filter {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field("methods", &self.methods)
+            .field(
+                "minimal_attributes",
+                &match self.minimal_attributes {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "host",
+                &match self.host {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "scheme",
+                &match self.scheme {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "user_agent_parser",
+                &match self.user_agent_parser {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field("disable_protocol_version", &self.disable_protocol_version)
+            .field("enable_listener", &self.enable_listener)
+            .field("enable_method_class", &self.enable_method_class)
+            .field("enable_locale", &self.enable_locale)
+            .field(
+                "enable_request_content_type",
+                &self.enable_request_content_type,
+            )
+            .field(
+                "enable_counted_response_body",
+                &self.enable_counted_response_body,
+            )
+            .field(
+                "enable_upgrade_protocol_name",
+                &self.enable_upgrade_protocol_name,
+            );
+
+        #[cfg(feature = "trace")]
+        debug_struct.field("enable_sampled_dimension", &self.enable_sampled_dimension);
+
+        debug_struct
+            .field("static_routes", &self.static_routes)
+            .field("resource_attributes", &self.resource_attributes)
+            .field("cardinality_limits", &self.cardinality_limits)
+            .field(
+                "histogram_selector",
+                &match self.histogram_selector {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "observer",
+                &match self.observer {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "body_sizes",
+                &match self.body_sizes {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field("meter", &"..")
+            .field("histogram_config", &self.histogram_config)
+            .field("histograms", &self.histograms)
+            .field("listener", &self.listener)
             .finish()
     }
 }
@@ -61,9 +243,25 @@ pub fn metrics(meter: impl Into<Metrics>) -> Metrics {
     meter.into()
 }
 
+/// an instrumentation scope with the provided name, and with this crate's version and schema url
+pub(crate) fn versioned_scope(name: &'static str) -> InstrumentationScope {
+    InstrumentationScope::builder(name)
+        .with_version(env!("CARGO_PKG_VERSION"))
+        .with_schema_url("https://opentelemetry.io/schemas/1.29.0")
+        .build()
+}
+
+/// a meter from the global meter provider with the provided name, and with this crate's version
+/// and schema url
+pub(crate) fn versioned_meter(name: &'static str) -> Meter {
+    global::meter_provider().meter_with_scope(versioned_scope(name))
+}
+
+/// Uses a meter from the global meter provider with the provided name, which carries this crate's
+/// version and schema url
 impl From<&'static str> for Metrics {
     fn from(value: &'static str) -> Self {
-        global::meter(value).into()
+        versioned_meter(value).into()
     }
 }
 
@@ -77,25 +275,39 @@ impl From<&Meter> for Metrics {
     fn from(meter: &Meter) -> Self {
         Self {
             route: None,
-            duration_histogram: meter
-                .f64_histogram(semconv::metric::HTTP_SERVER_REQUEST_DURATION)
-                .with_description("Measures the duration of inbound HTTP requests.")
-                .with_unit("s")
-                .build(),
-
-            request_size_histogram: meter
-                .u64_histogram(semconv::metric::HTTP_SERVER_REQUEST_BODY_SIZE)
-                .with_description("Measures the size of HTTP request messages (compressed).")
-                .with_unit("By")
-                .build(),
-
-            response_size_histogram: meter
-                .u64_histogram(semconv::metric::HTTP_SERVER_RESPONSE_BODY_SIZE)
-                .with_description("Measures the size of HTTP response messages (compressed).")
-                .with_unit("By")
-                .build(),
+            meter: meter.clone(),
             error_type: None,
+            error_type_key: semconv::attribute::ERROR_TYPE,
+            disable_default_error_type: false,
+            status_error_types: vec![],
+            module: None,
+            api_version: None,
+            extra_attributes: None,
             server_address_and_port: None,
+            filter: None,
+            methods: None,
+            minimal_attributes: None,
+            host: None,
+            scheme: None,
+            user_agent_parser: None,
+            disable_protocol_version: false,
+            enable_listener: false,
+            enable_method_class: false,
+            enable_locale: false,
+            enable_request_content_type: false,
+            enable_counted_response_body: false,
+            enable_upgrade_protocol_name: false,
+            #[cfg(feature = "trace")]
+            enable_sampled_dimension: false,
+            static_routes: vec![],
+            resource_attributes: vec![],
+            cardinality_limits: vec![],
+            histogram_selector: None,
+            observer: None,
+            body_sizes: None,
+            histogram_config: HistogramConfig::default(),
+            histograms: Histograms::default(),
+            listener: None,
         }
     }
 }
@@ -111,6 +323,9 @@ impl Metrics {
     /// in order to avoid forcing anyone to use a particular router, this is provided as a
     /// configuration hook.
     ///
+    /// if a trace handler has already resolved a route for the current request, that route is
+    /// reused so that the `http.route` on metrics always matches the span.
+    ///
     /// for use with [`trillium-router`](https://docs.trillium.rs/trillium_router/index.html),
     /// ```
     /// use trillium_router::RouterConnExt;
@@ -125,10 +340,24 @@ impl Metrics {
         self
     }
 
+    /// provides the route matched by
+    /// [`trillium-router`](https://docs.trillium.rs/trillium_router/index.html) to the metrics
+    /// collector, without a closure. requires the `router` feature.
+    #[cfg(feature = "router")]
+    pub fn with_router_route(self) -> Self {
+        use trillium_router::RouterConnExt;
+        self.with_route(|conn| conn.route().map(|r| r.to_string().into()))
+    }
+
     /// Provides an optional low-cardinality error type specification to the metrics collector.
     ///
     /// The implementation of this is application specific, but will often look like checking the
     /// [`Conn::state`] for an error enum and mapping that to a low-cardinality `&'static str`.
+    ///
+    /// As with [`Trace::with_error_type`][trace-error-type], this closure is called from
+    /// `before_send`, so it observes error state set by later handlers in `before_send`.
+    ///
+    /// [trace-error-type]: https://docs.rs/trillium-opentelemetry/latest/trillium_opentelemetry/struct.Trace.html#method.with_error_type
     pub fn with_error_type<F>(mut self, error_type: F) -> Self
     where
         F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
@@ -137,6 +366,109 @@ impl Metrics {
         self
     }
 
+    /// records `error_type` as the `error.type` for responses with the given status, when
+    /// [`Metrics::with_error_type`] does not provide one.
+    ///
+    /// this is useful for client errors that should be counted as failures, such as the `413
+    /// Payload Too Large` that body size limits respond with. it can be called more than once for
+    /// several statuses.
+    pub fn with_status_error_type(mut self, status: Status, error_type: &'static str) -> Self {
+        self.status_error_types.push((status, error_type));
+        self
+    }
+
+    /// never record the status code as the `error.type` for server errors.
+    ///
+    /// by default, when [`Metrics::with_error_type`] does not provide an error type for a response
+    /// with a 5xx status, the numeric status is recorded as the `error.type`. with this set, only
+    /// the error type closure provides `error.type`.
+    pub fn without_default_error_type(mut self) -> Self {
+        self.disable_default_error_type = true;
+        self
+    }
+
+    /// Overrides the attribute key used for the error type, which is `error.type` by default.
+    ///
+    /// This is intended for migrations to backends that expect a namespaced variant.
+    pub fn with_error_type_key(mut self, error_type_key: &'static str) -> Self {
+        self.error_type_key = error_type_key;
+        self
+    }
+
+    /// Provides an optional low-cardinality logical module name, such as `billing` or `auth`, to
+    /// the metrics collector. This is recorded as the `code.namespace` attribute.
+    ///
+    /// The module is usually derived from the route or from state that the matched handler sets,
+    /// and this closure is called from [`Handler::before_send`](trillium::Handler::before_send)
+    /// so that such state is available.
+    ///
+    /// ```
+    /// trillium_opentelemetry::Metrics::new(&opentelemetry::global::meter("example"))
+    ///     .with_module(|conn| {
+    ///         let module = conn.path().trim_start_matches('/').split('/').next()?;
+    ///         match module {
+    ///             "billing" | "auth" => Some(module.to_string().into()),
+    ///             _ => None,
+    ///         }
+    ///     });
+    /// ```
+    pub fn with_module<F>(mut self, module: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.module = Some(Arc::new(module));
+        self
+    }
+
+    /// Provides an optional low-cardinality api version, such as `v1`, to the metrics collector.
+    /// This is recorded as the `http.api.version` attribute.
+    ///
+    /// ```
+    /// trillium_opentelemetry::Metrics::new(&opentelemetry::global::meter("example"))
+    ///     .with_api_version(|conn| match conn.path().split('/').nth(1)? {
+    ///         version @ ("v1" | "v2") => Some(version.to_string().into()),
+    ///         _ => None,
+    ///     });
+    /// ```
+    pub fn with_api_version<F>(mut self, api_version: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.api_version = Some(Arc::new(api_version));
+        self
+    }
+
+    /// Provides additional attributes to the metrics collector, such as a tenant stored in the
+    /// conn state by an earlier handler.
+    ///
+    /// This is called once per request when the response is sent, and the returned attributes are
+    /// recorded on every response measurement for that request.
+    ///
+    /// Every distinct combination of attribute values is a separate time series in every
+    /// histogram, so only return attributes with a small, bounded set of values. Never return
+    /// user ids, paths, or other request-controlled values, which can exhaust the memory of the
+    /// meter provider and the metrics backend. [`Metrics::with_cardinality_limit`] can bound an
+    /// attribute that might be unexpectedly diverse.
+    ///
+    /// ```
+    /// use trillium_opentelemetry::opentelemetry::KeyValue;
+    /// # struct Tenant(&'static str);
+    ///
+    /// trillium_opentelemetry::Metrics::new(&opentelemetry::global::meter("example"))
+    ///     .with_extra_attributes(|conn| {
+    ///         conn.state::<Tenant>()
+    ///             .map(|tenant| vec![KeyValue::new("app.tenant", tenant.0)])
+    ///             .unwrap_or_default()
+    ///     });
+    /// ```
+    pub fn with_extra_attributes<F>(mut self, extra_attributes: F) -> Self
+    where
+        F: Fn(&Conn) -> Vec<KeyValue> + Send + Sync + 'static,
+    {
+        self.extra_attributes = Some(Arc::new(extra_attributes));
+        self
+    }
+
     /// Provides a callback for `server.address` and `server.port` attributes to the metrics
     /// collector.
     ///
@@ -155,14 +487,791 @@ impl Metrics {
         self.server_address_and_port = Some(Arc::new(server_address_and_port));
         self
     }
+
+    /// Provides a callback to segment metrics by virtual host, recorded as the `server.address`
+    /// attribute.
+    ///
+    /// This is intended for servers that host a bounded set of domains. The request `Host` is
+    /// controlled by the client, so the callback should map any host outside of the known set to a
+    /// single sentinel value in order to cap cardinality. If
+    /// [`Metrics::with_server_address_and_port`] is also configured and returns a value, that
+    /// takes precedence.
+    ///
+    /// ```
+    /// trillium_opentelemetry::Metrics::new("example").with_host_dimension(|conn| {
+    ///     let host = conn.inner().host()?;
+    ///     let host = host.split_once(':').map_or(host, |(host, _port)| host);
+    ///     Some(match host {
+    ///         "example.com" => "example.com".into(),
+    ///         "example.org" => "example.org".into(),
+    ///         _ => "other".into(),
+    ///     })
+    /// });
+    /// ```
+    pub fn with_host_dimension<F>(mut self, host: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.host = Some(Arc::new(host));
+        self
+    }
+
+    /// provides the scheme of the original request, for servers behind a proxy that terminates
+    /// tls.
+    ///
+    /// by default, `url.scheme` is `https` if the conn is secure and `http` otherwise. when this
+    /// closure returns a scheme, such as one a middleware stored in the conn state after reading
+    /// a forwarded header, it is recorded as the `url.scheme` instead.
+    pub fn with_scheme<F>(mut self, scheme: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<&'static str> + Send + Sync + 'static,
+    {
+        self.scheme = Some(Arc::new(scheme));
+        self
+    }
+
+    /// Enable the `trillium.handler.start.duration` histogram.
+    ///
+    /// This measures the time from when trillium started reading the request until this handler is
+    /// run, which includes reading and parsing the request head and any handlers that run before
+    /// this one. It is not a queue wait, as time before trillium starts reading the request, such
+    /// as waiting for the connection to be accepted, is not included. It is recorded with the
+    /// `http.request.method`, `url.scheme`, `network.protocol.name`, and
+    /// `network.protocol.version` attributes, as the response attributes are not yet known.
+    pub fn with_handler_start_duration(mut self) -> Self {
+        self.histogram_config_mut().handler_start_duration = true;
+        self
+    }
+
+    /// enable the `http.server.not_found` counter, which counts requests that did not match a
+    /// route.
+    ///
+    /// a request is counted when it has a 404 status and no `http.route`, such as when no route
+    /// was matched by the router. it is recorded only with the `http.request.method` attribute,
+    /// which makes 404-rate alerting inexpensive. without a [`Metrics::with_route`] closure, every
+    /// 404 is counted.
+    pub fn with_not_found_counter(mut self) -> Self {
+        self.histogram_config_mut().not_found_counter = true;
+        self
+    }
+
+    /// enable the `http.server.request.count` counter, which counts every response recorded by
+    /// this handler, with the same attributes as `http.server.request.duration`.
+    ///
+    /// the request count can also be derived from the duration histogram, but some pipelines are
+    /// simpler with a dedicated counter. like every instrument, whether it is exported as a delta
+    /// or a cumulative sum is chosen by the metric reader rather than by this handler, so a
+    /// pipeline that prefers delta counters configures its reader (or exporter) with delta
+    /// temporality:
+    ///
+    /// ```
+    /// use opentelemetry_sdk::metrics::{ManualReader, SdkMeterProvider, Temporality};
+    /// use trillium_opentelemetry::opentelemetry::metrics::MeterProvider;
+    ///
+    /// let reader = ManualReader::builder()
+    ///     .with_temporality(Temporality::Delta)
+    ///     .build();
+    /// let provider = SdkMeterProvider::builder().with_reader(reader).build();
+    /// let metrics = trillium_opentelemetry::Metrics::from(&provider.meter("my-app"))
+    ///     .with_request_counter();
+    /// # let _ = metrics;
+    /// ```
+    ///
+    /// with delta temporality each export contains only the requests counted since the previous
+    /// export, so counts are unaffected by process restarts. with cumulative temporality each
+    /// export contains the total since the process started.
+    pub fn with_request_counter(mut self) -> Self {
+        self.histogram_config_mut().request_counter = true;
+        self
+    }
+
+    /// build the instruments and record zero-value data points when the server starts, so that
+    /// series are exported before the first request.
+    ///
+    /// metric readers only export series that have been recorded to, and most instruments are
+    /// recorded with attributes that are not known until a request is handled, such as the route
+    /// and response status. this records:
+    ///
+    /// * zero `http.server.active_requests`, without attributes
+    /// * a single observation of zero in `http.server.request.duration` (and any
+    ///   [`Metrics::with_duration_histogram_variant`] histograms),
+    ///   `http.server.request.body.size` and `http.server.response.body.size`, without attributes.
+    ///   histograms have no way to record a series without an observation, so each of these
+    ///   attribute-less series has a count of one and a sum of zero
+    /// * with [`Metrics::with_not_found_counter`], zero `http.server.not_found` for each method in
+    ///   [`Metrics::with_methods`], or each standard method, which are the same series that
+    ///   unmatched requests are counted in
+    ///
+    /// ```
+    /// let metrics = trillium_opentelemetry::global::metrics()
+    ///     .register_eagerly()
+    ///     .with_not_found_counter();
+    /// # let _ = metrics;
+    /// ```
+    pub fn register_eagerly(mut self) -> Self {
+        self.histogram_config_mut().register_eagerly = true;
+        self
+    }
+
+    /// enable the `http.server.response.start.duration` histogram, which measures the time to
+    /// first byte.
+    ///
+    /// this is the time from when trillium started reading the request until the first bytes of
+    /// the response body are sent, which is a key indicator for streaming and proxied responses
+    /// that begin sending long before they are complete. this wraps the response body to observe
+    /// its first read, so responses without a body are not recorded.
+    pub fn with_ttfb_histogram(mut self) -> Self {
+        self.histogram_config_mut().response_start = true;
+        self
+    }
+
+    /// disable the `http.server.request.body.size` histogram.
+    ///
+    /// the histogram is not built, and the `Content-Length` request header is not read.
+    pub fn without_request_size_histogram(mut self) -> Self {
+        self.histogram_config_mut().disable_request_size = true;
+        self
+    }
+
+    /// disable the `http.server.response.body.size` histogram.
+    ///
+    /// the histogram is not built, and the response body length is not computed, so
+    /// [`Metrics::with_counted_response_body`] has no effect.
+    pub fn without_response_size_histogram(mut self) -> Self {
+        self.histogram_config_mut().disable_response_size = true;
+        self
+    }
+
+    /// Sets the unit of the `http.server.request.duration` histogram and its variants.
+    ///
+    /// The default is [`DurationUnit::Seconds`], as required by the semantic conventions. Use
+    /// [`DurationUnit::Milliseconds`] for dashboards and recording rules that expect
+    /// milliseconds. Boundaries provided to [`Metrics::with_duration_histogram_boundaries`] and
+    /// [`Metrics::with_duration_histogram_variant`] are always in seconds, and are scaled to this
+    /// unit. Other duration histograms are always recorded in seconds.
+    ///
+    /// ```
+    /// use trillium_opentelemetry::{DurationUnit, Metrics};
+    /// Metrics::new("example")
+    ///     .with_duration_unit(DurationUnit::Milliseconds)
+    ///     .with_duration_histogram_boundaries(vec![0.005, 0.01, 0.025, 0.05, 0.1]);
+    /// ```
+    pub fn with_duration_unit(mut self, duration_unit: DurationUnit) -> Self {
+        self.histogram_config_mut().duration_unit = duration_unit;
+        self
+    }
+
+    /// Sets explicit bucket boundaries, in seconds, for the `http.server.request.duration`
+    /// histogram.
+    ///
+    /// If this is not set, the meter provider's default boundaries are used. The boundaries are
+    /// scaled to the configured [`DurationUnit`].
+    pub fn with_duration_histogram_boundaries(mut self, boundaries: Vec<f64>) -> Self {
+        self.histogram_config_mut().duration_boundaries = Some(boundaries);
+        self
+    }
+
+    /// Registers an additional request duration histogram with its own bucket boundaries, in
+    /// seconds, for use with [`Metrics::with_histogram_selector`].
+    ///
+    /// The histogram is named `http.server.request.duration.{key}`, as instruments with the same
+    /// name cannot have different boundaries.
+    pub fn with_duration_histogram_variant(
+        mut self,
+        key: &'static str,
+        boundaries: Vec<f64>,
+    ) -> Self {
+        self.histogram_config_mut()
+            .duration_variants
+            .push((key, boundaries));
+        self
+    }
+
+    /// Provides a selector to record each request's duration to one of the histograms registered
+    /// with [`Metrics::with_duration_histogram_variant`].
+    ///
+    /// This is useful for endpoints with distinct latency profiles, such as cache hits and
+    /// database queries, that are poorly served by a single set of buckets. When the selector
+    /// returns None or a key that was not registered, the duration is recorded to
+    /// `http.server.request.duration`.
+    ///
+    /// ```
+    /// # struct CacheHit;
+    /// trillium_opentelemetry::Metrics::new("example")
+    ///     .with_duration_histogram_variant("cached", vec![0.0001, 0.0005, 0.001, 0.005, 0.01])
+    ///     .with_histogram_selector(|conn| conn.state::<CacheHit>().map(|_| "cached"));
+    /// ```
+    pub fn with_histogram_selector<F>(mut self, histogram_selector: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<&'static str> + Send + Sync + 'static,
+    {
+        self.histogram_selector = Some(Arc::new(histogram_selector));
+        self
+    }
+
+    /// Provides the request and response body sizes, in bytes, to record in the body size
+    /// histograms.
+    ///
+    /// By default, the request body size is read from the `Content-Length` request header and
+    /// the response body size is the length of the response body. When this is set, the closure
+    /// returns `(request_len, response_len)` instead, which is useful when sizes come from
+    /// application accounting, and a `None` size is not recorded. This replaces
+    /// [`Metrics::with_counted_response_body`].
+    ///
+    /// ```
+    /// # struct BodySizes { request: u64, response: u64 }
+    /// trillium_opentelemetry::Metrics::new("example").with_body_sizes(|conn| {
+    ///     conn.state::<BodySizes>()
+    ///         .map_or((None, None), |sizes| (Some(sizes.request), Some(sizes.response)))
+    /// });
+    /// ```
+    pub fn with_body_sizes<F>(mut self, body_sizes: F) -> Self
+    where
+        F: Fn(&Conn) -> (Option<u64>, Option<u64>) + Send + Sync + 'static,
+    {
+        self.body_sizes = Some(Arc::new(body_sizes));
+        self
+    }
+
+    /// Provides an observer that is called with every measurement as it is recorded, alongside
+    /// the OpenTelemetry instruments.
+    ///
+    /// This is intended for bridging to metrics systems that are not OpenTelemetry. Each
+    /// [`Observation`] carries the instrument name, unit, value, and the same attributes that the
+    /// instrument records. As with the instruments, the response measurements are observed after
+    /// the response has been sent.
+    ///
+    /// ```
+    /// trillium_opentelemetry::Metrics::new("example").with_observer(|observation| {
+    ///     println!("{} {}{}", observation.name, observation.value, observation.unit);
+    /// });
+    /// ```
+    pub fn with_observer<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&Observation<'_>) + Send + Sync + 'static,
+    {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Copies the named attributes from the provided [`Resource`] onto every metrics observation.
+    ///
+    /// This is for backends and dashboards that are unable to query by resource attributes. The
+    /// resource should be the same one the meter provider was built with, as a [`Meter`] does not
+    /// expose its provider's resource. Keys that are not present on the resource are ignored.
+    ///
+    /// Every distinct value becomes a separate series, so only low-cardinality resource attributes
+    /// such as `service.name` or `deployment.environment.name` should be copied.
+    ///
+    /// [`Resource`]: opentelemetry_sdk::Resource
+    #[cfg(feature = "sdk")]
+    pub fn with_resource_attributes_as_dimensions(
+        mut self,
+        resource: &opentelemetry_sdk::Resource,
+        keys: impl IntoIterator<Item = &'static str>,
+    ) -> Self {
+        self.resource_attributes = keys
+            .into_iter()
+            .filter_map(|key| {
+                resource
+                    .get(key.into())
+                    .map(|value| KeyValue::new(key, value))
+            })
+            .collect();
+        self
+    }
+
+    /// Provides a user agent parser to record low-cardinality `user_agent.name` and
+    /// `user_agent.os.name` metrics attributes.
+    ///
+    /// The parser is called with the `User-Agent` request header, if present. Because it is
+    /// client-controlled, the parser must map it to a small, bounded set of values, including a
+    /// sentinel for anything unrecognized.
+    pub fn with_user_agent_parser<F>(mut self, user_agent_parser: F) -> Self
+    where
+        F: Fn(&str) -> UserAgentInfo + Send + Sync + 'static,
+    {
+        self.user_agent_parser = Some(Arc::new(user_agent_parser));
+        self
+    }
+
+    /// Omit the `network.protocol.version` attribute from metrics.
+    ///
+    /// This avoids splitting every series by http version for dashboards that do not need it.
+    /// Trace spans still record the protocol version.
+    pub fn without_protocol_version(mut self) -> Self {
+        self.disable_protocol_version = true;
+        self
+    }
+
+    /// Enable recording which listener received the request as the `trillium.listener` metrics
+    /// attribute.
+    ///
+    /// This is the server's [listener description](trillium::Info::listener_description), so there
+    /// is one value per listener. This is useful to segment metrics when one process runs several
+    /// servers, such as a public and an admin port.
+    pub fn with_listener(mut self) -> Self {
+        self.enable_listener = true;
+        self
+    }
+
+    /// collapse requests under a static file directory into a single `http.route`.
+    ///
+    /// static file handlers such as
+    /// [`trillium-static`](https://docs.trillium.rs/trillium_static/index.html) don't provide a
+    /// route, and recording `url.path` instead would be unbounded cardinality. any request with a
+    /// path under `prefix` that does not otherwise have a route is recorded with an `http.route` of
+    /// `{prefix}/*`. this can be called more than once to register several prefixes; the first
+    /// matching prefix is used.
+    ///
+    /// ```
+    /// trillium_opentelemetry::Metrics::new(&opentelemetry::global::meter("example"))
+    ///     .with_static_route("/assets");
+    /// ```
+    pub fn with_static_route(mut self, prefix: impl Into<Cow<'static, str>>) -> Self {
+        let prefix = match prefix.into() {
+            Cow::Borrowed(prefix) => Cow::Borrowed(prefix.trim_end_matches('/')),
+            Cow::Owned(prefix) => Cow::Owned(prefix.trim_end_matches('/').to_string()),
+        };
+        let route = format!("{prefix}/*").into();
+        self.static_routes.push((prefix, route));
+        self
+    }
+
+    /// caps the number of distinct values recorded for the attribute `key` at `limit`.
+    ///
+    /// the first `limit` distinct values are recorded as usual, and every other value for that key
+    /// is recorded as `_OVERFLOW`. this protects backends from a misbehaving attribute callback,
+    /// such as a route closure that returns raw paths. clones share the values seen so far.
+    ///
+    /// ```
+    /// trillium_opentelemetry::Metrics::new(&opentelemetry::global::meter("example"))
+    ///     .with_cardinality_limit("http.route", 100);
+    /// ```
+    pub fn with_cardinality_limit(mut self, key: impl Into<Key>, limit: usize) -> Self {
+        self.cardinality_limits
+            .push(CardinalityLimit::new(key.into(), limit));
+        self
+    }
+
+    /// enable recording whether each request reads or writes as the `http.request.method_class`
+    /// attribute.
+    ///
+    /// [safe](https://httpwg.org/specs/rfc9110.html#safe.methods) methods such as `GET` and `HEAD`
+    /// are recorded as `read`, and all other methods such as `POST` are recorded as `write`. this
+    /// makes it possible to split latency by the kind of request without splitting by every
+    /// method.
+    pub fn with_method_class_dimension(mut self) -> Self {
+        self.enable_method_class = true;
+        self
+    }
+
+    /// enable recording the primary language of the `Accept-Language` request header as the
+    /// `trillium.locale` attribute.
+    ///
+    /// the language tag with the highest quality value is used, and only its primary subtag is
+    /// recorded, so `fr-CH, fr;q=0.9, en;q=0.8` is recorded as `fr`. to bound cardinality, any
+    /// primary subtag that isn't a two letter language code, including the `*` wildcard, is
+    /// recorded as `_OTHER`. requests without an `Accept-Language` header do not have this
+    /// attribute.
+    pub fn with_locale_dimension(mut self) -> Self {
+        self.enable_locale = true;
+        self
+    }
+
+    /// enable recording the media type of the `Content-Type` request header as the
+    /// `trillium.request.content_type` attribute, for apis that accept more than one body format.
+    ///
+    /// the type and subtype are lowercased and parameters are removed, so
+    /// `Application/JSON; charset=utf-8` is recorded as `application/json`. to bound cardinality,
+    /// any value that isn't a well-formed media type with a registered top-level type, such as
+    /// `application` or `text`, is recorded as `_OTHER`. requests without a `Content-Type` header
+    /// do not have this attribute.
+    pub fn with_request_content_type(mut self) -> Self {
+        self.enable_request_content_type = true;
+        self
+    }
+
+    /// record the negotiated protocol as `network.protocol.name` instead of always `http`.
+    ///
+    /// when the response switches protocols, such as a websocket upgrade, the protocol named by
+    /// the `Upgrade` response header is recorded, e.g. `websocket`. the handler start duration
+    /// histogram is recorded before the response, so it always records `http`.
+    pub fn with_upgrade_protocol_name(mut self) -> Self {
+        self.enable_upgrade_protocol_name = true;
+        self
+    }
+
+    /// enable recording the size of streaming response bodies of unknown length, such as chunked
+    /// responses.
+    ///
+    /// without this, responses with no known length are not recorded in the response body size
+    /// histogram. this wraps the response body to tally the bytes sent across all chunks without
+    /// buffering, and records the total after the response has been sent. as with other sizes,
+    /// this is measured where this handler sits in the handler chain, so handlers placed before it
+    /// that transform the body, such as compression, are not reflected.
+    pub fn with_counted_response_body(mut self) -> Self {
+        self.enable_counted_response_body = true;
+        self
+    }
+
+    /// only record metrics for requests with one of the provided methods.
+    ///
+    /// requests with any other method are not recorded in any of the histograms. this is useful
+    /// for narrowing metric volume to the requests of interest, such as mutating requests. calling
+    /// this again replaces the previous methods.
+    ///
+    /// ```
+    /// use trillium::Method;
+    /// trillium_opentelemetry::Metrics::new("example")
+    ///     .with_methods([Method::Post, Method::Put, Method::Patch, Method::Delete]);
+    /// ```
+    pub fn with_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = Some(methods.into_iter().collect());
+        self
+    }
+
+    /// Provides a predicate to record only the request method and response status for some
+    /// requests.
+    ///
+    /// When the predicate returns true for a conn, every other attribute is dropped from that
+    /// request's measurements, including the route, scheme, and protocol version. This keeps
+    /// high-volume endpoints cheap in backends that charge per time series.
+    ///
+    /// ```
+    /// trillium_opentelemetry::Metrics::new("example")
+    ///     .with_minimal_attributes_for(|conn| conn.path().starts_with("/events"));
+    /// ```
+    pub fn with_minimal_attributes_for<F>(mut self, minimal_attributes: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.minimal_attributes = Some(Arc::new(minimal_attributes));
+        self
+    }
+
+    /// Provides a predicate to skip metrics for some requests.
+    ///
+    /// When the predicate returns true for a conn, that request is not recorded in any of the
+    /// histograms.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+}
+
+impl Metrics {
+    /// attributes that are known as soon as the request has been received
+    fn request_attributes(&self, conn: &Conn) -> Vec<KeyValue> {
+        let scheme = scheme(conn, self.scheme.as_deref());
+
+        let mut attributes = vec![
+            KeyValue::new(
+                semconv::attribute::HTTP_REQUEST_METHOD,
+                conn.method().as_str(),
+            ),
+            KeyValue::new(semconv::attribute::NETWORK_PROTOCOL_NAME, "http"),
+            KeyValue::new(semconv::attribute::URL_SCHEME, scheme),
+        ];
+
+        if self.enable_method_class {
+            let method_class = if conn.method().is_safe() {
+                "read"
+            } else {
+                "write"
+            };
+            attributes.push(KeyValue::new("http.request.method_class", method_class));
+        }
+
+        if !self.disable_protocol_version {
+            let version = conn
+                .inner()
+                .http_version()
+                .as_str()
+                .strip_prefix("HTTP/")
+                .unwrap();
+            attributes.push(KeyValue::new(
+                semconv::attribute::NETWORK_PROTOCOL_VERSION,
+                version,
+            ));
+        }
+
+        attributes
+    }
+
+    /// the instruments, building them from the current configuration if they have not been built
+    fn instruments(&self) -> &Arc<Instruments> {
+        self.histograms
+            .get_or_init(&self.meter, &self.histogram_config)
+    }
+
+    /// the configuration of the instruments. this discards any instruments that have already been
+    /// built, so that they are built again with the changed configuration
+    fn histogram_config_mut(&mut self) -> &mut HistogramConfig {
+        self.histograms = Histograms::default();
+        &mut self.histogram_config
+    }
+
+    /// the attributes for `http.server.active_requests`. these are fewer than for the histograms,
+    /// as the counter is incremented before anything about the response is known.
+    fn active_request_attributes(&self, conn: &Conn) -> Vec<KeyValue> {
+        let mut attributes = vec![
+            KeyValue::new(
+                semconv::attribute::HTTP_REQUEST_METHOD,
+                conn.method().as_str(),
+            ),
+            KeyValue::new(
+                semconv::attribute::URL_SCHEME,
+                scheme(conn, self.scheme.as_deref()),
+            ),
+        ];
+
+        if let Some((address, port)) = self
+            .server_address_and_port
+            .as_ref()
+            .and_then(|server_address_and_port| server_address_and_port(conn))
+        {
+            attributes.push(KeyValue::new(semconv::attribute::SERVER_ADDRESS, address));
+            attributes.push(KeyValue::new(
+                semconv::attribute::SERVER_PORT,
+                i64::from(port),
+            ));
+        } else if let Some(host) = self.host.as_ref().and_then(|host| host(conn)) {
+            attributes.push(KeyValue::new(semconv::attribute::SERVER_ADDRESS, host));
+        }
+
+        if self
+            .minimal_attributes
+            .as_ref()
+            .is_some_and(|minimal_attributes| minimal_attributes(conn))
+        {
+            retain_minimal_attributes(&mut attributes);
+        }
+
+        attributes
+    }
+}
+
+/// The unit that request durations are recorded in. See [`Metrics::with_duration_unit`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DurationUnit {
+    /// seconds, `s`, as required by the semantic conventions
+    #[default]
+    Seconds,
+    /// milliseconds, `ms`
+    Milliseconds,
+}
+
+impl DurationUnit {
+    fn unit(self) -> &'static str {
+        match self {
+            DurationUnit::Seconds => "s",
+            DurationUnit::Milliseconds => "ms",
+        }
+    }
+
+    /// converts a number of seconds to this unit
+    fn scale(self, seconds: f64) -> f64 {
+        match self {
+            DurationUnit::Seconds => seconds,
+            DurationUnit::Milliseconds => seconds * 1000.0,
+        }
+    }
+}
+
+/// removes every attribute except the request method and response status
+fn retain_minimal_attributes(attributes: &mut Vec<KeyValue>) {
+    attributes.retain(|kv| {
+        kv.key.as_str() == semconv::attribute::HTTP_REQUEST_METHOD
+            || kv.key.as_str() == semconv::attribute::HTTP_RESPONSE_STATUS_CODE
+    });
+}
+
+/// the lowercased primary subtag of the highest quality language tag in an `Accept-Language`
+/// header, or `_OTHER` if it isn't a two letter language code
+fn primary_language(accept_language: &str) -> Cow<'static, str> {
+    let mut best: Option<(&str, f32)> = None;
+    for entry in accept_language.split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+
+        if let Some(quality) = quality {
+            if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((tag, quality));
+            }
+        }
+    }
+
+    let primary = best
+        .and_then(|(tag, _)| tag.split('-').next())
+        .filter(|primary| primary.len() == 2 && primary.bytes().all(|b| b.is_ascii_alphabetic()));
+
+    match primary {
+        Some(primary) => primary.to_ascii_lowercase().into(),
+        None => "_OTHER".into(),
+    }
+}
+
+/// the lowercased `type/subtype` of a `Content-Type` header without parameters, or `_OTHER` if it
+/// isn't a well-formed media type with a registered top-level type
+fn media_type(content_type: &str) -> Cow<'static, str> {
+    const TOP_LEVEL_TYPES: &[&str] = &[
+        "application",
+        "audio",
+        "font",
+        "image",
+        "message",
+        "model",
+        "multipart",
+        "text",
+        "video",
+    ];
+
+    let is_name = |name: &str| {
+        (1..=127).contains(&name.len())
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+    };
+
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    match media_type.split_once('/') {
+        Some((top_level, subtype)) if TOP_LEVEL_TYPES.contains(&top_level) && is_name(subtype) => {
+            media_type.into()
+        }
+        _ => "_OTHER".into(),
+    }
 }
 
 struct MetricsWasRun;
 
+struct MetricsMeter(Meter);
+
+/// A response body size provided with [`MetricsConnExt::record_response_size`].
+struct ResponseSize(u64);
+
+/// Extension trait for accessing the [`Meter`] used by the [`Metrics`] handler from within
+/// application handlers.
+///
+/// This allows handlers to create and record custom instruments under the same instrumentation
+/// scope without passing the meter around manually.
+///
+/// ```
+/// use trillium::Conn;
+/// use trillium_opentelemetry::MetricsConnExt;
+///
+/// async fn handler(conn: Conn) -> Conn {
+///     if let Some(meter) = conn.metrics_meter() {
+///         meter.u64_counter("app.widgets.created").build().add(1, &[]);
+///     }
+///     conn.ok("created")
+/// }
+/// ```
+pub trait MetricsConnExt {
+    /// Returns the [`Meter`] of the [`Metrics`] handler that was run on this conn, if any.
+    fn metrics_meter(&self) -> Option<&Meter>;
+
+    /// Provides the size of the response body in bytes, for responses whose length the
+    /// [`Metrics`] handler cannot determine, such as a streamed or proxied body.
+    ///
+    /// This size is recorded in the `http.server.response.body.size` histogram instead of the
+    /// length of the response body, and instead of the size returned by a
+    /// [`Metrics::with_body_sizes`] closure. It must be provided before the response is sent.
+    ///
+    /// ```
+    /// use trillium::Conn;
+    /// use trillium_opentelemetry::MetricsConnExt;
+    ///
+    /// async fn handler(mut conn: Conn) -> Conn {
+    ///     let upstream_len = 1024;
+    ///     conn.record_response_size(upstream_len);
+    ///     conn.ok("streamed from upstream")
+    /// }
+    /// ```
+    fn record_response_size(&mut self, bytes: u64);
+}
+
+impl MetricsConnExt for Conn {
+    fn metrics_meter(&self) -> Option<&Meter> {
+        self.state::<MetricsMeter>()
+            .map(|MetricsMeter(meter)| meter)
+    }
+
+    fn record_response_size(&mut self, bytes: u64) {
+        self.insert_state(ResponseSize(bytes));
+    }
+}
+
 #[async_trait]
 impl Handler for Metrics {
+    async fn init(&mut self, info: &mut Info) {
+        let instruments = self.instruments();
+        if self.histogram_config.register_eagerly {
+            instruments.register_eagerly(self.methods.as_deref().unwrap_or(&[
+                Method::Connect,
+                Method::Delete,
+                Method::Get,
+                Method::Head,
+                Method::Options,
+                Method::Patch,
+                Method::Post,
+                Method::Put,
+                Method::Trace,
+            ]));
+        }
+
+        if self.enable_listener {
+            self.listener = Some(info.listener_description().to_string())
+                .filter(|listener| !listener.is_empty());
+        }
+    }
+
     async fn run(&self, conn: Conn) -> Conn {
-        conn.with_state(MetricsWasRun)
+        if self.filter.as_ref().is_some_and(|filter| filter(&conn))
+            || self
+                .methods
+                .as_ref()
+                .is_some_and(|methods| !methods.contains(&conn.method()))
+        {
+            return conn;
+        }
+
+        let instruments = self.instruments();
+        if instruments.has_handler_start_duration() {
+            let mut attributes = self.request_attributes(&conn);
+            if self
+                .minimal_attributes
+                .as_ref()
+                .is_some_and(|minimal_attributes| minimal_attributes(&conn))
+            {
+                retain_minimal_attributes(&mut attributes);
+            }
+
+            instruments.record_handler_start_duration(
+                conn.inner().start_time().elapsed(),
+                &attributes,
+                self.observer.as_deref(),
+            );
+        }
+
+        let active_request = instruments
+            .start_active_request(self.active_request_attributes(&conn), self.observer.clone());
+
+        conn.with_state(active_request)
+            .with_state(MetricsWasRun)
+            .with_state(MetricsMeter(self.meter.clone()))
     }
 
     async fn before_send(&self, mut conn: Conn) -> Conn {
@@ -170,76 +1279,207 @@ impl Handler for Metrics {
             return conn;
         }
 
-        let Metrics {
-            route,
-            error_type,
-            server_address_and_port,
-            duration_histogram,
-            request_size_histogram,
-            response_size_histogram,
-        } = self.clone();
-        let error_type = error_type.and_then(|et| et(&conn)).or_else(|| {
-            let status = conn.status().unwrap_or(Status::NotFound);
-            if status.is_server_error() {
-                Some((status as u16).to_string().into())
-            } else {
-                None
-            }
-        });
+        let instruments = Arc::clone(self.instruments());
+        let error_type = self
+            .error_type
+            .as_ref()
+            .and_then(|et| et(&conn))
+            .or_else(|| status_error_type(&conn, &self.status_error_types))
+            .or_else(|| {
+                let status = conn.status().unwrap_or(Status::NotFound);
+                if status.is_server_error() && !self.disable_default_error_type {
+                    Some((status as u16).to_string().into())
+                } else {
+                    None
+                }
+            });
         let status: i64 = (conn.status().unwrap_or(Status::NotFound) as u16).into();
-        let route = route.and_then(|r| r(&conn));
+        let route = resolve_route(&mut conn, self.route.as_deref()).or_else(|| {
+            let path = conn.path();
+            self.static_routes
+                .iter()
+                .find(|(prefix, _)| {
+                    path.strip_prefix(&**prefix)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                })
+                .map(|(_, route)| route.clone())
+        });
+        let histogram_variant = self
+            .histogram_selector
+            .as_ref()
+            .and_then(|selector| selector(&conn));
         let start_time = conn.inner().start_time();
-        let method = conn.method().as_str();
-        let request_len = conn
-            .request_headers()
-            .get_str(KnownHeaderName::ContentLength)
-            .and_then(|src| src.parse::<u64>().ok());
-        let response_len = conn.response_len();
-        let scheme = if conn.is_secure() { "https" } else { "http" };
-        let version = conn
-            .inner()
-            .http_version()
-            .as_str()
-            .strip_prefix("HTTP/")
-            .unwrap();
-        let server_address_and_port = server_address_and_port.and_then(|f| f(&conn));
+        // trillium does not send a body for these, even if one was set
+        let body_sent = conn.method() != Method::Head
+            && !matches!(conn.status(), Some(Status::NotModified | Status::NoContent));
+        let record_request_size = !self.histogram_config.disable_request_size;
+        let record_response_size = !self.histogram_config.disable_response_size;
+        let (request_len, response_len) = match &self.body_sizes {
+            Some(body_sizes) if record_request_size || record_response_size => body_sizes(&conn),
+            Some(_) => (None, None),
+            None => (
+                record_request_size
+                    .then(|| {
+                        conn.request_headers()
+                            .get_str(KnownHeaderName::ContentLength)
+                    })
+                    .flatten()
+                    .and_then(|src| src.parse::<u64>().ok()),
+                match (record_response_size, body_sent) {
+                    (false, _) => None,
+                    (true, true) => conn.response_len(),
+                    (true, false) => Some(0),
+                },
+            ),
+        };
+        let response_len = match conn.take_state::<ResponseSize>() {
+            Some(ResponseSize(bytes)) if record_response_size => Some(bytes),
+            _ => response_len,
+        };
+        let counted_response_len = if self.enable_counted_response_body
+            && record_response_size
+            && self.body_sizes.is_none()
+            && response_len.is_none()
+        {
+            count_response_body(&mut conn)
+        } else {
+            None
+        };
+        let first_byte = if self.histogram_config.response_start && body_sent {
+            time_first_byte(&mut conn)
+        } else {
+            None
+        };
+        let server_address_and_port = self.server_address_and_port.as_ref().and_then(|f| f(&conn));
+        let active_request = conn.take_state::<ActiveRequest>();
 
-        let mut attributes = vec![
-            KeyValue::new(semconv::attribute::HTTP_REQUEST_METHOD, method),
-            KeyValue::new(semconv::attribute::HTTP_RESPONSE_STATUS_CODE, status),
-            KeyValue::new(semconv::attribute::NETWORK_PROTOCOL_NAME, "http"),
-            KeyValue::new(semconv::attribute::URL_SCHEME, scheme),
-            KeyValue::new(semconv::attribute::NETWORK_PROTOCOL_VERSION, version),
-        ];
+        if route.is_none() && status == 404 {
+            instruments.record_not_found(
+                &[KeyValue::new(
+                    semconv::attribute::HTTP_REQUEST_METHOD,
+                    conn.method().as_str(),
+                )],
+                self.observer.as_deref(),
+            );
+        }
+
+        let mut attributes = self.request_attributes(&conn);
+        if self.enable_upgrade_protocol_name {
+            if let Some(protocol) = attributes
+                .iter_mut()
+                .find(|kv| kv.key.as_str() == semconv::attribute::NETWORK_PROTOCOL_NAME)
+            {
+                protocol.value = protocol_name(&conn).into();
+            }
+        }
+        attributes.push(KeyValue::new(
+            semconv::attribute::HTTP_RESPONSE_STATUS_CODE,
+            status,
+        ));
+
+        attributes.extend(self.resource_attributes.iter().cloned());
+
+        if let Some(listener) = &self.listener {
+            attributes.push(KeyValue::new("trillium.listener", listener.clone()));
+        }
+
+        attributes.extend(user_agent::attributes(
+            &conn,
+            self.user_agent_parser.as_deref(),
+        ));
+
+        if self.enable_locale {
+            if let Some(accept_language) = conn
+                .request_headers()
+                .get_str(KnownHeaderName::AcceptLanguage)
+            {
+                attributes.push(KeyValue::new(
+                    "trillium.locale",
+                    primary_language(accept_language),
+                ));
+            }
+        }
+
+        if self.enable_request_content_type {
+            if let Some(content_type) = conn.request_headers().get_str(KnownHeaderName::ContentType)
+            {
+                attributes.push(KeyValue::new(
+                    "trillium.request.content_type",
+                    media_type(content_type),
+                ));
+            }
+        }
 
         if let Some(error_type) = error_type {
-            attributes.push(KeyValue::new("error.type", error_type));
+            attributes.push(KeyValue::new(self.error_type_key, error_type));
         }
 
         if let Some(route) = route {
             attributes.push(KeyValue::new(semconv::attribute::HTTP_ROUTE, route))
         };
 
+        if let Some(module) = self.module.as_ref().and_then(|module| module(&conn)) {
+            attributes.push(KeyValue::new(semconv::attribute::CODE_NAMESPACE, module));
+        }
+
+        if let Some(api_version) = self
+            .api_version
+            .as_ref()
+            .and_then(|api_version| api_version(&conn))
+        {
+            attributes.push(KeyValue::new("http.api.version", api_version));
+        }
+
+        if let Some(extra_attributes) = &self.extra_attributes {
+            attributes.extend(extra_attributes(&conn));
+        }
+
+        #[cfg(feature = "trace")]
+        if self.enable_sampled_dimension {
+            attributes.push(KeyValue::new("trillium.trace.sampled", conn.is_sampled()));
+        }
+
         if let Some((address, port)) = server_address_and_port {
             attributes.push(KeyValue::new(semconv::attribute::SERVER_ADDRESS, address));
             attributes.push(KeyValue::new(
                 semconv::attribute::SERVER_PORT,
                 i64::from(port),
             ));
+        } else if let Some(host) = self.host.as_ref().and_then(|h| h(&conn)) {
+            attributes.push(KeyValue::new(semconv::attribute::SERVER_ADDRESS, host));
         }
 
-        conn.inner_mut().after_send(move |_| {
-            let duration_s = (Instant::now() - start_time).as_secs_f64();
+        if self
+            .minimal_attributes
+            .as_ref()
+            .is_some_and(|minimal_attributes| minimal_attributes(&conn))
+        {
+            retain_minimal_attributes(&mut attributes);
+        }
 
-            duration_histogram.record(duration_s, &attributes);
+        for cardinality_limit in &self.cardinality_limits {
+            cardinality_limit.apply(&mut attributes);
+        }
 
-            if let Some(response_len) = response_len {
-                response_size_histogram.record(response_len, &attributes);
-            }
+        let observer = self.observer.clone();
+        conn.inner_mut().after_send(move |_| {
+            let duration = start_time.elapsed();
 
-            if let Some(request_len) = request_len {
-                request_size_histogram.record(request_len, &attributes);
-            }
+            instruments.record(
+                Measurements {
+                    duration_variant: histogram_variant,
+                    duration,
+                    response_start: first_byte.and_then(|first_byte| first_byte.get().copied()),
+                    request_len,
+                    response_len: response_len.or_else(|| {
+                        counted_response_len.map(|count| count.load(Ordering::Relaxed))
+                    }),
+                },
+                &attributes,
+                observer.as_deref(),
+            );
+
+            drop(active_request);
         });
 
         conn
@@ -0,0 +1,44 @@
+use opentelemetry::{Key, KeyValue, Value};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+/// The value recorded in place of any value beyond a [`CardinalityLimit`]
+const OVERFLOW: &str = "_OVERFLOW";
+
+/// Caps the number of distinct values recorded for a single attribute key.
+///
+/// Clones share the set of values seen so far.
+#[derive(Clone, Debug)]
+pub(super) struct CardinalityLimit {
+    key: Key,
+    limit: usize,
+    seen: Arc<Mutex<HashSet<String>>>,
+}
+
+impl CardinalityLimit {
+    pub(super) fn new(key: Key, limit: usize) -> Self {
+        Self {
+            key,
+            limit,
+            seen: Arc::default(),
+        }
+    }
+
+    /// replaces the value of any attribute with this key with `_OVERFLOW` once `limit` other
+    /// distinct values have been seen
+    pub(super) fn apply(&self, attributes: &mut [KeyValue]) {
+        for attribute in attributes.iter_mut().filter(|kv| kv.key == self.key) {
+            let value = attribute.value.as_str();
+            let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+            if !seen.contains(&*value) {
+                if seen.len() < self.limit {
+                    seen.insert(value.into_owned());
+                } else {
+                    attribute.value = Value::from(OVERFLOW);
+                }
+            }
+        }
+    }
+}
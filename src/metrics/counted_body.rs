@@ -0,0 +1,55 @@
+use futures_lite::AsyncRead;
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use trillium::{Body, Conn};
+
+/// A streaming response body that tallies the bytes read from it without buffering.
+struct CountedBody {
+    reader: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    count: Arc<AtomicU64>,
+}
+
+impl AsyncRead for CountedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.reader.as_mut().poll_read(cx, buf);
+        if let Poll::Ready(Ok(bytes)) = &poll {
+            self.count.fetch_add(*bytes as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+/// Replaces a streaming response body of unknown length with one that counts the bytes sent.
+///
+/// Returns the running count, which is complete by the time `after_send` hooks run. Returns `None`
+/// if the response body is not a streaming body of unknown length.
+pub(super) fn count_response_body(conn: &mut Conn) -> Option<Arc<AtomicU64>> {
+    if !conn
+        .response_body()
+        .is_some_and(|body| body.is_streaming() && body.len().is_none())
+    {
+        return None;
+    }
+
+    let body = conn.take_response_body()?;
+    let count = Arc::new(AtomicU64::new(0));
+    conn.set_body(Body::new_streaming(
+        CountedBody {
+            reader: body.into_reader(),
+            count: Arc::clone(&count),
+        },
+        None,
+    ));
+    Some(count)
+}
@@ -0,0 +1,51 @@
+use futures_lite::AsyncRead;
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use trillium::{Body, Conn};
+
+/// A response body that notes how long after the start of the request its first bytes were read.
+struct FirstByteBody {
+    reader: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    start_time: Instant,
+    first_byte: Arc<OnceLock<Duration>>,
+}
+
+impl AsyncRead for FirstByteBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.reader.as_mut().poll_read(cx, buf);
+        if let Poll::Ready(Ok(1..)) = &poll {
+            let elapsed = self.start_time.elapsed();
+            self.first_byte.get_or_init(|| elapsed);
+        }
+        poll
+    }
+}
+
+/// Replaces the response body with one that records when its first bytes are sent, preserving
+/// the body's length.
+///
+/// Returns the time to first byte, which is set by the time `after_send` hooks run if any of the
+/// body was sent. Returns `None` if there is no response body.
+pub(super) fn time_first_byte(conn: &mut Conn) -> Option<Arc<OnceLock<Duration>>> {
+    let body = conn.take_response_body()?;
+    let len = body.len();
+    let first_byte = Arc::new(OnceLock::new());
+    conn.set_body(Body::new_streaming(
+        FirstByteBody {
+            reader: body.into_reader(),
+            start_time: conn.inner().start_time(),
+            first_byte: Arc::clone(&first_byte),
+        },
+        len,
+    ));
+    Some(first_byte)
+}
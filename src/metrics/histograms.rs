@@ -0,0 +1,383 @@
+use super::{DurationUnit, Observation, ObserverFn};
+use opentelemetry::{
+    metrics::{Counter, Histogram, Meter, UpDownCounter},
+    KeyValue,
+};
+use opentelemetry_semantic_conventions as semconv;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use trillium::Method;
+
+/// The instruments recorded by [`Metrics`](super::Metrics).
+///
+/// These are built from the meter and [`HistogramConfig`] in
+/// [`Handler::init`](trillium::Handler::init), or when they are first recorded to if the handler
+/// is run without being initialized. Clones share the same instruments. Changing the
+/// configuration replaces this with a new, unbuilt `Histograms`, so the instruments are always
+/// built from the final configuration.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Histograms(Arc<OnceLock<Arc<Instruments>>>);
+
+#[derive(Debug)]
+pub(super) struct Instruments {
+    duration_unit: DurationUnit,
+    duration: Histogram<f64>,
+    duration_variants: HashMap<&'static str, Histogram<f64>>,
+    handler_start_duration: Option<Histogram<f64>>,
+    not_found: Option<Counter<u64>>,
+    active_requests: UpDownCounter<i64>,
+    requests: Option<Counter<u64>>,
+    response_start: Option<Histogram<f64>>,
+    request_size: Option<Histogram<u64>>,
+    response_size: Option<Histogram<u64>>,
+}
+
+/// The measurements taken for a single response, all recorded with the same attributes.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Measurements<'a> {
+    pub(super) duration_variant: Option<&'a str>,
+    pub(super) duration: Duration,
+    pub(super) response_start: Option<Duration>,
+    pub(super) request_len: Option<u64>,
+    pub(super) response_len: Option<u64>,
+}
+
+/// The configuration needed to build [`Histograms`]
+#[derive(Clone, Debug, Default)]
+pub(super) struct HistogramConfig {
+    pub(super) duration_unit: DurationUnit,
+    pub(super) duration_boundaries: Option<Vec<f64>>,
+    pub(super) duration_variants: Vec<(&'static str, Vec<f64>)>,
+    pub(super) handler_start_duration: bool,
+    pub(super) not_found_counter: bool,
+    pub(super) request_counter: bool,
+    pub(super) response_start: bool,
+    pub(super) disable_request_size: bool,
+    pub(super) disable_response_size: bool,
+    pub(super) register_eagerly: bool,
+}
+
+/// builds a request duration histogram, with boundaries provided in seconds scaled to `unit`
+fn duration_histogram(
+    meter: &Meter,
+    name: String,
+    boundaries: Option<Vec<f64>>,
+    unit: DurationUnit,
+) -> Histogram<f64> {
+    let builder = meter
+        .f64_histogram(name)
+        .with_description("Measures the duration of inbound HTTP requests.")
+        .with_unit(unit.unit());
+
+    match boundaries {
+        Some(boundaries) => builder.with_boundaries(
+            boundaries
+                .into_iter()
+                .map(|boundary| unit.scale(boundary))
+                .collect(),
+        ),
+        None => builder,
+    }
+    .build()
+}
+
+impl Histograms {
+    /// the instruments, building them if they have not been built yet
+    pub(super) fn get_or_init(&self, meter: &Meter, config: &HistogramConfig) -> &Arc<Instruments> {
+        self.0
+            .get_or_init(|| Arc::new(Instruments::new(meter, config)))
+    }
+}
+
+impl Instruments {
+    fn new(meter: &Meter, config: &HistogramConfig) -> Self {
+        let HistogramConfig {
+            duration_unit,
+            duration_boundaries,
+            duration_variants,
+            handler_start_duration,
+            not_found_counter,
+            request_counter,
+            response_start,
+            disable_request_size,
+            disable_response_size,
+            register_eagerly: _,
+        } = config.clone();
+
+        Self {
+            duration_unit,
+            duration: duration_histogram(
+                meter,
+                semconv::metric::HTTP_SERVER_REQUEST_DURATION.into(),
+                duration_boundaries,
+                duration_unit,
+            ),
+
+            duration_variants: duration_variants
+                .into_iter()
+                .map(|(key, boundaries)| {
+                    let name = format!("{}.{key}", semconv::metric::HTTP_SERVER_REQUEST_DURATION);
+                    let histogram = duration_histogram(meter, name, Some(boundaries), duration_unit);
+                    (key, histogram)
+                })
+                .collect(),
+
+            handler_start_duration: handler_start_duration.then(|| {
+                meter
+                    .f64_histogram("trillium.handler.start.duration")
+                    .with_description(
+                        "Measures the time between receiving an inbound HTTP request and running the \
+                         metrics handler.",
+                    )
+                    .with_unit("s")
+                    .build()
+            }),
+
+            not_found: not_found_counter.then(|| {
+                meter
+                    .u64_counter("http.server.not_found")
+                    .with_description("Counts inbound HTTP requests that did not match a route.")
+                    .with_unit("{request}")
+                    .build()
+            }),
+
+            active_requests: meter
+                .i64_up_down_counter(semconv::metric::HTTP_SERVER_ACTIVE_REQUESTS)
+                .with_description("Number of active HTTP server requests.")
+                .with_unit("{request}")
+                .build(),
+
+            requests: request_counter.then(|| {
+                meter
+                    .u64_counter("http.server.request.count")
+                    .with_description("Counts inbound HTTP requests.")
+                    .with_unit("{request}")
+                    .build()
+            }),
+
+            response_start: response_start.then(|| {
+                meter
+                    .f64_histogram("http.server.response.start.duration")
+                    .with_description(
+                        "Measures the time between receiving an inbound HTTP request and sending \
+                         the first bytes of the response body.",
+                    )
+                    .with_unit("s")
+                    .build()
+            }),
+
+            request_size: (!disable_request_size).then(|| {
+                meter
+                    .u64_histogram(semconv::metric::HTTP_SERVER_REQUEST_BODY_SIZE)
+                    .with_description("Measures the size of HTTP request messages (compressed).")
+                    .with_unit("By")
+                    .build()
+            }),
+
+            response_size: (!disable_response_size).then(|| {
+                meter
+                    .u64_histogram(semconv::metric::HTTP_SERVER_RESPONSE_BODY_SIZE)
+                    .with_description("Measures the size of HTTP response messages (compressed).")
+                    .with_unit("By")
+                    .build()
+            }),
+        }
+    }
+
+    /// records every response measurement with a single shared attribute slice.
+    ///
+    /// the duration is recorded to the duration histogram variant registered for
+    /// `duration_variant`, or to the default duration histogram if there is no such variant
+    ///
+    /// each measurement is also provided to the observer, if there is one
+    pub(super) fn record(
+        &self,
+        measurements: Measurements<'_>,
+        attributes: &[KeyValue],
+        observer: Option<&ObserverFn>,
+    ) {
+        let Measurements {
+            duration_variant,
+            duration,
+            response_start,
+            request_len,
+            response_len,
+        } = measurements;
+
+        let observe = |name: &str, unit, value| {
+            if let Some(observer) = observer {
+                observer(&Observation {
+                    name,
+                    unit,
+                    value,
+                    attributes,
+                });
+            }
+        };
+
+        let variant =
+            duration_variant.and_then(|variant| self.duration_variants.get_key_value(variant));
+        let duration_histogram = variant.map_or(&self.duration, |(_, histogram)| histogram);
+        let duration = self.duration_unit.scale(duration.as_secs_f64());
+        duration_histogram.record(duration, attributes);
+        if observer.is_some() {
+            let name = match variant {
+                Some((key, _)) => {
+                    format!("{}.{key}", semconv::metric::HTTP_SERVER_REQUEST_DURATION).into()
+                }
+                None => Cow::Borrowed(semconv::metric::HTTP_SERVER_REQUEST_DURATION),
+            };
+            observe(&name, self.duration_unit.unit(), duration);
+        }
+
+        if let Some(requests) = &self.requests {
+            requests.add(1, attributes);
+            observe("http.server.request.count", "{request}", 1.0);
+        }
+
+        if let Some((histogram, response_start)) = self.response_start.as_ref().zip(response_start)
+        {
+            histogram.record(response_start.as_secs_f64(), attributes);
+            observe(
+                "http.server.response.start.duration",
+                "s",
+                response_start.as_secs_f64(),
+            );
+        }
+
+        if let Some((histogram, response_len)) = self.response_size.as_ref().zip(response_len) {
+            histogram.record(response_len, attributes);
+            observe(
+                semconv::metric::HTTP_SERVER_RESPONSE_BODY_SIZE,
+                "By",
+                response_len as f64,
+            );
+        }
+
+        if let Some((histogram, request_len)) = self.request_size.as_ref().zip(request_len) {
+            histogram.record(request_len, attributes);
+            observe(
+                semconv::metric::HTTP_SERVER_REQUEST_BODY_SIZE,
+                "By",
+                request_len as f64,
+            );
+        }
+    }
+
+    pub(super) fn record_handler_start_duration(
+        &self,
+        duration: Duration,
+        attributes: &[KeyValue],
+        observer: Option<&ObserverFn>,
+    ) {
+        if let Some(handler_start_duration) = &self.handler_start_duration {
+            handler_start_duration.record(duration.as_secs_f64(), attributes);
+            if let Some(observer) = observer {
+                observer(&Observation {
+                    name: "trillium.handler.start.duration",
+                    unit: "s",
+                    value: duration.as_secs_f64(),
+                    attributes,
+                });
+            }
+        }
+    }
+
+    pub(super) fn record_not_found(&self, attributes: &[KeyValue], observer: Option<&ObserverFn>) {
+        if let Some(not_found) = &self.not_found {
+            not_found.add(1, attributes);
+            if let Some(observer) = observer {
+                observer(&Observation {
+                    name: "http.server.not_found",
+                    unit: "{request}",
+                    value: 1.0,
+                    attributes,
+                });
+            }
+        }
+    }
+
+    /// records zeros so that series are exported before the first request: a zero for each
+    /// method in the not-found counter, which are the series that unmatched requests are counted
+    /// in, zero active requests, and a single zero observation in each duration and body size
+    /// histogram, without attributes
+    pub(super) fn register_eagerly(&self, methods: &[Method]) {
+        if let Some(not_found) = &self.not_found {
+            for method in methods {
+                not_found.add(
+                    0,
+                    &[KeyValue::new(
+                        semconv::attribute::HTTP_REQUEST_METHOD,
+                        method.as_str(),
+                    )],
+                );
+            }
+        }
+
+        self.active_requests.add(0, &[]);
+        self.duration.record(0.0, &[]);
+        for histogram in self.duration_variants.values() {
+            histogram.record(0.0, &[]);
+        }
+        for histogram in [&self.request_size, &self.response_size]
+            .into_iter()
+            .flatten()
+        {
+            histogram.record(0, &[]);
+        }
+    }
+
+    /// increments the active request counter, returning a guard that decrements it with the same
+    /// attributes when dropped
+    pub(super) fn start_active_request(
+        &self,
+        attributes: Vec<KeyValue>,
+        observer: Option<Arc<ObserverFn>>,
+    ) -> ActiveRequest {
+        let active_request = ActiveRequest {
+            active_requests: self.active_requests.clone(),
+            attributes,
+            observer,
+        };
+        active_request.add(1);
+        active_request
+    }
+
+    pub(super) fn has_handler_start_duration(&self) -> bool {
+        self.handler_start_duration.is_some()
+    }
+}
+
+/// An in-flight request counted by `http.server.active_requests`.
+///
+/// This is decremented when dropped rather than when the response is sent, so that the counter
+/// stays balanced for conns that are dropped without a response, such as when a handler panics.
+pub(super) struct ActiveRequest {
+    active_requests: UpDownCounter<i64>,
+    attributes: Vec<KeyValue>,
+    observer: Option<Arc<ObserverFn>>,
+}
+
+impl ActiveRequest {
+    fn add(&self, value: i64) {
+        self.active_requests.add(value, &self.attributes);
+        if let Some(observer) = &self.observer {
+            observer(&Observation {
+                name: semconv::metric::HTTP_SERVER_ACTIVE_REQUESTS,
+                unit: "{request}",
+                value: value as f64,
+                attributes: &self.attributes,
+            });
+        }
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.add(-1);
+    }
+}
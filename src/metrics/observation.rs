@@ -0,0 +1,20 @@
+use opentelemetry::KeyValue;
+
+/// A single measurement recorded by [`Metrics`](super::Metrics), as provided to
+/// [`Metrics::with_observer`](super::Metrics::with_observer).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct Observation<'a> {
+    /// The name of the instrument that the measurement was recorded to, such as
+    /// `http.server.request.duration`
+    pub name: &'a str,
+
+    /// The unit of the measurement, `s` for durations and `By` for sizes
+    pub unit: &'static str,
+
+    /// The measured value, in [`Observation::unit`]
+    pub value: f64,
+
+    /// The attributes the measurement was recorded with
+    pub attributes: &'a [KeyValue],
+}
@@ -0,0 +1,22 @@
+use trillium::{async_trait, Conn, Handler};
+
+/// A trillium handler that does nothing.
+///
+/// This is always available, including when neither the `trace` nor the `metrics` feature is
+/// enabled, so that applications can swap it in for [`Instrument`](crate::Instrument),
+/// [`Trace`](crate::Trace) or [`Metrics`](crate::Metrics) when instrumentation is compiled out,
+/// without changing the shape of their handler chain. Conns pass through it untouched.
+///
+/// ```
+/// let handler = (trillium_opentelemetry::NoOp, "ok");
+/// # let _ = handler;
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoOp;
+
+#[async_trait]
+impl Handler for NoOp {
+    async fn run(&self, conn: Conn) -> Conn {
+        conn
+    }
+}
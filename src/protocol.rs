@@ -0,0 +1,30 @@
+use std::borrow::Cow;
+use trillium::{Conn, KnownHeaderName, Status};
+
+pub(crate) type SchemeFn = dyn Fn(&Conn) -> Option<&'static str> + Send + Sync + 'static;
+
+/// The scheme for `url.scheme`: the scheme provided by the override if there is one, and otherwise
+/// `https` for secure conns and `http` for the rest.
+pub(crate) fn scheme(conn: &Conn, scheme_override: Option<&SchemeFn>) -> &'static str {
+    scheme_override
+        .and_then(|scheme_override| scheme_override(conn))
+        .unwrap_or(if conn.is_secure() { "https" } else { "http" })
+}
+
+/// The negotiated protocol for `network.protocol.name`: the lowercased `Upgrade` response header
+/// when the response switches protocols, such as `websocket`, and `http` otherwise.
+pub(crate) fn protocol_name(conn: &Conn) -> Cow<'static, str> {
+    if conn.status() == Some(Status::SwitchingProtocols) {
+        if let Some(upgrade) = conn
+            .response_headers()
+            .get_str(KnownHeaderName::Upgrade)
+            .and_then(|upgrade| upgrade.split(',').next())
+            .map(str::trim)
+            .filter(|upgrade| !upgrade.is_empty())
+        {
+            return Cow::Owned(upgrade.to_ascii_lowercase());
+        }
+    }
+
+    Cow::Borrowed("http")
+}
@@ -0,0 +1,57 @@
+use std::borrow::Cow;
+use trillium::{async_trait, Conn, Handler};
+
+/// The route resolved by the first instrumentation handler to resolve one for this conn, so that
+/// spans and metrics always agree on `http.route`.
+struct ResolvedRoute(Cow<'static, str>);
+
+/// The route recorded for conns marked with [`FallbackRoute`].
+const FALLBACK_ROUTE: &str = "/*fallback";
+
+/// A marker for requests served by a fallback or catch-all handler, such as a single page app
+/// serving `index.html` for any unmatched path.
+///
+/// When no route is otherwise known for a conn, instrumentation records `http.route` as
+/// `"/*fallback"` for conns carrying this marker, which also gives their spans a distinct name
+/// (`GET /*fallback`). Requests with a matched route keep their route.
+///
+/// The marker can be inserted as conn state, or placed in the handler chain directly ahead of the
+/// catch-all handler, since it is itself a handler that marks every conn passing through it:
+///
+/// ```
+/// use trillium_opentelemetry::FallbackRoute;
+/// use trillium_router::Router;
+///
+/// let handler = (
+///     Router::new().get("/api/users/:id", "user"),
+///     (FallbackRoute, "index.html"),
+/// );
+/// # let _ = handler;
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FallbackRoute;
+
+#[async_trait]
+impl Handler for FallbackRoute {
+    async fn run(&self, conn: Conn) -> Conn {
+        conn.with_state(FallbackRoute)
+    }
+}
+
+/// Returns the route resolved earlier in this request if there is one, otherwise calls the
+/// provided route closure and stores the result for other instrumentation handlers. Conns marked
+/// with [`FallbackRoute`] resolve to `"/*fallback"` when the closure returns none.
+pub(crate) fn resolve_route<F>(conn: &mut Conn, route: Option<&F>) -> Option<Cow<'static, str>>
+where
+    F: Fn(&Conn) -> Option<Cow<'static, str>> + ?Sized,
+{
+    if let Some(ResolvedRoute(route)) = conn.state() {
+        return Some(route.clone());
+    }
+
+    let route = route
+        .and_then(|route| route(conn))
+        .or_else(|| conn.state::<FallbackRoute>().map(|_| FALLBACK_ROUTE.into()))?;
+    conn.insert_state(ResolvedRoute(route.clone()));
+    Some(route)
+}
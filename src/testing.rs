@@ -0,0 +1,85 @@
+//! Helpers for asserting that instrumentation conforms to the
+//! [semantic conventions for http spans][http-spans].
+//!
+//! This module is only available with the `testing` feature.
+//!
+//! [http-spans]: https://opentelemetry.io/docs/specs/semconv/http/http-spans
+
+use opentelemetry::{trace::SpanKind, Value};
+use opentelemetry_sdk::export::trace::SpanData;
+
+/// Asserts that `span` is a spec-compliant http server span, such as one produced by
+/// [`Trace`](crate::Trace).
+///
+/// This checks that the span is a server span named `{method}` or `{method} {route}`, and that the
+/// required attributes are present with the correct types: `http.request.method`, `url.path`,
+/// `url.scheme` and `http.response.status_code`. If `http.route` is present, it must be a string.
+///
+/// # Panics
+///
+/// Panics with a description of the first violation found.
+///
+/// ```no_run
+/// # fn check(exporter: opentelemetry_sdk::testing::trace::InMemorySpanExporter) {
+/// for span in exporter.get_finished_spans().unwrap() {
+///     trillium_opentelemetry::testing::assert_http_server_span(&span);
+/// }
+/// # }
+/// ```
+#[track_caller]
+pub fn assert_http_server_span(span: &SpanData) {
+    let get = |key: &str| {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| &kv.value)
+    };
+
+    let string = |key: &str| match get(key) {
+        Some(Value::String(value)) => value.as_str(),
+        Some(other) => panic!("expected `{key}` to be a string, but it was {other:?}"),
+        None => panic!("expected a `{key}` attribute on span `{}`", span.name),
+    };
+
+    assert_eq!(
+        span.span_kind,
+        SpanKind::Server,
+        "expected span `{}` to be a server span",
+        span.name
+    );
+
+    let method = string("http.request.method");
+    string("url.path");
+
+    let scheme = string("url.scheme");
+    assert!(
+        scheme == "http" || scheme == "https",
+        "expected `url.scheme` to be http or https, but it was {scheme}"
+    );
+
+    match get("http.response.status_code") {
+        Some(Value::I64(status)) => assert!(
+            (100..600).contains(status),
+            "expected `http.response.status_code` to be a valid status, but it was {status}"
+        ),
+        Some(other) => {
+            panic!("expected `http.response.status_code` to be an integer, but it was {other:?}")
+        }
+        None => panic!(
+            "expected a `http.response.status_code` attribute on span `{}`",
+            span.name
+        ),
+    }
+
+    let expected_name = match get("http.route") {
+        Some(Value::String(route)) => format!("{method} {}", route.as_str()),
+        Some(other) => panic!("expected `http.route` to be a string, but it was {other:?}"),
+        None => method.to_string(),
+    };
+
+    assert!(
+        span.name.eq_ignore_ascii_case(&expected_name),
+        "expected span to be named `{expected_name}`, but it was `{}`",
+        span.name
+    );
+}
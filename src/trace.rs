@@ -1,34 +1,219 @@
+#[cfg(feature = "cpu-time")]
+use crate::cpu_time::process_cpu_time;
+use crate::{
+    error_type::status_error_type,
+    protocol::{protocol_name, scheme, SchemeFn},
+    state::resolve_route,
+    user_agent::{self, UserAgentInfo, UserAgentParserFn},
+};
+#[cfg(feature = "cpu-time")]
+use opentelemetry::trace::SpanRef;
 use opentelemetry::{
-    trace::{SpanBuilder, SpanKind, TraceContextExt, Tracer},
-    Array, Context, KeyValue, Value,
+    global,
+    propagation::Injector,
+    trace::{
+        Link, SamplingDecision, SamplingResult, SpanBuilder, SpanContext, SpanId, SpanKind,
+        TraceContextExt, TraceFlags, TraceId, TraceState, Tracer,
+    },
+    Array, Context, Key, KeyValue, Value,
 };
 use std::{
     borrow::Cow,
     fmt::{self, Debug, Formatter},
     net::SocketAddr,
     sync::Arc,
-    time::{Instant, SystemTime},
+    time::{Duration, SystemTime},
+};
+use trillium::{
+    async_trait, log, Conn, Handler, HeaderName, Headers, KnownHeaderName, Method, Status,
 };
-use trillium::{async_trait, Conn, Handler, HeaderName, KnownHeaderName, Status};
 
 type StringExtractionFn = dyn Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static;
+type PredicateFn = dyn Fn(&Conn) -> bool + Send + Sync + 'static;
+type SpanNameFn = dyn Fn(&Conn) -> Cow<'static, str> + Send + Sync + 'static;
+type ClockFn = dyn Fn() -> SystemTime + Send + Sync + 'static;
+#[cfg(feature = "testing")]
+type IdGeneratorFn = dyn Fn() -> (TraceId, SpanId) + Send + Sync + 'static;
+type OperationSpanFn = dyn Fn(SpanBuilder) -> Context + Send + Sync + 'static;
+type RouteSamplingFn = dyn Fn(&str) -> Option<f64> + Send + Sync + 'static;
+type RouteParamsFn =
+    dyn for<'a> Fn(&'a Conn) -> Vec<(Cow<'a, str>, Cow<'a, str>)> + Send + Sync + 'static;
+
+/// A conn state type that provides attributes for the request span.
+///
+/// Register a state type with [`Trace::with_state_attributes`]. If a value of that type is in the
+/// conn state when the response is sent, its attributes are added to the span.
+///
+/// ```
+/// use trillium_opentelemetry::{opentelemetry::KeyValue, StateAttributes};
+///
+/// struct Account {
+///     plan: &'static str,
+/// }
+///
+/// impl StateAttributes for Account {
+///     fn attributes(&self) -> Vec<KeyValue> {
+///         vec![KeyValue::new("app.account.plan", self.plan)]
+///     }
+/// }
+///
+/// trillium_opentelemetry::global::trace().with_state_attributes::<Account>();
+/// ```
+pub trait StateAttributes: Send + Sync + 'static {
+    /// The attributes to add to the request span
+    fn attributes(&self) -> Vec<KeyValue>;
+}
+
+type StateAttributesFn = fn(&Conn) -> Option<Vec<KeyValue>>;
+
+fn state_attributes<S: StateAttributes>(conn: &Conn) -> Option<Vec<KeyValue>> {
+    conn.state::<S>().map(S::attributes)
+}
+
+/// A conn state that sets the status of the request span, instead of the status that [`Trace`]
+/// would derive from the response status and timeout.
+///
+/// Handlers can use this to mark a response with a successful status code as failed, such as a
+/// `200 OK` with a degraded result, or to mark a span as explicitly `Ok`. This does not change
+/// the `error.type` attribute or metrics.
+///
+/// ```
+/// use trillium::Conn;
+/// use trillium_opentelemetry::{opentelemetry::trace::Status, SpanStatusOverride};
+///
+/// async fn handler(conn: Conn) -> Conn {
+///     conn.with_state(SpanStatusOverride(Status::error("served stale results")))
+///         .ok("stale results")
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanStatusOverride(pub opentelemetry::trace::Status);
 
 /// Trillium handler that instruments per-request spans as per [semantic conventions for http][http-spans].
 ///
+/// Clones share the same tracer.
+///
+/// Each request gets its own span, including each of several requests on one keep-alive
+/// connection. Trillium builds a new [`Conn`] with empty state for every request, and this handler
+/// never attaches the request span to the task's current context, so request spans are neither
+/// reused nor nested across requests.
+///
+/// If a downstream handler panics while the panic unwinds, the request span is ended with an error
+/// status and an `error.type` of `"panic"`. This is not possible when panics abort.
+///
 /// [http-spans]: https://opentelemetry.io/docs/specs/semconv/http/http-spans
-#[derive(Clone)]
 pub struct Trace<T> {
     pub(crate) route: Option<Arc<StringExtractionFn>>,
     pub(crate) error_type: Option<Arc<StringExtractionFn>>,
+    pub(crate) error_description: Option<Arc<StringExtractionFn>>,
+    pub(crate) error_type_key: &'static str,
+    pub(crate) disable_default_error_type: bool,
+    pub(crate) status_error_types: Vec<(Status, &'static str)>,
+    pub(crate) client_address: Option<Arc<StringExtractionFn>>,
+    pub(crate) scheme: Option<Arc<SchemeFn>>,
+    pub(crate) filter: Option<Arc<PredicateFn>>,
+    pub(crate) timeout: Option<Arc<PredicateFn>>,
+    pub(crate) route_params: Option<Arc<RouteParamsFn>>,
+    pub(crate) fallback_span_name: Option<Arc<SpanNameFn>>,
+    pub(crate) operation_name: Option<Arc<StringExtractionFn>>,
+    pub(crate) route_sampling: Option<Arc<RouteSamplingFn>>,
+    pub(crate) clock: Option<Arc<ClockFn>>,
+    pub(crate) state_attributes: Vec<StateAttributesFn>,
+    pub(crate) user_agent_parser: Option<Arc<UserAgentParserFn>>,
+    pub(crate) boolean_attributes: Vec<(Key, Arc<PredicateFn>)>,
     pub(crate) headers: Vec<HeaderName<'static>>,
+    pub(crate) correlation_id_header: Option<HeaderName<'static>>,
+    pub(crate) request_id: Option<Arc<StringExtractionFn>>,
+    pub(crate) parent_trace_id_header: Option<HeaderName<'static>>,
+    pub(crate) enable_correlation_id_generation: bool,
     pub(crate) enable_local_address_and_port: bool,
-    tracer: T,
+    pub(crate) enable_redirect_cross_origin: bool,
+    pub(crate) disable_url_query: bool,
+    pub(crate) enable_duration_attribute: bool,
+    pub(crate) duration_attribute_granularity: Option<Duration>,
+    pub(crate) enable_zipkin_compat: bool,
+    pub(crate) enable_datadog_compat: bool,
+    pub(crate) enable_end_before_body_send: bool,
+    pub(crate) enable_root_context: bool,
+    pub(crate) enable_listener: bool,
+    pub(crate) enable_auth_scheme: bool,
+    pub(crate) enable_upgrade_protocol_name: bool,
+    pub(crate) enable_upgrade_header: bool,
+    pub(crate) enable_response_header_stats: bool,
+    pub(crate) enable_late_route_event: bool,
+    pub(crate) tail_sampling_threshold: Option<Duration>,
+    pub(crate) max_attributes: Option<usize>,
+    pub(crate) enable_sorted_attributes: bool,
+    #[cfg(feature = "testing")]
+    pub(crate) id_generator: Option<Arc<IdGeneratorFn>>,
+    #[cfg(feature = "cpu-time")]
+    pub(crate) enable_cpu_time: bool,
+    tracer: Arc<T>,
+    operation_span: Option<Arc<OperationSpanFn>>,
     socket_addr: Option<SocketAddr>,
+    listener: Option<String>,
+}
+
+impl<T> Clone for Trace<T> {
+    fn clone(&self) -> Self {
+        Self {
+            route: self.route.clone(),
+            error_type: self.error_type.clone(),
+            error_description: self.error_description.clone(),
+            error_type_key: self.error_type_key,
+            disable_default_error_type: self.disable_default_error_type,
+            status_error_types: self.status_error_types.clone(),
+            client_address: self.client_address.clone(),
+            scheme: self.scheme.clone(),
+            filter: self.filter.clone(),
+            timeout: self.timeout.clone(),
+            route_params: self.route_params.clone(),
+            fallback_span_name: self.fallback_span_name.clone(),
+            operation_name: self.operation_name.clone(),
+            route_sampling: self.route_sampling.clone(),
+            clock: self.clock.clone(),
+            state_attributes: self.state_attributes.clone(),
+            user_agent_parser: self.user_agent_parser.clone(),
+            boolean_attributes: self.boolean_attributes.clone(),
+            headers: self.headers.clone(),
+            correlation_id_header: self.correlation_id_header.clone(),
+            request_id: self.request_id.clone(),
+            parent_trace_id_header: self.parent_trace_id_header.clone(),
+            enable_correlation_id_generation: self.enable_correlation_id_generation,
+            enable_local_address_and_port: self.enable_local_address_and_port,
+            enable_redirect_cross_origin: self.enable_redirect_cross_origin,
+            disable_url_query: self.disable_url_query,
+            enable_duration_attribute: self.enable_duration_attribute,
+            duration_attribute_granularity: self.duration_attribute_granularity,
+            enable_zipkin_compat: self.enable_zipkin_compat,
+            enable_datadog_compat: self.enable_datadog_compat,
+            enable_end_before_body_send: self.enable_end_before_body_send,
+            enable_root_context: self.enable_root_context,
+            enable_listener: self.enable_listener,
+            enable_auth_scheme: self.enable_auth_scheme,
+            enable_upgrade_protocol_name: self.enable_upgrade_protocol_name,
+            enable_upgrade_header: self.enable_upgrade_header,
+            enable_response_header_stats: self.enable_response_header_stats,
+            enable_late_route_event: self.enable_late_route_event,
+            tail_sampling_threshold: self.tail_sampling_threshold,
+            max_attributes: self.max_attributes,
+            enable_sorted_attributes: self.enable_sorted_attributes,
+            #[cfg(feature = "testing")]
+            id_generator: self.id_generator.clone(),
+            #[cfg(feature = "cpu-time")]
+            enable_cpu_time: self.enable_cpu_time,
+            tracer: Arc::clone(&self.tracer),
+            operation_span: self.operation_span.clone(),
+            socket_addr: self.socket_addr,
+            listener: self.listener.clone(),
+        }
+    }
 }
 
 impl<Span> Debug for Trace<Span> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Trace")
+        let mut debug_struct = f.debug_struct("Trace");
+        debug_struct
             .field(
                 "route",
                 &match self.route {
@@ -43,8 +228,172 @@ impl<Span> Debug for Trace<Span> {
                     _ => "None",
                 },
             )
-            .field("tracer", &"..")
-            .finish()
+            .field(
+                "error_description",
+                &match self.error_description {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field("error_type_key", &self.error_type_key)
+            .field(
+                "disable_default_error_type",
+                &self.disable_default_error_type,
+            )
+            .field("status_error_types", &self.status_error_types)
+            .field(
+                "client_address",
+                &match self.client_address {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "scheme",
+                &match self.scheme {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "filter",
+                &match self.filter {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "timeout",
+                &match self.timeout {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "route_params",
+                &match self.route_params {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "fallback_span_name",
+                &match self.fallback_span_name {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "operation_name",
+                &match self.operation_name {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "route_sampling",
+                &match self.route_sampling {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "clock",
+                &match self.clock {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "user_agent_parser",
+                &match self.user_agent_parser {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field(
+                "boolean_attributes",
+                &self
+                    .boolean_attributes
+                    .iter()
+                    .map(|(key, _)| key)
+                    .collect::<Vec<_>>(),
+            )
+            .field("headers", &self.headers)
+            .field("correlation_id_header", &self.correlation_id_header)
+            .field(
+                "request_id",
+                &match self.request_id {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field("parent_trace_id_header", &self.parent_trace_id_header)
+            .field(
+                "enable_correlation_id_generation",
+                &self.enable_correlation_id_generation,
+            )
+            .field(
+                "enable_local_address_and_port",
+                &self.enable_local_address_and_port,
+            )
+            .field(
+                "enable_redirect_cross_origin",
+                &self.enable_redirect_cross_origin,
+            )
+            .field("disable_url_query", &self.disable_url_query)
+            .field("enable_duration_attribute", &self.enable_duration_attribute)
+            .field(
+                "duration_attribute_granularity",
+                &self.duration_attribute_granularity,
+            )
+            .field("enable_zipkin_compat", &self.enable_zipkin_compat)
+            .field("enable_datadog_compat", &self.enable_datadog_compat)
+            .field(
+                "enable_end_before_body_send",
+                &self.enable_end_before_body_send,
+            )
+            .field("enable_root_context", &self.enable_root_context)
+            .field("enable_listener", &self.enable_listener)
+            .field("enable_auth_scheme", &self.enable_auth_scheme)
+            .field(
+                "enable_upgrade_protocol_name",
+                &self.enable_upgrade_protocol_name,
+            )
+            .field("enable_upgrade_header", &self.enable_upgrade_header)
+            .field(
+                "enable_response_header_stats",
+                &self.enable_response_header_stats,
+            )
+            .field("enable_late_route_event", &self.enable_late_route_event)
+            .field("tail_sampling_threshold", &self.tail_sampling_threshold)
+            .field("max_attributes", &self.max_attributes)
+            .field("enable_sorted_attributes", &self.enable_sorted_attributes)
+            .field(
+                "operation_span",
+                &match self.operation_span {
+                    Some(_) => "Some(..)",
+                    _ => "None",
+                },
+            )
+            .field("socket_addr", &self.socket_addr)
+            .field("listener", &self.listener)
+            .field("tracer", &"..");
+
+        #[cfg(feature = "testing")]
+        debug_struct.field(
+            "id_generator",
+            &match self.id_generator {
+                Some(_) => "Some(..)",
+                _ => "None",
+            },
+        );
+
+        #[cfg(feature = "cpu-time")]
+        debug_struct.field("enable_cpu_time", &self.enable_cpu_time);
+
+        // state attribute functions are not shown
+        debug_struct.finish_non_exhaustive()
     }
 }
 
@@ -59,69 +408,1061 @@ impl<T: Tracer> Trace<T> {
         Trace {
             route: None,
             error_type: None,
+            error_description: None,
+            error_type_key: "error.type",
+            disable_default_error_type: false,
+            status_error_types: vec![],
+            client_address: None,
+            scheme: None,
+            filter: None,
+            timeout: None,
+            route_params: None,
+            fallback_span_name: None,
+            operation_name: None,
+            route_sampling: None,
+            clock: None,
+            state_attributes: vec![],
+            user_agent_parser: None,
+            boolean_attributes: vec![],
             enable_local_address_and_port: false,
-            tracer,
+            enable_redirect_cross_origin: false,
+            disable_url_query: false,
+            enable_duration_attribute: false,
+            duration_attribute_granularity: None,
+            enable_zipkin_compat: false,
+            enable_datadog_compat: false,
+            enable_end_before_body_send: false,
+            enable_root_context: false,
+            enable_listener: false,
+            enable_auth_scheme: false,
+            enable_upgrade_protocol_name: false,
+            enable_upgrade_header: false,
+            enable_response_header_stats: false,
+            enable_late_route_event: false,
+            tail_sampling_threshold: None,
+            max_attributes: None,
+            enable_sorted_attributes: false,
+            #[cfg(feature = "testing")]
+            id_generator: None,
+            #[cfg(feature = "cpu-time")]
+            enable_cpu_time: false,
+            tracer: Arc::new(tracer),
+            operation_span: None,
             headers: vec![],
+            correlation_id_header: None,
+            request_id: None,
+            parent_trace_id_header: None,
+            enable_correlation_id_generation: false,
             socket_addr: None,
+            listener: None,
         }
     }
 
-    /// provides a route specification to include in the trace spans.
+    /// provides a route specification to include in the trace spans.
+    ///
+    /// in order to avoid forcing anyone to use a particular router, this is provided as a
+    /// configuration hook.
+    ///
+    /// the resolved route is shared with the metrics handler through conn state, so both record the
+    /// same `http.route` regardless of which resolves it first.
+    ///
+    /// for use with [`trillium-router`](https://docs.trillium.rs/trillium_router/index.html),
+    /// ```
+    /// use trillium_router::RouterConnExt;
+    /// trillium_opentelemetry::Metrics::new(&opentelemetry::global::meter("example"))
+    ///     .with_route(|conn| conn.route().map(|r| r.to_string().into()));
+    /// ```
+    pub fn with_route<F>(mut self, route: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.route = Some(Arc::new(route));
+        self
+    }
+
+    /// Uses the route matched by [`trillium-router`](https://docs.trillium.rs/trillium_router/index.html)
+    /// as the `http.route`, without a closure.
+    ///
+    /// This is equivalent to calling [`Trace::with_route`] with
+    /// `|conn| conn.route().map(|r| r.to_string().into())`, and requires the `router` feature.
+    #[cfg(feature = "router")]
+    pub fn with_router_route(self) -> Self {
+        use trillium_router::RouterConnExt;
+        self.with_route(|conn| conn.route().map(|r| r.to_string().into()))
+    }
+
+    /// Provides the source of the `client.address` attribute.
+    ///
+    /// By default, `client.address` is the peer ip of the connection. In some deployments the
+    /// meaningful client identity is carried elsewhere, such as in a header set by a proxy, and
+    /// this closure replaces the peer ip entirely. When it returns `None`, no `client.address` is
+    /// recorded.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_client_address(|conn| {
+    ///     conn.request_headers()
+    ///         .get_str("x-client-id")
+    ///         .map(|client| client.to_string().into())
+    /// });
+    /// ```
+    pub fn with_client_address<F>(mut self, client_address: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.client_address = Some(Arc::new(client_address));
+        self
+    }
+
+    /// Provides the scheme of the original request, for servers behind a proxy that terminates
+    /// tls.
+    ///
+    /// By default, `url.scheme` is `https` if the conn is secure and `http` otherwise. When this
+    /// closure returns a scheme, such as one a middleware stored in the conn state after reading
+    /// a forwarded header, it is used for `url.scheme` and for the default `server.port`, and as
+    /// the scheme of the request in [`Trace::with_redirect_cross_origin`].
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_scheme(|conn| {
+    ///     match conn.request_headers().get_str("x-forwarded-proto") {
+    ///         Some("https") => Some("https"),
+    ///         Some("http") => Some("http"),
+    ///         _ => None,
+    ///     }
+    /// });
+    /// ```
+    pub fn with_scheme<F>(mut self, scheme: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<&'static str> + Send + Sync + 'static,
+    {
+        self.scheme = Some(Arc::new(scheme));
+        self
+    }
+
+    /// Provides an optional low-cardinality error type specification to include in the trace spans.
+    ///
+    /// The implementation of this is application specific, but will often look like checking the
+    /// [`Conn::state`] for an error enum and mapping that to a low-cardinality `&'static str`.
+    ///
+    /// This closure is called from [`Handler::before_send`](trillium::Handler::before_send).
+    /// Trillium calls `before_send` in reverse order, so when this handler is placed before the
+    /// rest of the application, the closure observes any state that later handlers set in either
+    /// `run` or `before_send`.
+    pub fn with_error_type<F>(mut self, error_type: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.error_type = Some(Arc::new(error_type));
+        self
+    }
+
+    /// Provides a description for the error span status of server error responses.
+    ///
+    /// By default the description is empty, as the `error.type` attribute identifies the failure.
+    /// The implementation of this is application specific, but will often look like checking the
+    /// [`Conn::state`] for an error and formatting it. Unlike `error.type`, the description does
+    /// not need to be low-cardinality. Timeouts detected with [`Trace::with_timeout_detection`]
+    /// are always described as `timeout`.
+    ///
+    /// ```
+    /// # struct DatabaseError(String);
+    /// trillium_opentelemetry::global::trace().with_error_description(|conn| {
+    ///     conn.state::<DatabaseError>()
+    ///         .map(|DatabaseError(message)| message.clone().into())
+    /// });
+    /// ```
+    pub fn with_error_description<F>(mut self, error_description: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.error_description = Some(Arc::new(error_description));
+        self
+    }
+
+    /// Records the type name of an error state type as the `error.type`.
+    ///
+    /// When a value of type `E` is in the conn state, [`std::any::type_name`] of `E` is recorded as
+    /// the `error.type`. This is a convenience for the common case of [`Trace::with_error_type`],
+    /// and replaces any error type closure.
+    ///
+    /// ```
+    /// struct DatabaseError;
+    /// trillium_opentelemetry::global::trace().with_error_type_name::<DatabaseError>();
+    /// ```
+    pub fn with_error_type_name<E: Send + Sync + 'static>(self) -> Self {
+        self.with_error_type(|conn| {
+            conn.state::<E>()
+                .map(|_| Cow::Borrowed(std::any::type_name::<E>()))
+        })
+    }
+
+    /// Records `error_type` as the `error.type` for responses with the given status.
+    ///
+    /// This is a convenience for statuses that are not server errors but should still be
+    /// considered failures, such as the `413 Payload Too Large` that body size limits respond
+    /// with before the body is read. It applies when [`Trace::with_error_type`] does not provide
+    /// an error type, and can be called more than once for several statuses.
+    ///
+    /// ```
+    /// use trillium::Status;
+    /// trillium_opentelemetry::global::trace()
+    ///     .with_status_error_type(Status::PayloadTooLarge, "request_too_large");
+    /// ```
+    pub fn with_status_error_type(mut self, status: Status, error_type: &'static str) -> Self {
+        self.status_error_types.push((status, error_type));
+        self
+    }
+
+    /// Never record the status code as the `error.type` for server errors.
+    ///
+    /// By default, when [`Trace::with_error_type`] does not provide an error type for a response
+    /// with a 5xx status, the numeric status is recorded as the `error.type`. With this set, only
+    /// the error type closure provides `error.type`. Server errors are still recorded with an
+    /// error span status.
+    pub fn without_default_error_type(mut self) -> Self {
+        self.disable_default_error_type = true;
+        self
+    }
+
+    /// Overrides the attribute key used for the error type, which is `error.type` by default.
+    ///
+    /// This is intended for migrations to backends that expect a namespaced variant, and applies
+    /// to every error type recorded on the span, including send errors and panics.
+    pub fn with_error_type_key(mut self, error_type_key: &'static str) -> Self {
+        self.error_type_key = error_type_key;
+        self
+    }
+
+    /// Provides resolved route parameters to include in the trace spans.
+    ///
+    /// Each `(name, value)` pair is recorded as a `url.path.param.{name}` attribute when the
+    /// response is sent, after the router has run.
+    ///
+    /// **WARNING** Path parameters are usually high-cardinality and may contain identifiers that
+    /// should not leave your system. This is intended for debugging, and these attributes should
+    /// never be copied onto metrics.
+    ///
+    /// for use with [`trillium-router`](https://docs.trillium.rs/trillium_router/index.html),
+    /// ```
+    /// use trillium_router::RouterConnExt;
+    /// trillium_opentelemetry::global::trace().with_route_params(|conn| {
+    ///     conn.param("user_id")
+    ///         .map(|user_id| ("user_id".into(), user_id.into()))
+    ///         .into_iter()
+    ///         .collect()
+    /// });
+    /// ```
+    pub fn with_route_params<F>(mut self, route_params: F) -> Self
+    where
+        F: for<'a> Fn(&'a Conn) -> Vec<(Cow<'a, str>, Cow<'a, str>)> + Send + Sync + 'static,
+    {
+        self.route_params = Some(Arc::new(route_params));
+        self
+    }
+
+    /// Provides a clock to use for span start and end timestamps instead of [`SystemTime::now`].
+    ///
+    /// The span start is computed by subtracting the time elapsed since the request was received
+    /// from the clock at the time the trace handler runs, and the span end is the clock at the
+    /// time the response has been sent. This is primarily useful for deterministic tests, or for
+    /// applications with a custom time source.
+    pub fn with_clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> SystemTime + Send + Sync + 'static,
+    {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Include attributes from a [`StateAttributes`] conn state type in the trace spans.
+    ///
+    /// This can be called multiple times to register several state types. The state is read when
+    /// the response is sent, so it may be set by any later handler.
+    pub fn with_state_attributes<S: StateAttributes>(mut self) -> Self {
+        self.state_attributes.push(state_attributes::<S>);
+        self
+    }
+
+    /// Provides a user agent parser to record low-cardinality `user_agent.name` and
+    /// `user_agent.os.name` attributes in the trace spans.
+    ///
+    /// The parser is called with the `User-Agent` request header, if present, and should map it to
+    /// a bounded set of values. `user_agent.original` is recorded regardless.
+    pub fn with_user_agent_parser<F>(mut self, user_agent_parser: F) -> Self
+    where
+        F: Fn(&str) -> UserAgentInfo + Send + Sync + 'static,
+    {
+        self.user_agent_parser = Some(Arc::new(user_agent_parser));
+        self
+    }
+
+    /// Records a boolean attribute in the trace spans, as determined by the provided closure.
+    ///
+    /// The closure is called when the response is sent, so it can check for conn state set by
+    /// other handlers, such as a cache hit marker. This can be called multiple times with distinct
+    /// keys.
+    ///
+    /// ```
+    /// # struct CacheHit;
+    /// trillium_opentelemetry::global::trace()
+    ///     .with_boolean_attribute("http.cache.hit", |conn| conn.state::<CacheHit>().is_some());
+    /// ```
+    pub fn with_boolean_attribute<F>(mut self, key: impl Into<Key>, attribute: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.boolean_attributes
+            .push((key.into(), Arc::new(attribute)));
+        self
+    }
+
+    /// Provides the span name for requests that have no route.
+    ///
+    /// By default, the span for a request with no route is named with only the request method,
+    /// such as `GET`. This closure provides the full span name instead, which is used until a
+    /// route is resolved. Span names should be low-cardinality, so this should not include the
+    /// full path.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_fallback_span_name(|conn| {
+    ///     let segment = conn.path().split('/').nth(1).unwrap_or_default();
+    ///     format!("{} /{segment}", conn.method()).into()
+    /// });
+    /// ```
+    pub fn with_fallback_span_name<F>(mut self, fallback_span_name: F) -> Self
+    where
+        F: Fn(&Conn) -> Cow<'static, str> + Send + Sync + 'static,
+    {
+        self.fallback_span_name = Some(Arc::new(fallback_span_name));
+        self
+    }
+
+    /// Provides an operation name to use as the span name, such as `users.create`.
+    ///
+    /// Some teams name spans by resource and action rather than by method and route. When this
+    /// closure returns a name, it is used verbatim as the span name, taking precedence over the
+    /// route and [`Trace::with_fallback_span_name`], and is not lowercased by
+    /// [`Trace::zipkin_compat`]. It is called again if the route is only resolved in
+    /// `before_send`. Span names should be low-cardinality.
+    ///
+    /// ```
+    /// # use trillium::Method;
+    /// trillium_opentelemetry::global::trace().with_operation_name(|conn| {
+    ///     match (conn.method(), conn.path()) {
+    ///         (Method::Post, "/users") => Some("users.create".into()),
+    ///         _ => None,
+    ///     }
+    /// });
+    /// ```
+    pub fn with_operation_name<F>(mut self, operation_name: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.operation_name = Some(Arc::new(operation_name));
+        self
+    }
+
+    /// Provides a sample ratio for each resolved route.
+    ///
+    /// The callback receives the route resolved by [`Trace::with_route`] and returns the fraction
+    /// of requests to that route that should be sampled, from `0.0` (none) to `1.0` (all). When
+    /// it returns `None`, or if no route is available when the request starts, the configured
+    /// tracer's sampler decides as usual. This overrides the sampler for the request span, so a
+    /// parent-based sampler will not be consulted for routes that have a ratio.
+    ///
+    /// The sampling decision is made when the span starts, so the route must be known before the
+    /// trace handler runs. trillium-router's `RouterConnExt::route` is only set once the router
+    /// has run, after the span has started, so it never reaches this callback; resolve the route
+    /// from the request itself instead. As with the sdk's `TraceIdRatioBased`
+    /// sampler, the decision is derived from the trace id when the request has a parent, so
+    /// every service sampling at the same ratio agrees.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace()
+    ///     .with_route(|conn| match conn.path() {
+    ///         "/checkout" => Some("/checkout".into()),
+    ///         path if path.starts_with("/health") => Some("/health".into()),
+    ///         _ => None,
+    ///     })
+    ///     .with_route_sampling(|route| match route {
+    ///         "/checkout" => Some(1.0),
+    ///         "/health" => Some(0.01),
+    ///         _ => None,
+    ///     });
+    /// ```
+    pub fn with_route_sampling<F>(mut self, route_sampling: F) -> Self
+    where
+        F: Fn(&str) -> Option<f64> + Send + Sync + 'static,
+    {
+        self.route_sampling = Some(Arc::new(route_sampling));
+        self
+    }
+
+    /// Provides a predicate to skip tracing for some requests.
+    ///
+    /// When the predicate returns true for a conn, no span is created for that request and any
+    /// [`InstrumentHandler`](crate::InstrumentHandler)s will pass it through untraced.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Provides a predicate that detects requests that exceeded a server-imposed timeout.
+    ///
+    /// The predicate usually checks for conn state set by a timeout handler. When it returns true,
+    /// the span is marked as an error with an `error.type` of `"timeout"`, regardless of the
+    /// response status or [`Trace::with_error_type`]. This is checked in
+    /// [`Handler::before_send`](trillium::Handler::before_send), so it can't detect requests that
+    /// were aborted by dropping the conn.
+    pub fn with_timeout_detection<F>(mut self, timeout: F) -> Self
+    where
+        F: Fn(&Conn) -> bool + Send + Sync + 'static,
+    {
+        self.timeout = Some(Arc::new(timeout));
+        self
+    }
+
+    /// Specify a list of request headers to include in the trace spans
+    pub fn with_headers(
+        mut self,
+        headers: impl IntoIterator<Item = impl Into<HeaderName<'static>>>,
+    ) -> Self {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Provides an internally generated request id to record as the `trillium.request.id` span
+    /// attribute, such as one that a request id middleware stored in the conn state.
+    ///
+    /// This complements [`Trace::with_correlation_id_header`] for ids that do not arrive in a
+    /// request header. Request ids are unique per request, so they are only recorded on spans. The
+    /// closure is called from [`Handler::before_send`], so it observes state set by handlers
+    /// that run after this one.
+    ///
+    /// ```
+    /// # struct RequestId(String);
+    /// trillium_opentelemetry::global::trace().with_request_id(|conn| {
+    ///     conn.state::<RequestId>()
+    ///         .map(|RequestId(id)| id.clone().into())
+    /// });
+    /// ```
+    pub fn with_request_id<F>(mut self, request_id: F) -> Self
+    where
+        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+    {
+        self.request_id = Some(Arc::new(request_id));
+        self
+    }
+
+    /// Record the value of a correlation id request header, such as `x-request-id`, as the
+    /// `trillium.correlation_id` span attribute.
+    ///
+    /// Correlation ids are unique per request, so they are only recorded on spans and never on
+    /// metrics. See also [`Trace::with_correlation_id_generation`].
+    pub fn with_correlation_id_header(mut self, header: impl Into<HeaderName<'static>>) -> Self {
+        self.correlation_id_header = Some(header.into());
+        self
+    }
+
+    /// Build request spans in an empty context instead of the current context.
+    ///
+    /// By default the request span is a child of any span that is active in the current context
+    /// when this handler runs. With some async runtimes and middleware, a context can leak into the
+    /// server task and accidentally become the parent of unrelated requests. With this set,
+    /// request spans are the root of their own trace unless they continue a remote trace from
+    /// [`Trace::with_parent_trace_id_header`].
+    pub fn with_root_context(mut self) -> Self {
+        self.enable_root_context = true;
+        self
+    }
+
+    /// Continue the trace identified by a request header containing only a hex trace id, for
+    /// upstream systems that do not support W3C trace context.
+    ///
+    /// When the header is present and contains a valid trace id, the request span is built as the
+    /// child of a remote, sampled parent with that trace id and a generated parent span id. This is
+    /// a lightweight alternative to configuring a propagator for simple setups, and takes
+    /// precedence over the current context.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_parent_trace_id_header("x-trace-id");
+    /// ```
+    pub fn with_parent_trace_id_header(mut self, header: impl Into<HeaderName<'static>>) -> Self {
+        self.parent_trace_id_header = Some(header.into());
+        self
+    }
+
+    /// Generate a correlation id for requests that don't have one, and echo the correlation id in
+    /// the response headers.
+    ///
+    /// When the request does not include the header configured with
+    /// [`Trace::with_correlation_id_header`], the span's trace id is used as the correlation id.
+    /// Nothing is generated if the tracer does not produce valid trace ids, such as a no-op tracer.
+    /// The response header is not overwritten if a downstream handler has already set it.
+    pub fn with_correlation_id_generation(mut self) -> Self {
+        self.enable_correlation_id_generation = true;
+        self
+    }
+
+    /// Enable population of the local socket address and port in the trace spans.
+    ///
+    /// This populates the `network.local.address` and `network.local.port` attributes.
+    pub fn with_local_address_and_port(mut self) -> Self {
+        self.enable_local_address_and_port = true;
+        self
+    }
+
+    /// Never record the `url.query` attribute in the trace spans.
+    ///
+    /// Query strings frequently contain tokens or personal information. This is an alternative to
+    /// redacting them for applications that never need the query in their traces.
+    pub fn without_url_query(mut self) -> Self {
+        self.disable_url_query = true;
+        self
+    }
+
+    /// Enable recording the elapsed handler time as a span attribute.
+    ///
+    /// This populates `http.server.duration_ms` with the milliseconds elapsed between trillium
+    /// receiving the request and the response being ready to send. This is redundant with the span
+    /// duration, which additionally includes sending the response body, but is useful for trace
+    /// interfaces that can filter on attributes but not on duration.
+    pub fn with_duration_attribute(mut self) -> Self {
+        self.enable_duration_attribute = true;
+        self
+    }
+
+    /// Round the `http.server.duration_ms` attribute to the nearest multiple of `granularity`,
+    /// such as one millisecond.
+    ///
+    /// The span's own timestamps are not affected. This has no effect unless
+    /// [`Trace::with_duration_attribute`] is enabled. A zero granularity is ignored.
+    pub fn with_duration_attribute_granularity(mut self, granularity: Duration) -> Self {
+        self.duration_attribute_granularity = Some(granularity).filter(|g| !g.is_zero());
+        self
+    }
+
+    /// Enable recording the scheme of the `Authorization` request header, such as `bearer` or
+    /// `basic`, as the `http.request.auth.scheme` attribute.
+    ///
+    /// Credentials are never recorded. Only schemes in the [IANA http authentication scheme
+    /// registry](https://www.iana.org/assignments/http-authschemes) are recorded by name, so a
+    /// header that contains a bare token with no scheme is recorded as `other`, and requests
+    /// without an `Authorization` header are recorded as `none`.
+    pub fn with_auth_scheme(mut self) -> Self {
+        self.enable_auth_scheme = true;
+        self
+    }
+
+    /// Record the negotiated protocol as `network.protocol.name` instead of always `http`.
+    ///
+    /// When the response switches protocols, such as a websocket upgrade, the protocol named by
+    /// the `Upgrade` response header is recorded, e.g. `websocket`. Because the protocol is only
+    /// known once the response status is set, the attribute is added when the response is sent
+    /// rather than when the span starts.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_upgrade_protocol_name();
+    /// ```
+    pub fn with_upgrade_protocol_name(mut self) -> Self {
+        self.enable_upgrade_protocol_name = true;
+        self
+    }
+
+    /// Enable recording the protocols requested in the `Upgrade` request header as the
+    /// `http.request.header.upgrade` attribute.
+    ///
+    /// The protocols are recorded as requested, whether or not the upgrade is accepted, which
+    /// helps diagnose failed upgrade negotiations. Each comma-separated protocol is trimmed and
+    /// lowercased, so `Upgrade: WebSocket` is recorded as `["websocket"]`. Don't also include
+    /// `upgrade` in [`Trace::with_headers`], which records the raw header values under the same
+    /// attribute.
+    pub fn with_upgrade_header(mut self) -> Self {
+        self.enable_upgrade_header = true;
+        self
+    }
+
+    /// Enable recording the number of response headers and their total size in the trace spans.
+    ///
+    /// This populates `http.response.header.count` and `http.response.header.bytes`, which is
+    /// useful for spotting middleware that add excessive headers. The size is that of the
+    /// serialized header lines. Headers that trillium adds while sending the response, such as
+    /// `Date` and `Content-Length`, are not included.
+    pub fn with_response_header_stats(mut self) -> Self {
+        self.enable_response_header_stats = true;
+        self
+    }
+
+    /// Enable recording which listener received the request in the trace spans.
+    ///
+    /// This populates the `trillium.listener` attribute with the server's
+    /// [listener description](trillium::Info::listener_description), which helps to tell apart
+    /// requests when one process runs several servers, such as a public and an admin port. See
+    /// also [`Trace::with_local_address_and_port`].
+    pub fn with_listener(mut self) -> Self {
+        self.enable_listener = true;
+        self
+    }
+
+    /// Add a `trillium.route.late_resolution` span event when the route was not available in `run`
+    /// and was only resolved in `before_send`.
+    ///
+    /// The event includes the resolved `http.route`. This is useful for diagnosing routers that
+    /// set their state late, since sampling decisions and the initial span name cannot use a late
+    /// route.
+    pub fn with_late_route_event(mut self) -> Self {
+        self.enable_late_route_event = true;
+        self
+    }
+
+    /// Only export spans for requests that are slow or fail.
+    ///
+    /// With this enabled, the span is not started in `run`. Instead, its attributes are buffered
+    /// and the span is only created when the response is about to be sent, if the request took at
+    /// least `threshold` to handle, responded with a server error, or has an `error.type`. All other
+    /// requests produce no span at all.
+    ///
+    /// Because no span exists while the rest of the handler chain runs, there is no active context
+    /// to propagate: [`InstrumentHandler`](crate::InstrumentHandler) spans and outbound requests
+    /// made by downstream handlers will not be parented to the request span, and the sampling
+    /// decision of the configured tracer is still applied on top of this one.
+    pub fn with_tail_sampling(mut self, threshold: Duration) -> Self {
+        self.tail_sampling_threshold = Some(threshold);
+        self
+    }
+
+    /// Limits the number of attributes this handler records on each request span, to bound span
+    /// size if an attribute closure or state type misbehaves.
+    ///
+    /// Attributes beyond the limit are dropped, most recently added first, and a debug message is
+    /// logged. The attributes required by the http semantic conventions are always kept, even if
+    /// that exceeds the limit: `http.request.method`, `url.path`, `url.scheme`, `http.route`,
+    /// `http.response.status_code` and the error type. Room is left for the status code, and for
+    /// a route that is resolved after the span starts, so optional attributes recorded when the
+    /// span starts do not push them over the limit. Attributes that other code adds to the span
+    /// through its context are not counted.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_max_attributes(32);
+    /// ```
+    pub fn with_max_attributes(mut self, max_attributes: usize) -> Self {
+        self.max_attributes = Some(max_attributes);
+        self
+    }
+
+    /// Record request span attributes in a deterministic order, sorted by key.
+    ///
+    /// OpenTelemetry attributes are unordered, but some span processors and test assertions rely
+    /// on a stable order. The attributes known when the request starts are sorted before the span
+    /// is built, and the attributes recorded when the response is sent are sorted among
+    /// themselves and follow them. Attributes with the same key keep their relative order.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_sorted_attributes();
+    /// ```
+    pub fn with_sorted_attributes(mut self) -> Self {
+        self.enable_sorted_attributes = true;
+        self
+    }
+
+    /// Provides the trace id and span id for each request span, for tests that assert exact ids.
     ///
-    /// in order to avoid forcing anyone to use a particular router, this is provided as a
-    /// configuration hook.
+    /// This is only available with the `testing` feature, and should not be used in production:
+    /// ids must be unique for traces to be reassembled correctly. The trace id is only used when
+    /// the request span has no parent.
     ///
-    /// for use with [`trillium-router`](https://docs.trillium.rs/trillium_router/index.html),
     /// ```
-    /// use trillium_router::RouterConnExt;
-    /// trillium_opentelemetry::Metrics::new(&opentelemetry::global::meter("example"))
-    ///     .with_route(|conn| conn.route().map(|r| r.to_string().into()));
+    /// use trillium_opentelemetry::opentelemetry::trace::{SpanId, TraceId};
+    /// trillium_opentelemetry::global::trace()
+    ///     .with_id_generator(|| (TraceId::from(1), SpanId::from(1)));
     /// ```
-    pub fn with_route<F>(mut self, route: F) -> Self
+    #[cfg(feature = "testing")]
+    pub fn with_id_generator<F>(mut self, id_generator: F) -> Self
     where
-        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
+        F: Fn() -> (TraceId, SpanId) + Send + Sync + 'static,
     {
-        self.route = Some(Arc::new(route));
+        self.id_generator = Some(Arc::new(id_generator));
         self
     }
 
-    /// Provides an optional low-cardinality error type specification to include in the trace spans.
+    /// Record the cpu time consumed by this process while the request was in flight, in seconds,
+    /// as `process.cpu.time`. A request whose wall-clock duration greatly exceeds its cpu time
+    /// spent most of that time waiting, which can help diagnose blocked or starved tasks.
     ///
-    /// The implementation of this is application specific, but will often look like checking the
-    /// [`Conn::state`] for an error enum and mapping that to a low-cardinality `&'static str`.
-    pub fn with_error_type<F>(mut self, error_type: F) -> Self
-    where
-        F: Fn(&Conn) -> Option<Cow<'static, str>> + Send + Sync + 'static,
-    {
-        self.error_type = Some(Arc::new(error_type));
+    /// This is process-wide: concurrent requests and background work are included. It is only
+    /// available with the `cpu-time` feature, and is currently only supported on linux on
+    /// architectures where the kernel reports cpu time in hundredths of a second: x86, arm,
+    /// risc-v, powerpc, s390x, mips, loongarch64 and sparc64, with a resolution of ten
+    /// milliseconds. On other platforms this is a no-op.
+    ///
+    /// The cpu time is read from `/proc/self/stat` when the request starts and again when the
+    /// response is sent. These are blocking reads on the request task, but the file is generated
+    /// by the kernel from memory, so each read typically takes a few microseconds.
+    ///
+    /// ```
+    /// trillium_opentelemetry::global::trace().with_cpu_time();
+    /// ```
+    #[cfg(feature = "cpu-time")]
+    pub fn with_cpu_time(mut self) -> Self {
+        self.enable_cpu_time = true;
         self
     }
 
-    /// Specify a list of request headers to include in the trace spans
-    pub fn with_headers(
-        mut self,
-        headers: impl IntoIterator<Item = impl Into<HeaderName<'static>>>,
-    ) -> Self {
-        self.headers = headers.into_iter().map(Into::into).collect();
+    /// Emit span names and attributes that match Zipkin's http conventions, for teams migrating
+    /// existing Zipkin dashboards.
+    ///
+    /// Span names are lowercased (`get /users/:id`), and the Zipkin `http.method`, `http.path`, and
+    /// `http.status_code` attributes are recorded in addition to their OpenTelemetry equivalents.
+    pub fn zipkin_compat(mut self) -> Self {
+        self.enable_zipkin_compat = true;
         self
     }
 
-    /// Enable population of the local socket address and port in the trace spans.
+    /// Emit the attributes that Datadog's OpenTelemetry ingestion uses to name spans, so that
+    /// Datadog's service and resource pages populate as they would for spans from Datadog's own
+    /// tracers.
     ///
-    /// This populates the `network.local.address` and `network.local.port` attributes.
-    pub fn with_local_address_and_port(mut self) -> Self {
-        self.enable_local_address_and_port = true;
+    /// This records `operation.name` as `http.server.request`, `span.type` as `web`, and
+    /// `resource.name` as the span name, such as `GET /users/:id`, in addition to the
+    /// OpenTelemetry attributes. Datadog infers the service from the `service.name` resource
+    /// attribute, which is configured on the tracer provider rather than on each span.
+    pub fn datadog_compat(mut self) -> Self {
+        self.enable_datadog_compat = true;
+        self
+    }
+
+    /// End the request span when the response is ready to send, instead of after the response body
+    /// has been sent.
+    ///
+    /// By default the span ends once the response has been written to the transport, so its
+    /// duration includes the time spent sending the body, which is long for large bodies and slow
+    /// clients. With this set, the span ends in [`Handler::before_send`], so its duration measures
+    /// only the time the application took to produce the response. Because the span has already
+    /// ended, failures while sending the response are not recorded on it. This does not affect
+    /// metrics durations.
+    pub fn end_before_body_send(mut self) -> Self {
+        self.enable_end_before_body_send = true;
+        self
+    }
+
+    /// Enable recording whether redirect responses send the client to another origin.
+    ///
+    /// For 3xx responses with a `Location` header, this populates the
+    /// `http.response.redirect.cross_origin` boolean attribute by comparing the scheme and host of
+    /// the `Location` to those of the request. A port that is the default for the scheme, such as
+    /// `:443` for https, is the same origin as no port. Relative locations are always
+    /// same-origin. The location itself is not recorded.
+    pub fn with_redirect_cross_origin(mut self) -> Self {
+        self.enable_redirect_cross_origin = true;
         self
     }
 }
 
+/// The request fields that an access log usually records, computed the same way as the
+/// corresponding span attributes. See [`Trace::access_log_fields`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AccessLogFields<'a> {
+    /// The request method, recorded as `http.request.method`
+    pub method: Method,
+
+    /// The request path without the query, recorded as `url.path`
+    pub path: &'a str,
+
+    /// The response status, recorded as `http.response.status_code`. Responses without a status
+    /// are recorded as 404.
+    pub status: Status,
+
+    /// The time elapsed since trillium started reading the request. With [`Trace::with_clock`],
+    /// this is measured with the provided clock from the request span's start time, so it
+    /// matches the span's duration.
+    pub duration: Duration,
+}
+
+impl<T> Trace<T> {
+    /// Returns the access log fields for this conn, consistent with the span attributes.
+    ///
+    /// This allows an access logger to reuse exactly the values recorded on the request span
+    /// instead of computing them separately. Call this once the response is ready to send, such as
+    /// from a logger's `before_send`, for the status and duration to be final.
+    ///
+    /// ```
+    /// # use trillium::Conn;
+    /// let trace = trillium_opentelemetry::global::trace();
+    /// let logger = move |conn: Conn| {
+    ///     let fields = trace.access_log_fields(&conn);
+    ///     let _ = format!("{} {} {} {:?}", fields.method, fields.path, fields.status, fields.duration);
+    ///     async move { conn }
+    /// };
+    /// ```
+    pub fn access_log_fields<'a>(&self, conn: &'a Conn) -> AccessLogFields<'a> {
+        AccessLogFields {
+            method: conn.method(),
+            path: conn.inner().path(),
+            status: conn.status().unwrap_or(Status::NotFound),
+            duration: match (&self.clock, conn.state()) {
+                (Some(clock), Some(ClockStartTime(start_time))) => {
+                    clock().duration_since(*start_time).unwrap_or_default()
+                }
+                _ => conn.inner().start_time().elapsed(),
+            },
+        }
+    }
+
+    fn span_name(&self, conn: &Conn, route: Option<&str>) -> Cow<'static, str> {
+        if let Some(operation_name) = self
+            .operation_name
+            .as_ref()
+            .and_then(|operation_name| operation_name(conn))
+        {
+            return operation_name;
+        }
+
+        let method = conn.method().as_str();
+        let name: Cow<'static, str> = match route {
+            Some(route) => format!("{method} {route}").into(),
+            None => match &self.fallback_span_name {
+                Some(fallback_span_name) => fallback_span_name(conn),
+                None => method.into(),
+            },
+        };
+
+        if self.enable_zipkin_compat {
+            name.to_lowercase().into()
+        } else {
+            name
+        }
+    }
+
+    /// The context to build the request span in: a remote parent from the
+    /// [`Trace::with_parent_trace_id_header`] header if it contains a valid trace id, within the
+    /// current context or an empty one with [`Trace::with_root_context`].
+    fn parent_context(&self, conn: &Conn) -> Context {
+        let context = if self.enable_root_context {
+            Context::new()
+        } else {
+            Context::current()
+        };
+
+        let trace_id = self
+            .parent_trace_id_header
+            .as_ref()
+            .and_then(|header| conn.request_headers().get_str(header.clone()))
+            .and_then(|trace_id| TraceId::from_hex(trace_id.trim()).ok())
+            .filter(|trace_id| *trace_id != TraceId::INVALID);
+
+        let Some(trace_id) = trace_id else {
+            return context;
+        };
+
+        let span_id = SpanId::from(rand::random::<u64>().max(1));
+        context.with_remote_span_context(SpanContext::new(
+            trace_id,
+            span_id,
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct TraceContext {
     pub(crate) context: Context,
+    pub(crate) depth: i64,
+    pub(crate) subtree_attributes: Arc<[KeyValue]>,
 }
 
 struct RouteWasAvailable;
 
+struct OperationSpan(Arc<OperationSpanFn>);
+
+/// Extension trait for working with the request span from within a handler.
+///
+/// ```
+/// use trillium::Conn;
+/// use trillium_opentelemetry::{opentelemetry::trace::TraceContextExt, TraceConnExt};
+///
+/// async fn batch(conn: Conn) -> Conn {
+///     for item in ["first", "second"] {
+///         if let Some(context) = conn.new_operation_span(format!("process {item}")) {
+///             // ... process the item ...
+///             context.span().end();
+///         }
+///     }
+///     conn.ok("processed")
+/// }
+/// ```
+pub trait TraceConnExt {
+    /// Starts a new span for one logical operation of a batch request, linked to the request span
+    /// instead of nested within it.
+    ///
+    /// The operation span is the root of its own trace and has a link to the request span. It is
+    /// ended when [`Span::end`](opentelemetry::trace::Span::end) is called on it or when the last
+    /// clone of the returned context is dropped. Returns `None` if there is no request span, such
+    /// as when [`Trace`] was not run on this conn or the request was filtered.
+    fn new_operation_span(&self, name: impl Into<Cow<'static, str>>) -> Option<Context>;
+
+    /// Serializes the active trace context of this conn into outgoing request headers with the
+    /// configured [global text map propagator](opentelemetry::global::set_text_map_propagator).
+    ///
+    /// Use this on the headers of a downstream request, such as one made with trillium-client, so
+    /// that the downstream service continues this trace. Within an
+    /// [`InstrumentHandler`](crate::InstrumentHandler), the handler's span is the parent of the
+    /// downstream request. This does nothing if there is no request span, and with the default
+    /// no-op propagator.
+    fn inject_into(&self, headers: &mut Headers);
+
+    /// Returns whether the active span of this conn is sampled.
+    ///
+    /// Use this to skip expensive optional work, such as detailed logging or extra spans, for
+    /// requests that will not be exported. Within an
+    /// [`InstrumentHandler`](crate::InstrumentHandler), this reflects the handler's span. This is
+    /// false if there is no request span, such as when [`Trace`] was not run on this conn, the
+    /// request was filtered, or [`Trace::with_tail_sampling`] has not yet decided to record it.
+    ///
+    /// ```
+    /// use trillium::Conn;
+    /// use trillium_opentelemetry::TraceConnExt;
+    ///
+    /// async fn handler(conn: Conn) -> Conn {
+    ///     if conn.is_sampled() {
+    ///         // expensive span work
+    ///     }
+    ///     conn
+    /// }
+    /// ```
+    fn is_sampled(&self) -> bool;
+}
+
+struct HeaderInjector<'a>(&'a mut Headers);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+impl TraceConnExt for Conn {
+    fn new_operation_span(&self, name: impl Into<Cow<'static, str>>) -> Option<Context> {
+        let TraceContext {
+            context,
+            subtree_attributes,
+            ..
+        } = self.state()?;
+        let OperationSpan(operation_span) = self.state()?;
+        let link = Link::with_context(context.span().span_context().clone());
+        Some(operation_span(
+            SpanBuilder::from_name(name)
+                .with_kind(SpanKind::Internal)
+                .with_attributes(subtree_attributes.iter().cloned())
+                .with_links(vec![link]),
+        ))
+    }
+
+    fn inject_into(&self, headers: &mut Headers) {
+        if let Some(TraceContext { context, .. }) = self.state() {
+            global::get_text_map_propagator(|propagator| {
+                propagator.inject_context(context, &mut HeaderInjector(headers))
+            });
+        }
+    }
+
+    fn is_sampled(&self) -> bool {
+        self.state::<TraceContext>()
+            .is_some_and(|TraceContext { context, .. }| context.span().span_context().is_sampled())
+    }
+}
+
+struct PendingSpan(SpanBuilder, Context);
+
+/// The number of attributes that can still be added to the request span in `before_send`.
+struct AttributeBudget(usize);
+
+/// The request span's start time, from the clock provided to [`Trace::with_clock`].
+struct ClockStartTime(SystemTime);
+
+/// Stably sorts attributes by key.
+fn sort_attributes(attributes: &mut [KeyValue]) {
+    attributes.sort_by(|a, b| a.key.as_str().cmp(b.key.as_str()));
+}
+
+/// Drops the most recently added attributes until at most `max` remain, without dropping any
+/// attribute required by the semantic conventions.
+fn truncate_attributes(attributes: &mut Vec<KeyValue>, max: usize, error_type_key: &str) {
+    let is_required = |attribute: &KeyValue| {
+        let key = attribute.key.as_str();
+        key == error_type_key
+            || matches!(
+                key,
+                "http.request.method"
+                    | "url.path"
+                    | "url.scheme"
+                    | "http.route"
+                    | "http.response.status_code"
+            )
+    };
+
+    let len = attributes.len();
+    if len <= max {
+        return;
+    }
+
+    let mut optional = max.saturating_sub(attributes.iter().filter(|a| is_required(a)).count());
+    attributes.retain(|attribute| {
+        if is_required(attribute) {
+            true
+        } else if optional > 0 {
+            optional -= 1;
+            true
+        } else {
+            false
+        }
+    });
+
+    log::debug!(
+        "dropped {} span attributes beyond the limit of {max}",
+        len - attributes.len()
+    );
+}
+
+/// The process cpu time when the request span started.
+#[cfg(feature = "cpu-time")]
+struct CpuTimeStart(Duration);
+
+#[cfg(feature = "cpu-time")]
+fn record_cpu_time(span: &SpanRef<'_>, start: Option<CpuTimeStart>) {
+    if let Some((CpuTimeStart(start), end)) = start.zip(process_cpu_time()) {
+        span.set_attribute(KeyValue::new(
+            "process.cpu.time",
+            end.saturating_sub(start).as_secs_f64(),
+        ));
+    }
+}
+
+/// Marks the request span as failed if the conn is dropped because a handler panicked. This is
+/// removed from the conn in `before_send`, which does not run on panic.
+struct PanicGuard {
+    context: Context,
+    clock: Option<Arc<ClockFn>>,
+    error_type_key: &'static str,
+}
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            let span = self.context.span();
+            span.set_status(opentelemetry::trace::Status::Error {
+                description: "panic".into(),
+            });
+            span.set_attribute(KeyValue::new(self.error_type_key, "panic"));
+            span.end_with_timestamp(now(self.clock.as_deref()));
+        }
+    }
+}
+
 #[async_trait]
 impl<T> Handler for Trace<T>
 where
@@ -129,15 +1470,37 @@ where
     T::Span: Send + Sync + 'static,
 {
     async fn init(&mut self, info: &mut trillium::Info) {
+        let tracer = Arc::clone(&self.tracer);
+        self.operation_span = Some(Arc::new(move |builder| {
+            Context::new().with_span(tracer.build_with_context(builder, &Context::new()))
+        }));
+
         if self.enable_local_address_and_port {
             self.socket_addr = info.tcp_socket_addr().cloned();
         }
+
+        if self.enable_listener {
+            self.listener = Some(info.listener_description().to_string())
+                .filter(|listener| !listener.is_empty());
+        }
     }
+
     async fn run(&self, mut conn: Conn) -> Conn {
-        let start_time =
-            Some(SystemTime::now() - conn.inner().start_time().duration_since(Instant::now()));
+        if self.filter.as_ref().is_some_and(|filter| filter(&conn)) {
+            return conn;
+        }
+
+        let start_time = now(self.clock.as_deref()) - conn.inner().start_time().elapsed();
+        if self.clock.is_some() {
+            conn.insert_state(ClockStartTime(start_time));
+        }
+
+        #[cfg(feature = "cpu-time")]
+        if let Some(cpu_time) = self.enable_cpu_time.then(process_cpu_time).flatten() {
+            conn.insert_state(CpuTimeStart(cpu_time));
+        }
 
-        let scheme = if conn.is_secure() { "https" } else { "http" };
+        let scheme = scheme(&conn, self.scheme.as_deref());
         let method = conn.method().as_str();
 
         let version = conn
@@ -151,11 +1514,20 @@ where
             KeyValue::new("http.request.method", method),
             KeyValue::new("url.path", conn.inner().path().to_string()),
             KeyValue::new("url.scheme", scheme),
-            KeyValue::new("url.query", conn.inner().querystring().to_string()),
-            KeyValue::new("network.protocol.name", "http"),
             KeyValue::new("network.protocol.version", version),
         ];
 
+        if !self.enable_upgrade_protocol_name {
+            attributes.push(KeyValue::new("network.protocol.name", "http"));
+        }
+
+        if !self.disable_url_query {
+            attributes.push(KeyValue::new(
+                "url.query",
+                conn.inner().querystring().to_string(),
+            ));
+        }
+
         if let Some(socket_addr) = &self.socket_addr {
             attributes.push(KeyValue::new(
                 "network.local.address",
@@ -168,8 +1540,17 @@ where
             ));
         }
 
-        if let Some(peer_ip) = conn.inner().peer_ip() {
-            attributes.push(KeyValue::new("client.address", peer_ip.to_string()));
+        if let Some(listener) = &self.listener {
+            attributes.push(KeyValue::new("trillium.listener", listener.clone()));
+        }
+
+        let client_address = match &self.client_address {
+            Some(client_address) => client_address(&conn),
+            None => conn.inner().peer_ip().map(|ip| ip.to_string().into()),
+        };
+
+        if let Some(client_address) = client_address {
+            attributes.push(KeyValue::new("client.address", client_address));
         }
 
         for (header_name, header_values) in self.headers.iter().filter_map(|hn| {
@@ -188,10 +1569,29 @@ where
             ));
         }
 
+        if self.enable_upgrade_header {
+            if let Some(upgrade) = conn.request_headers().get_values(KnownHeaderName::Upgrade) {
+                let protocols = upgrade
+                    .iter()
+                    .filter_map(|value| value.as_str())
+                    .flat_map(|value| value.split(','))
+                    .map(str::trim)
+                    .filter(|protocol| !protocol.is_empty())
+                    .map(|protocol| protocol.to_ascii_lowercase().into())
+                    .collect::<Vec<_>>();
+                if !protocols.is_empty() {
+                    attributes.push(KeyValue::new(
+                        "http.request.header.upgrade",
+                        Value::Array(Array::String(protocols)),
+                    ));
+                }
+            }
+        }
+
         let address_and_port = conn.inner().host().map(|host| {
             host.split_once(':')
                 .and_then(|(host, port)| Some((String::from(host), port.parse().ok()?)))
-                .unwrap_or_else(|| (String::from(host), if conn.is_secure() { 443 } else { 80 }))
+                .unwrap_or_else(|| (String::from(host), if scheme == "https" { 443 } else { 80 }))
         });
 
         if let Some((address, port)) = address_and_port {
@@ -199,88 +1599,437 @@ where
             attributes.push(KeyValue::new("server.port", port));
         }
 
+        if self.enable_auth_scheme {
+            let auth_scheme = conn
+                .request_headers()
+                .get_str(KnownHeaderName::Authorization)
+                .map_or("none", auth_scheme);
+            attributes.push(KeyValue::new("http.request.auth.scheme", auth_scheme));
+        }
+
         if let Some(user_agent) = conn.request_headers().get_str(KnownHeaderName::UserAgent) {
             attributes.push(KeyValue::new("user_agent.original", user_agent.to_string()));
         }
 
-        let name = if let Some(route) = self.route.as_ref().and_then(|route| route(&conn)) {
-            conn.set_state(RouteWasAvailable);
-            attributes.push(KeyValue::new("http.route", route.clone()));
-            format!("{} {route}", conn.method().as_str()).into()
-        } else {
-            conn.method().as_str().into()
-        };
+        attributes.extend(user_agent::attributes(
+            &conn,
+            self.user_agent_parser.as_deref(),
+        ));
+
+        let route = resolve_route(&mut conn, self.route.as_deref());
+        let name = self.span_name(&conn, route.as_deref());
+        let sample_ratio = self
+            .route_sampling
+            .as_ref()
+            .zip(route.as_deref())
+            .and_then(|(route_sampling, route)| route_sampling(route));
+        if self.enable_datadog_compat {
+            attributes.push(KeyValue::new("operation.name", "http.server.request"));
+            attributes.push(KeyValue::new("span.type", "web"));
+            // without a route, the resource is named once the route can no longer change
+            if route.is_some() {
+                attributes.push(KeyValue::new("resource.name", name.clone()));
+            }
+        }
+
+        if let Some(route) = route {
+            conn.insert_state(RouteWasAvailable);
+            attributes.push(KeyValue::new("http.route", route));
+        }
+
+        if self.enable_zipkin_compat {
+            attributes.push(KeyValue::new("http.method", method));
+            attributes.push(KeyValue::new("http.path", conn.path().to_string()));
+        }
+
+        if let Some(max_attributes) = self.max_attributes {
+            // leave room for the required attributes that are recorded in before_send
+            let reserved = 1 + usize::from(
+                !attributes
+                    .iter()
+                    .any(|attribute| attribute.key.as_str() == "http.route"),
+            );
+            truncate_attributes(
+                &mut attributes,
+                max_attributes.saturating_sub(reserved),
+                self.error_type_key,
+            );
+            conn.insert_state(AttributeBudget(
+                max_attributes.saturating_sub(attributes.len()),
+            ));
+        }
 
-        let span = self.tracer.build(SpanBuilder {
+        if self.enable_sorted_attributes {
+            sort_attributes(&mut attributes);
+        }
+
+        let builder = SpanBuilder {
             name,
-            start_time,
+            start_time: Some(start_time),
             span_kind: Some(SpanKind::Server),
             attributes: Some(attributes),
             ..SpanBuilder::default()
-        });
-        let context = Context::current_with_span(span);
+        };
 
-        conn.with_state(TraceContext { context })
+        #[cfg(feature = "testing")]
+        let builder = match &self.id_generator {
+            Some(id_generator) => {
+                let (trace_id, span_id) = id_generator();
+                SpanBuilder {
+                    trace_id: Some(trace_id),
+                    span_id: Some(span_id),
+                    ..builder
+                }
+            }
+            None => builder,
+        };
+
+        let parent = self.parent_context(&conn);
+        let builder = match sample_ratio {
+            Some(ratio) => SpanBuilder {
+                sampling_result: Some(sample(ratio, &parent, builder.trace_id)),
+                ..builder
+            },
+            None => builder,
+        };
+
+        if self.tail_sampling_threshold.is_some() {
+            return conn.with_state(PendingSpan(builder, parent));
+        }
+
+        let span = self.tracer.build_with_context(builder, &parent);
+        let context = parent.with_span(span);
+
+        if let Some(operation_span) = &self.operation_span {
+            conn.insert_state(OperationSpan(Arc::clone(operation_span)));
+        }
+
+        conn.with_state(PanicGuard {
+            context: context.clone(),
+            clock: self.clock.clone(),
+            error_type_key: self.error_type_key,
+        })
+        .with_state(TraceContext {
+            context,
+            depth: 0,
+            subtree_attributes: Arc::new([]),
+        })
     }
 
     async fn before_send(&self, mut conn: Conn) -> Conn {
-        let Some(TraceContext { context }) = conn.state().cloned() else {
-            return conn;
-        };
+        drop(conn.take_state::<PanicGuard>());
 
-        let span = context.span();
+        let timed_out = self.timeout.as_ref().is_some_and(|timeout| timeout(&conn));
 
-        let error_type = self
-            .error_type
-            .as_ref()
-            .and_then(|et| et(&conn))
+        let error_type = timed_out
+            .then_some(Cow::Borrowed("timeout"))
+            .or_else(|| self.error_type.as_ref().and_then(|et| et(&conn)))
+            .or_else(|| status_error_type(&conn, &self.status_error_types))
             .or_else(|| {
                 let status = conn.status().unwrap_or(Status::NotFound);
-                if status.is_server_error() {
+                if status.is_server_error() && !self.disable_default_error_type {
                     Some((status as u16).to_string().into())
                 } else {
                     None
                 }
             });
 
-        if conn.status().is_some_and(|s| s.is_server_error()) {
+        let context = match (
+            conn.take_state::<PendingSpan>(),
+            self.tail_sampling_threshold,
+        ) {
+            (Some(PendingSpan(builder, parent)), Some(threshold)) => {
+                if error_type.is_none()
+                    && !conn.status().is_some_and(|s| s.is_server_error())
+                    && conn.inner().start_time().elapsed() < threshold
+                {
+                    conn.take_state::<RouteWasAvailable>();
+                    return conn;
+                }
+
+                let context = parent.with_span(self.tracer.build_with_context(builder, &parent));
+                conn.insert_state(TraceContext {
+                    context: context.clone(),
+                    depth: 0,
+                    subtree_attributes: Arc::new([]),
+                });
+                context
+            }
+
+            _ => match conn.state::<TraceContext>() {
+                Some(TraceContext { context, .. }) => context.clone(),
+                None => return conn,
+            },
+        };
+
+        let span = context.span();
+
+        if let Some(SpanStatusOverride(status)) = conn.take_state() {
+            span.set_status(status);
+        } else if timed_out {
             span.set_status(opentelemetry::trace::Status::Error {
-                description: "".into(), // see error.type
+                description: "timeout".into(),
             });
+        } else if conn.status().is_some_and(|s| s.is_server_error()) {
+            // see error.type
+            let description = self
+                .error_description
+                .as_ref()
+                .and_then(|error_description| error_description(&conn))
+                .unwrap_or_default();
+            span.set_status(opentelemetry::trace::Status::Error { description });
         }
 
         let status: i64 = (conn.status().unwrap_or(Status::NotFound) as u16).into();
 
         let mut attributes = vec![KeyValue::new("http.response.status_code", status)];
 
+        if self.enable_zipkin_compat {
+            attributes.push(KeyValue::new("http.status_code", status.to_string()));
+        }
+
+        if self.enable_upgrade_protocol_name {
+            attributes.push(KeyValue::new("network.protocol.name", protocol_name(&conn)));
+        }
+
         if conn.take_state::<RouteWasAvailable>().is_none() {
-            let route = self.route.as_ref().and_then(|route| route(&conn));
+            let route = resolve_route(&mut conn, self.route.as_deref());
             if let Some(route) = &route {
+                span.update_name(self.span_name(&conn, Some(route)));
+                if self.enable_late_route_event {
+                    span.add_event(
+                        "trillium.route.late_resolution",
+                        vec![KeyValue::new("http.route", route.clone())],
+                    );
+                }
                 attributes.push(KeyValue::new("http.route", route.clone()));
-                span.update_name(format!("{} {route}", conn.method().as_str()));
+            }
+
+            if self.enable_datadog_compat {
+                attributes.push(KeyValue::new(
+                    "resource.name",
+                    self.span_name(&conn, route.as_deref()),
+                ));
             }
         }
 
         if let Some(error_type) = error_type {
-            attributes.push(KeyValue::new("error.type", error_type));
+            attributes.push(KeyValue::new(self.error_type_key, error_type));
         }
 
-        span.set_attributes(attributes);
+        if let Some(route_params) = &self.route_params {
+            for (name, value) in route_params(&conn) {
+                attributes.push(KeyValue::new(
+                    format!("url.path.param.{name}"),
+                    value.into_owned(),
+                ));
+            }
+        }
+
+        if self.enable_duration_attribute {
+            let mut duration_ms = conn.inner().start_time().elapsed().as_secs_f64() * 1000.0;
+            if let Some(granularity) = self.duration_attribute_granularity {
+                let granularity_ms = granularity.as_secs_f64() * 1000.0;
+                duration_ms = (duration_ms / granularity_ms).round() * granularity_ms;
+            }
+            attributes.push(KeyValue::new("http.server.duration_ms", duration_ms));
+        }
+
+        if self.enable_response_header_stats {
+            let (count, bytes) =
+                conn.response_headers()
+                    .iter()
+                    .fold((0, 0), |(count, bytes), (name, values)| {
+                        values.iter().fold((count, bytes), |(count, bytes), value| {
+                            // serialized as `{name}: {value}\r\n`
+                            let len = name.as_ref().len() + value.as_ref().len() + 4;
+                            (count + 1, bytes + len as i64)
+                        })
+                    });
+            attributes.push(KeyValue::new("http.response.header.count", count));
+            attributes.push(KeyValue::new("http.response.header.bytes", bytes));
+        }
+
+        for (key, attribute) in &self.boolean_attributes {
+            attributes.push(KeyValue::new(key.clone(), attribute(&conn)));
+        }
+
+        for state_attributes in &self.state_attributes {
+            attributes.extend(state_attributes(&conn).into_iter().flatten());
+        }
+
+        if self.enable_redirect_cross_origin && conn.status().is_some_and(|s| s.is_redirection()) {
+            if let Some(location) = conn.response_headers().get_str(KnownHeaderName::Location) {
+                attributes.push(KeyValue::new(
+                    "http.response.redirect.cross_origin",
+                    is_cross_origin(&conn, scheme(&conn, self.scheme.as_deref()), location),
+                ));
+            }
+        }
+
+        if let Some(header) = &self.correlation_id_header {
+            let correlation_id = conn
+                .request_headers()
+                .get_str(header.clone())
+                .map(String::from)
+                .or_else(|| {
+                    let span_context = span.span_context();
+                    (self.enable_correlation_id_generation && span_context.is_valid())
+                        .then(|| span_context.trace_id().to_string())
+                });
+
+            if let Some(correlation_id) = correlation_id {
+                if self.enable_correlation_id_generation {
+                    conn.response_headers_mut()
+                        .try_insert(header.clone(), correlation_id.clone());
+                }
+                attributes.push(KeyValue::new("trillium.correlation_id", correlation_id));
+            }
+        }
 
+        if let Some(request_id) = self
+            .request_id
+            .as_ref()
+            .and_then(|request_id| request_id(&conn))
         {
+            attributes.push(KeyValue::new("trillium.request.id", request_id));
+        }
+
+        if let Some(max_attributes) = self.max_attributes {
+            let budget = conn
+                .take_state::<AttributeBudget>()
+                .map_or(max_attributes, |AttributeBudget(budget)| budget);
+            truncate_attributes(&mut attributes, budget, self.error_type_key);
+        }
+
+        if self.enable_sorted_attributes {
+            sort_attributes(&mut attributes);
+        }
+
+        span.set_attributes(attributes);
+
+        #[cfg(feature = "cpu-time")]
+        let cpu_time_start = conn.take_state::<CpuTimeStart>();
+
+        if self.enable_end_before_body_send {
+            #[cfg(feature = "cpu-time")]
+            record_cpu_time(&span, cpu_time_start);
+            span.end_with_timestamp(now(self.clock.as_deref()));
+        } else {
             let context = context.clone();
+            let clock = self.clock.clone();
+            let error_type_key = self.error_type_key;
             conn.inner_mut().after_send(move |send_status| {
                 let span = context.span();
+                #[cfg(feature = "cpu-time")]
+                record_cpu_time(&span, cpu_time_start);
                 if !send_status.is_success() {
                     span.set_status(opentelemetry::trace::Status::Error {
                         description: "http send error".into(),
                     });
-                    span.set_attribute(KeyValue::new("error.type", "http send error"));
+                    span.set_attribute(KeyValue::new(error_type_key, "http send error"));
                 }
-                span.end();
+                span.end_with_timestamp(now(clock.as_deref()));
             });
         }
 
         conn
     }
 }
+
+/// decides whether to sample a request span at `ratio`. like the sdk's `TraceIdRatioBased`
+/// sampler, the decision is derived from the trace id when one is known (the parent's, or one
+/// assigned to the builder), so every span in a trace agrees. root spans without an assigned trace
+/// id fall back to randomness, since the tracer has not generated their id yet.
+fn sample(ratio: f64, parent: &Context, trace_id: Option<TraceId>) -> SamplingResult {
+    let parent_span_context = parent.span().span_context().clone();
+    let trace_id = Some(parent_span_context.trace_id())
+        .filter(|trace_id| *trace_id != TraceId::INVALID)
+        .or(trace_id);
+
+    // the low 63 bits of the trace id, or 63 random bits
+    let random = match trace_id {
+        Some(trace_id) => u64::from_be_bytes(trace_id.to_bytes()[8..].try_into().unwrap()) >> 1,
+        None => rand::random::<u64>() >> 1,
+    };
+
+    let decision = if ratio >= 1.0 || random < (ratio.max(0.0) * (1u64 << 63) as f64) as u64 {
+        SamplingDecision::RecordAndSample
+    } else {
+        SamplingDecision::Drop
+    };
+
+    SamplingResult {
+        decision,
+        attributes: vec![],
+        trace_state: parent_span_context.trace_state().clone(),
+    }
+}
+
+/// the lowercased scheme of an `Authorization` header value, if it is a registered scheme. this
+/// never returns any part of the credentials.
+fn auth_scheme(authorization: &str) -> &'static str {
+    const SCHEMES: &[&str] = &[
+        "basic",
+        "bearer",
+        "concealed",
+        "digest",
+        "dpop",
+        "gnap",
+        "hoba",
+        "mutual",
+        "negotiate",
+        "oauth",
+        "privatetoken",
+        "scram-sha-1",
+        "scram-sha-256",
+        "vapid",
+    ];
+
+    let scheme = authorization
+        .split_ascii_whitespace()
+        .next()
+        .unwrap_or_default();
+    SCHEMES
+        .iter()
+        .find(|known| known.eq_ignore_ascii_case(scheme))
+        .copied()
+        .unwrap_or("other")
+}
+
+fn now(clock: Option<&ClockFn>) -> SystemTime {
+    clock.map_or_else(SystemTime::now, |clock| clock())
+}
+
+fn is_cross_origin(conn: &Conn, request_scheme: &str, location: &str) -> bool {
+    let (scheme, rest) = match location.split_once("://") {
+        Some((scheme, rest)) => (scheme, rest),
+        None => match location.strip_prefix("//") {
+            Some(rest) => (request_scheme, rest),
+            None => return false,
+        },
+    };
+
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let authority = authority.rsplit('@').next().unwrap_or_default();
+
+    !scheme.eq_ignore_ascii_case(request_scheme)
+        || !conn.inner().host().is_some_and(|host| {
+            without_default_port(authority, scheme)
+                .eq_ignore_ascii_case(without_default_port(host, request_scheme))
+        })
+}
+
+/// the authority without its port if it is the default port for the scheme, so that
+/// `example.com:443` and `example.com` are the same https origin
+fn without_default_port<'a>(authority: &'a str, scheme: &str) -> &'a str {
+    let default_port = if scheme.eq_ignore_ascii_case("https") {
+        ":443"
+    } else if scheme.eq_ignore_ascii_case("http") {
+        ":80"
+    } else {
+        return authority;
+    };
+
+    authority.strip_suffix(default_port).unwrap_or(authority)
+}
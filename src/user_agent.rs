@@ -0,0 +1,35 @@
+use opentelemetry::KeyValue;
+use std::borrow::Cow;
+use trillium::{Conn, KnownHeaderName};
+
+pub(crate) type UserAgentParserFn = dyn Fn(&str) -> UserAgentInfo + Send + Sync + 'static;
+
+/// Low-cardinality information parsed from a `User-Agent` request header.
+///
+/// This is returned by the parser provided to `with_user_agent_parser`. This crate does not
+/// include a user agent parser.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserAgentInfo {
+    /// The browser or client name, recorded as `user_agent.name`. For example, `"Firefox"`.
+    pub name: Option<Cow<'static, str>>,
+
+    /// The operating system name, recorded as `user_agent.os.name`. For example, `"iOS"`.
+    pub os_name: Option<Cow<'static, str>>,
+}
+
+pub(crate) fn attributes(conn: &Conn, parser: Option<&UserAgentParserFn>) -> Vec<KeyValue> {
+    let Some(parser) = parser else {
+        return vec![];
+    };
+
+    let Some(user_agent) = conn.request_headers().get_str(KnownHeaderName::UserAgent) else {
+        return vec![];
+    };
+
+    let UserAgentInfo { name, os_name } = parser(user_agent);
+
+    name.map(|name| KeyValue::new("user_agent.name", name))
+        .into_iter()
+        .chain(os_name.map(|os_name| KeyValue::new("user_agent.os.name", os_name)))
+        .collect()
+}
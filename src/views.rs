@@ -0,0 +1,45 @@
+use crate::{metrics::versioned_scope, Metrics};
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry_sdk::metrics::{
+    new_view, Aggregation, Instrument, MeterProviderBuilder, MetricResult, SdkMeterProvider, Stream,
+};
+use opentelemetry_semantic_conventions as semconv;
+
+/// Builds a meter provider that records the request and response body size histograms with
+/// base-2 exponential bucketing, and a [`Metrics`] handler that records to it.
+///
+/// Body sizes span many orders of magnitude, which the default explicit bucket boundaries
+/// represent poorly. This adds a [`View`](opentelemetry_sdk::metrics::View) for each of
+/// `http.server.request.body.size` and `http.server.response.body.size` to the provided builder,
+/// so the builder should already have its readers and resource configured. Other instruments keep
+/// their default aggregation. The meter provider is returned so that it can be registered
+/// globally and shut down once the server has stopped. The handler's meter carries this crate's
+/// name, version and schema url.
+///
+/// ```
+/// use opentelemetry_sdk::metrics::SdkMeterProvider;
+/// let (meter_provider, metrics) =
+///     trillium_opentelemetry::exponential_size_histograms(SdkMeterProvider::builder()).unwrap();
+/// ```
+pub fn exponential_size_histograms(
+    mut builder: MeterProviderBuilder,
+) -> MetricResult<(SdkMeterProvider, Metrics)> {
+    for name in [
+        semconv::metric::HTTP_SERVER_REQUEST_BODY_SIZE,
+        semconv::metric::HTTP_SERVER_RESPONSE_BODY_SIZE,
+    ] {
+        builder = builder.with_view(new_view(
+            Instrument::new().name(name),
+            Stream::new().aggregation(Aggregation::Base2ExponentialHistogram {
+                max_size: 160,
+                max_scale: 20,
+                record_min_max: true,
+            }),
+        )?);
+    }
+
+    let meter_provider = builder.build();
+    let metrics =
+        Metrics::new(meter_provider.meter_with_scope(versioned_scope(env!("CARGO_PKG_NAME"))));
+    Ok((meter_provider, metrics))
+}
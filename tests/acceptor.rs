@@ -0,0 +1,45 @@
+mod common;
+
+use common::Telemetry;
+use trillium_opentelemetry::InstrumentAcceptor;
+use trillium_server_common::{async_trait, Acceptor, Transport};
+use trillium_testing::TestTransport;
+
+/// a stand-in for a tls acceptor, which fails the handshake when `fail` is true
+#[derive(Clone, Debug)]
+struct FakeTls {
+    fail: bool,
+}
+
+#[async_trait]
+impl<Input: Transport> Acceptor<Input> for FakeTls {
+    type Output = Input;
+    type Error = &'static str;
+
+    async fn accept(&self, input: Input) -> Result<Self::Output, Self::Error> {
+        if self.fail {
+            Err("handshake failed")
+        } else {
+            Ok(input)
+        }
+    }
+}
+
+#[test]
+fn counts_failed_handshakes() {
+    let telemetry = Telemetry::new();
+    let meter = telemetry.meter();
+    let failing = InstrumentAcceptor::new(FakeTls { fail: true }, &meter);
+    let succeeding = InstrumentAcceptor::new(FakeTls { fail: false }, &meter);
+
+    trillium_testing::block_on(async {
+        assert!(failing.accept(TestTransport::new().0).await.is_err());
+        assert!(failing.accept(TestTransport::new().0).await.is_err());
+        assert!(succeeding.accept(TestTransport::new().0).await.is_ok());
+    });
+
+    let point = telemetry.point("tls.handshake.errors");
+    assert_eq!(point.value, 2.0);
+    assert_eq!(point.unit, "{connection}");
+    assert!(point.attributes.is_empty());
+}
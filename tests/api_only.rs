@@ -0,0 +1,52 @@
+//! the `metrics` and `trace` features only depend on the opentelemetry api, so that applications
+//! can bring their own sdk. test builds enable every feature through the dev-dependency on this
+//! crate, so these run cargo against the manifest with only those features enabled.
+
+use std::{path::Path, process::Command};
+
+const FEATURES: [&str; 3] = ["metrics", "trace", "metrics,trace"];
+
+fn cargo(subcommand: &str, features: &str) -> Command {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut command = Command::new(env!("CARGO"));
+    command
+        .arg(subcommand)
+        .arg("--manifest-path")
+        .arg(manifest_dir.join("Cargo.toml"))
+        .args(["--no-default-features", "--features", features])
+        // a separate target directory, since the one running this test is locked
+        .env(
+            "CARGO_TARGET_DIR",
+            manifest_dir.join("target").join("api-only"),
+        );
+    command
+}
+
+#[test]
+fn metrics_and_trace_do_not_depend_on_the_sdk() {
+    for features in FEATURES {
+        let output = cargo("tree", features)
+            .args(["--edges", "normal", "--prefix", "none"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let tree = String::from_utf8(output.stdout).unwrap();
+        assert!(!tree.contains("opentelemetry_sdk"), "{features}: {tree}");
+    }
+}
+
+#[test]
+fn metrics_and_trace_build_without_the_sdk() {
+    for features in FEATURES {
+        let output = cargo("check", features).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{features}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
@@ -0,0 +1,418 @@
+#![allow(dead_code)]
+
+use opentelemetry::{
+    metrics::{Meter, MeterProvider as _},
+    trace::TracerProvider as _,
+    InstrumentationScope, KeyValue, Value,
+};
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    metrics::{
+        data::{ExponentialHistogram, Histogram, ResourceMetrics, Sum},
+        reader::MetricReader,
+        InstrumentKind, ManualReader, MeterProviderBuilder, MetricResult, Pipeline,
+        SdkMeterProvider, Temporality,
+    },
+    testing::trace::InMemorySpanExporter,
+    trace::{Sampler, Tracer, TracerProvider},
+};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+use trillium::Handler;
+use trillium_testing::{futures_lite::AsyncRead, ServerConnector};
+
+/// an in-memory tracer provider and meter provider to assert on what a handler recorded
+pub struct Telemetry {
+    exporter: InMemorySpanExporter,
+    reader: SharedReader,
+    tracer_provider: TracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self::with_temporality(Temporality::Cumulative)
+    }
+
+    pub fn with_temporality(temporality: Temporality) -> Self {
+        Self::build(temporality, Sampler::AlwaysOn)
+    }
+
+    pub fn with_sampler(sampler: Sampler) -> Self {
+        Self::build(Temporality::Cumulative, sampler)
+    }
+
+    /// builds the meter provider with `build`, from a builder that already has the reader
+    pub fn with_meter_provider(
+        build: impl FnOnce(MeterProviderBuilder) -> SdkMeterProvider,
+    ) -> Self {
+        Self::build_with(Temporality::Cumulative, Sampler::AlwaysOn, build)
+    }
+
+    fn build(temporality: Temporality, sampler: Sampler) -> Self {
+        Self::build_with(temporality, sampler, MeterProviderBuilder::build)
+    }
+
+    fn build_with(
+        temporality: Temporality,
+        sampler: Sampler,
+        build: impl FnOnce(MeterProviderBuilder) -> SdkMeterProvider,
+    ) -> Self {
+        let exporter = InMemorySpanExporter::default();
+        let reader = SharedReader(Arc::new(
+            ManualReader::builder()
+                .with_temporality(temporality)
+                .build(),
+        ));
+        Self {
+            tracer_provider: TracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .with_sampler(sampler)
+                .build(),
+            meter_provider: build(SdkMeterProvider::builder().with_reader(reader.clone())),
+            exporter,
+            reader,
+        }
+    }
+
+    pub fn tracer(&self) -> Tracer {
+        self.tracer_provider.tracer("test")
+    }
+
+    pub fn meter(&self) -> Meter {
+        self.meter_provider.meter("test")
+    }
+
+    /// every span that has ended, in the order they ended
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.exporter.get_finished_spans().unwrap()
+    }
+
+    /// the only span that has ended
+    pub fn span(&self) -> SpanData {
+        let mut spans = self.spans();
+        assert_eq!(spans.len(), 1, "expected one span, found {spans:#?}");
+        spans.remove(0)
+    }
+
+    /// the ended span with this name
+    pub fn span_named(&self, name: &str) -> SpanData {
+        self.spans()
+            .into_iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("no span named {name} in {:#?}", self.spans()))
+    }
+
+    /// the meter provider, to install as the global meter provider
+    pub fn meter_provider(&self) -> SdkMeterProvider {
+        self.meter_provider.clone()
+    }
+
+    /// the instrumentation scope of every meter that has recorded, as of now
+    pub fn scopes(&self) -> Vec<InstrumentationScope> {
+        self.collect()
+            .scope_metrics
+            .into_iter()
+            .map(|scope_metrics| scope_metrics.scope)
+            .collect()
+    }
+
+    fn collect(&self) -> ResourceMetrics {
+        let mut resource_metrics = ResourceMetrics {
+            resource: Default::default(),
+            scope_metrics: vec![],
+        };
+        self.reader.collect(&mut resource_metrics).unwrap();
+        resource_metrics
+    }
+
+    /// every data point of every instrument, as of now
+    pub fn points(&self) -> Vec<Point> {
+        let mut points = vec![];
+        for metric in self
+            .collect()
+            .scope_metrics
+            .into_iter()
+            .flat_map(|scope_metrics| scope_metrics.metrics)
+        {
+            let name = metric.name.to_string();
+            let unit = metric.unit.to_string();
+            let data = metric.data.as_any();
+            let point = |attributes: &[KeyValue], value: f64, count: u64, bounds: &[f64]| Point {
+                name: name.clone(),
+                unit: unit.clone(),
+                attributes: attributes.to_vec(),
+                value,
+                count,
+                bounds: bounds.to_vec(),
+                scale: None,
+            };
+
+            if let Some(histogram) = data.downcast_ref::<Histogram<f64>>() {
+                points.extend(histogram.data_points.iter().map(|data_point| {
+                    point(
+                        &data_point.attributes,
+                        data_point.sum,
+                        data_point.count,
+                        &data_point.bounds,
+                    )
+                }));
+            } else if let Some(histogram) = data.downcast_ref::<Histogram<u64>>() {
+                points.extend(histogram.data_points.iter().map(|data_point| {
+                    point(
+                        &data_point.attributes,
+                        data_point.sum as f64,
+                        data_point.count,
+                        &data_point.bounds,
+                    )
+                }));
+            } else if let Some(histogram) = data.downcast_ref::<ExponentialHistogram<u64>>() {
+                points.extend(histogram.data_points.iter().map(|data_point| Point {
+                    scale: Some(data_point.scale),
+                    ..point(
+                        &data_point.attributes,
+                        data_point.sum as f64,
+                        data_point.count as u64,
+                        &[],
+                    )
+                }));
+            } else if let Some(sum) = data.downcast_ref::<Sum<u64>>() {
+                points.extend(sum.data_points.iter().map(|data_point| {
+                    point(&data_point.attributes, data_point.value as f64, 0, &[])
+                }));
+            } else if let Some(sum) = data.downcast_ref::<Sum<i64>>() {
+                points.extend(sum.data_points.iter().map(|data_point| {
+                    point(&data_point.attributes, data_point.value as f64, 0, &[])
+                }));
+            } else {
+                panic!("unexpected data for {name}");
+            }
+        }
+        points
+    }
+
+    /// every data point of the named instrument
+    pub fn points_named(&self, name: &str) -> Vec<Point> {
+        self.points()
+            .into_iter()
+            .filter(|point| point.name == name)
+            .collect()
+    }
+
+    /// the only data point of the named instrument
+    pub fn point(&self, name: &str) -> Point {
+        let mut points = self.points_named(name);
+        assert_eq!(
+            points.len(),
+            1,
+            "expected one {name} point, found {:#?}",
+            self.points()
+        );
+        points.remove(0)
+    }
+
+    /// whether any data points were recorded for the named instrument
+    pub fn has_metric(&self, name: &str) -> bool {
+        self.points().iter().any(|point| point.name == name)
+    }
+}
+
+/// a single data point, flattened across instrument kinds. for histograms, `value` is the sum of
+/// the recorded values
+#[derive(Debug, Clone)]
+pub struct Point {
+    pub name: String,
+    pub unit: String,
+    pub attributes: Vec<KeyValue>,
+    pub value: f64,
+    pub count: u64,
+    pub bounds: Vec<f64>,
+    /// the scale of an exponential histogram, which is None for every other kind of instrument
+    pub scale: Option<i8>,
+}
+
+impl Point {
+    pub fn attribute(&self, key: &str) -> Option<String> {
+        attribute(&self.attributes, key)
+    }
+}
+
+/// the value of the attribute with this key, formatted as a string
+pub fn attribute(attributes: &[KeyValue], key: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == key)
+        .map(|kv| match &kv.value {
+            Value::String(s) => s.to_string(),
+            other => other.to_string(),
+        })
+}
+
+/// the value of the span attribute with this key, formatted as a string
+pub fn span_attribute(span: &SpanData, key: &str) -> Option<String> {
+    attribute(&span.attributes, key)
+}
+
+#[derive(Clone, Debug)]
+struct SharedReader(Arc<ManualReader>);
+
+impl MetricReader for SharedReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, resource_metrics: &mut ResourceMetrics) -> MetricResult<()> {
+        self.0.collect(resource_metrics)
+    }
+
+    fn force_flush(&self) -> MetricResult<()> {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> MetricResult<()> {
+        self.0.shutdown()
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+/// an initialized handler served over an in-memory transport, so that responses are actually
+/// sent and after_send callbacks see a successful send
+pub struct Server<H>(ServerConnector<H>);
+
+impl<H: Handler> Server<H> {
+    pub fn new(mut handler: H) -> Self {
+        trillium_testing::init(&mut handler);
+        Self(ServerConnector::new(handler))
+    }
+
+    /// like [`Server::new`], but initializes the handler with the provided server info
+    pub fn with_info(mut handler: H, mut info: trillium::Info) -> Self {
+        trillium_testing::block_on(handler.init(&mut info));
+        Self(ServerConnector::new(handler))
+    }
+
+    /// sends a request with a host of example.com and returns the raw response
+    pub fn request(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Response {
+        self.request_with_body(method, path, headers, "")
+    }
+
+    pub fn get(&self, path: &str) -> Response {
+        self.request("GET", path, &[])
+    }
+
+    pub fn request_with_body(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Response {
+        let mut request = format!("{method} {path} HTTP/1.1\r\nConnection: close\r\n");
+        if !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("host"))
+        {
+            request.push_str("Host: example.com\r\n");
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        self.raw(&request)
+    }
+
+    /// writes the raw bytes of a request and reads the response until the server closes the
+    /// connection
+    pub fn raw(&self, request: &str) -> Response {
+        trillium_testing::block_on(async {
+            use trillium_testing::futures_lite::AsyncReadExt;
+            let mut client = self.0.connect(false).await;
+            client.write_all(request);
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            Response::parse(response)
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    fn parse(response: String) -> Self {
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|status_line| status_line.split(' ').nth(1))
+            .and_then(|status| status.parse().ok())
+            .unwrap_or_default();
+        let headers = lines
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+            .collect();
+        Self {
+            status,
+            headers,
+            body: body.to_string(),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &**value)
+    }
+}
+
+/// a streaming response body that takes `delay` to produce each chunk after the first
+pub struct DelayedChunks {
+    chunks: VecDeque<&'static str>,
+    delay: Duration,
+    first: bool,
+}
+
+impl DelayedChunks {
+    pub fn new(chunks: impl IntoIterator<Item = &'static str>, delay: Duration) -> Self {
+        Self {
+            chunks: chunks.into_iter().collect(),
+            delay,
+            first: true,
+        }
+    }
+}
+
+impl AsyncRead for DelayedChunks {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let Some(chunk) = self.chunks.pop_front() else {
+            return Poll::Ready(Ok(0));
+        };
+        if !std::mem::take(&mut self.first) {
+            std::thread::sleep(self.delay);
+        }
+        buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+        Poll::Ready(Ok(chunk.len()))
+    }
+}
@@ -0,0 +1,567 @@
+mod common;
+
+use common::{span_attribute, Server, Telemetry};
+use trillium::Conn;
+use trillium_opentelemetry::{instrument, MetricsConnExt, TraceConnExt};
+
+#[test]
+fn filter_skips_both_trace_and_metrics() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer())
+            .with_filter(|conn| conn.path() == "/health"),
+        |conn: Conn| async move {
+            let state = format!("{} {}", conn.metrics_meter().is_some(), conn.is_sampled());
+            conn.ok(state)
+        },
+    ));
+
+    assert_eq!(server.get("/health").body, "false false");
+    assert!(telemetry.spans().is_empty());
+    assert!(telemetry.points().is_empty());
+
+    assert_eq!(server.get("/").body, "true true");
+    assert_eq!(telemetry.span().name, "GET");
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("url.scheme")
+            .as_deref(),
+        Some("http")
+    );
+}
+
+struct ErrorState(&'static str);
+
+/// sets error state in before_send, after the response body has been set
+struct SetsErrorInBeforeSend;
+
+#[trillium::async_trait]
+impl trillium::Handler for SetsErrorInBeforeSend {
+    async fn run(&self, conn: Conn) -> Conn {
+        conn
+    }
+
+    async fn before_send(&self, conn: Conn) -> Conn {
+        conn.with_state(ErrorState("set in before_send"))
+    }
+}
+
+#[test]
+fn error_type_reads_state_set_in_before_send() {
+    use std::borrow::Cow;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()).with_error_type(|conn| {
+            conn.state::<ErrorState>()
+                .map(|ErrorState(error)| Cow::Borrowed(*error))
+        }),
+        SetsErrorInBeforeSend,
+        "ok",
+    ));
+
+    server.get("/");
+    assert_eq!(
+        span_attribute(&telemetry.span(), "error.type").as_deref(),
+        Some("set in before_send")
+    );
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("error.type")
+            .as_deref(),
+        Some("set in before_send")
+    );
+}
+
+#[test]
+fn clones_share_tracer_and_instruments() {
+    let telemetry = Telemetry::new();
+    let instrument = instrument(telemetry.meter(), telemetry.tracer())
+        .with_route(|conn| Some(std::borrow::Cow::Owned(conn.path().to_string())));
+    let first = Server::new((instrument.clone(), "ok"));
+    let second = Server::new((instrument, "ok"));
+
+    first.get("/");
+    second.get("/");
+
+    assert_eq!(
+        telemetry
+            .spans()
+            .iter()
+            .map(|span| &*span.name)
+            .collect::<Vec<_>>(),
+        ["GET /", "GET /"]
+    );
+    assert_eq!(telemetry.point("http.server.request.duration").count, 2);
+}
+
+#[test]
+fn metrics_durations_ignore_clock_jumps() {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::{Duration, SystemTime},
+    };
+    // a clock that jumps an hour backwards every time it is read, as after an ntp adjustment
+    let seconds = AtomicU64::new(1_000_000_000);
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()).with_clock(move || {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(seconds.fetch_sub(3600, Ordering::SeqCst))
+        }),
+        |conn: Conn| async move {
+            std::thread::sleep(Duration::from_millis(10));
+            conn.ok("ok")
+        },
+    ));
+
+    server.get("/");
+    let duration = telemetry.point("http.server.request.duration").value;
+    assert!((0.01..1.0).contains(&duration), "{duration}");
+}
+
+#[test]
+fn route_is_resolved_once_for_span_and_metrics() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let calls = AtomicUsize::new(0);
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()).with_route(move |_| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            Some(format!("/route/{call}").into())
+        }),
+        "ok",
+    ));
+
+    server.get("/");
+    let span_route = span_attribute(&telemetry.span(), "http.route");
+    assert_eq!(span_route.as_deref(), Some("/route/0"));
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("http.route"),
+        span_route
+    );
+}
+
+#[test]
+fn router_route_matches_on_span_and_metrics() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()).with_router_route(),
+        trillium_router::router().get("/users/:id", "ok"),
+    ));
+
+    server.get("/users/1");
+    let span = telemetry.span();
+    assert_eq!(
+        span_attribute(&span, "http.route").as_deref(),
+        Some("/users/:id")
+    );
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("http.route")
+            .as_deref(),
+        Some("/users/:id")
+    );
+}
+
+#[test]
+fn user_agent_parser() {
+    use trillium_opentelemetry::UserAgentInfo;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()).with_user_agent_parser(|user_agent| {
+            if user_agent.contains("Firefox") {
+                UserAgentInfo {
+                    name: Some("Firefox".into()),
+                    os_name: Some("Linux".into()),
+                }
+            } else {
+                UserAgentInfo::default()
+            }
+        }),
+        "ok",
+    ));
+
+    let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:130.0) Gecko/20100101 Firefox/130.0";
+    server.request("GET", "/", &[("User-Agent", firefox)]);
+    let span = telemetry.span();
+    assert_eq!(
+        span_attribute(&span, "user_agent.name").as_deref(),
+        Some("Firefox")
+    );
+    assert_eq!(
+        span_attribute(&span, "user_agent.os.name").as_deref(),
+        Some("Linux")
+    );
+    assert_eq!(
+        span_attribute(&span, "user_agent.original").as_deref(),
+        Some(firefox)
+    );
+    let point = telemetry.point("http.server.request.duration");
+    assert_eq!(
+        point.attribute("user_agent.name").as_deref(),
+        Some("Firefox")
+    );
+    assert_eq!(
+        point.attribute("user_agent.os.name").as_deref(),
+        Some("Linux")
+    );
+    assert_eq!(point.attribute("user_agent.original"), None);
+
+    server.request("GET", "/", &[("User-Agent", "curl/8.0")]);
+    let spans = telemetry.spans();
+    assert_eq!(span_attribute(&spans[1], "user_agent.name"), None);
+    assert_eq!(span_attribute(&spans[1], "user_agent.os.name"), None);
+}
+
+#[test]
+fn listener() {
+    let telemetry = Telemetry::new();
+    let server = Server::with_info(
+        (
+            instrument(telemetry.meter(), telemetry.tracer()).with_listener(),
+            "ok",
+        ),
+        trillium::Info::from("127.0.0.1:9090".parse::<std::net::SocketAddr>().unwrap()),
+    );
+
+    server.get("/");
+    assert_eq!(
+        span_attribute(&telemetry.span(), "trillium.listener").as_deref(),
+        Some("127.0.0.1:9090")
+    );
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("trillium.listener")
+            .as_deref(),
+        Some("127.0.0.1:9090")
+    );
+}
+
+#[test]
+fn listener_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::with_info(
+        (instrument(telemetry.meter(), telemetry.tracer()), "ok"),
+        trillium::Info::from("127.0.0.1:9090".parse::<std::net::SocketAddr>().unwrap()),
+    );
+
+    server.get("/");
+    assert_eq!(span_attribute(&telemetry.span(), "trillium.listener"), None);
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("trillium.listener"),
+        None
+    );
+}
+
+#[test]
+fn sampled_dimension() {
+    use opentelemetry_sdk::trace::Sampler;
+    for (sampler, sampled) in [(Sampler::AlwaysOn, "true"), (Sampler::AlwaysOff, "false")] {
+        let telemetry = Telemetry::with_sampler(sampler);
+        let server = Server::new((
+            instrument(telemetry.meter(), telemetry.tracer()).with_metrics_sampled_dimension(),
+            "ok",
+        ));
+
+        server.get("/");
+        assert_eq!(
+            telemetry
+                .point("http.server.request.duration")
+                .attribute("trillium.trace.sampled")
+                .as_deref(),
+            Some(sampled)
+        );
+    }
+}
+
+#[test]
+fn sampled_dimension_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((instrument(telemetry.meter(), telemetry.tracer()), "ok"));
+    server.get("/");
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("trillium.trace.sampled"),
+        None
+    );
+}
+
+#[test]
+fn error_type_key() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()).with_error_type_key("app.error.type"),
+        |conn: Conn| async move { conn.with_status(500) },
+    ));
+
+    server.get("/");
+    let span = telemetry.span();
+    assert_eq!(
+        span_attribute(&span, "app.error.type").as_deref(),
+        Some("500")
+    );
+    assert_eq!(span_attribute(&span, "error.type"), None);
+    let point = telemetry.point("http.server.request.duration");
+    assert_eq!(point.attribute("app.error.type").as_deref(), Some("500"));
+    assert_eq!(point.attribute("error.type"), None);
+}
+
+#[test]
+fn without_default_error_type() {
+    use opentelemetry::trace::Status as SpanStatus;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer())
+            .with_error_type(|conn| (conn.path() == "/managed").then_some("managed".into()))
+            .without_default_error_type(),
+        |conn: Conn| async move { conn.with_status(500) },
+    ));
+
+    server.get("/");
+    let span = telemetry.span();
+    assert_eq!(span_attribute(&span, "error.type"), None);
+    assert!(matches!(span.status, SpanStatus::Error { .. }));
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("error.type"),
+        None
+    );
+
+    server.get("/managed");
+    assert_eq!(
+        span_attribute(&telemetry.spans()[1], "error.type").as_deref(),
+        Some("managed")
+    );
+}
+
+#[test]
+fn default_error_type() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()),
+        |conn: Conn| async move { conn.with_status(500) },
+    ));
+
+    server.get("/");
+    assert_eq!(
+        span_attribute(&telemetry.span(), "error.type").as_deref(),
+        Some("500")
+    );
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("error.type")
+            .as_deref(),
+        Some("500")
+    );
+}
+
+#[test]
+fn status_error_type() {
+    use trillium::{KnownHeaderName, Status};
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer())
+            .with_status_error_type(Status::PayloadTooLarge, "request_too_large"),
+        // a body size limit that rejects the request before reading the body
+        |conn: Conn| async move {
+            let too_large = conn
+                .request_headers()
+                .get_str(KnownHeaderName::ContentLength)
+                .and_then(|len| len.parse::<u64>().ok())
+                .is_some_and(|len| len > 10);
+            if too_large {
+                conn.with_status(Status::PayloadTooLarge).halt()
+            } else {
+                conn.ok("ok")
+            }
+        },
+    ));
+
+    let response = server.request_with_body("POST", "/", &[], &"x".repeat(100));
+    assert_eq!(response.status, 413);
+    server.request_with_body("POST", "/", &[], "small");
+
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "error.type").as_deref(),
+        Some("request_too_large")
+    );
+    assert_eq!(
+        span_attribute(&spans[0], "http.response.status_code").as_deref(),
+        Some("413")
+    );
+    assert_eq!(span_attribute(&spans[1], "error.type"), None);
+
+    let mut points = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| {
+            (
+                point.attribute("http.response.status_code").unwrap(),
+                point.attribute("error.type"),
+            )
+        })
+        .collect::<Vec<_>>();
+    points.sort();
+    assert_eq!(
+        points,
+        [
+            ("200".to_string(), None),
+            ("413".to_string(), Some("request_too_large".to_string())),
+        ]
+    );
+}
+
+#[test]
+fn upgrade_protocol_name() {
+    use trillium::{KnownHeaderName, Status};
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer())
+            .with_upgrade_protocol_name()
+            .with_route(|conn| Some(conn.path().to_string().into())),
+        |conn: Conn| async move {
+            if conn.path() == "/socket" {
+                conn.with_status(Status::SwitchingProtocols)
+                    .with_response_header(KnownHeaderName::Upgrade, "WebSocket")
+                    .with_response_header(KnownHeaderName::Connection, "upgrade")
+                    .halt()
+            } else {
+                conn.ok("ok")
+            }
+        },
+    ));
+
+    server.request(
+        "GET",
+        "/socket",
+        &[("Upgrade", "websocket"), ("Sec-WebSocket-Version", "13")],
+    );
+    server.get("/");
+
+    let spans = telemetry.spans();
+    for (span, protocol) in spans.iter().zip(["websocket", "http"]) {
+        assert_eq!(
+            span_attribute(span, "network.protocol.name").as_deref(),
+            Some(protocol),
+            "{}",
+            span.name
+        );
+    }
+
+    let mut protocols = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| {
+            (
+                point.attribute("http.route").unwrap(),
+                point.attribute("network.protocol.name").unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    protocols.sort();
+    assert_eq!(
+        protocols,
+        [
+            ("/".to_string(), "http".to_string()),
+            ("/socket".to_string(), "websocket".to_string()),
+        ]
+    );
+}
+
+/// the scheme of the original request, as a reverse proxy middleware might store it
+#[derive(Clone, Copy)]
+struct ForwardedProto(&'static str);
+
+#[test]
+fn scheme() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        |conn: Conn| async move {
+            match conn.request_headers().get_str("x-forwarded-proto") {
+                Some("https") => conn.with_state(ForwardedProto("https")),
+                _ => conn,
+            }
+        },
+        instrument(telemetry.meter(), telemetry.tracer())
+            .with_scheme(|conn| conn.state().map(|ForwardedProto(scheme)| *scheme)),
+        "ok",
+    ));
+
+    server.request("GET", "/", &[("x-forwarded-proto", "https")]);
+    server.request("POST", "/", &[]);
+    let spans = telemetry.spans();
+    assert_eq!(spans.len(), 2);
+    for span in spans {
+        let expected = if span.name == "GET" { "https" } else { "http" };
+        assert_eq!(
+            span_attribute(&span, "url.scheme").as_deref(),
+            Some(expected)
+        );
+    }
+    let points = telemetry.points();
+    assert!(!points.is_empty());
+    for point in points {
+        let expected = match point.attribute("http.request.method").as_deref() {
+            Some("GET") => "https",
+            _ => "http",
+        };
+        assert_eq!(
+            point.attribute("url.scheme").as_deref(),
+            Some(expected),
+            "{point:?}"
+        );
+    }
+}
+
+#[test]
+fn fallback_route() {
+    use trillium_opentelemetry::FallbackRoute;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        instrument(telemetry.meter(), telemetry.tracer()).with_router_route(),
+        trillium_router::router().get("/api/users/:id", "user"),
+        (FallbackRoute, "index.html"),
+    ));
+
+    assert_eq!(server.get("/api/users/1").body, "user");
+    assert_eq!(server.get("/some/page").body, "index.html");
+    let spans = telemetry
+        .spans()
+        .iter()
+        .map(|span| (span.name.to_string(), span_attribute(span, "http.route")))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        spans,
+        [
+            (
+                "GET /api/users/:id".to_string(),
+                Some("/api/users/:id".to_string())
+            ),
+            ("GET /*fallback".to_string(), Some("/*fallback".to_string())),
+        ]
+    );
+    let mut routes = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| point.attribute("http.route"))
+        .collect::<Vec<_>>();
+    routes.sort();
+    assert_eq!(
+        routes,
+        [
+            Some("/*fallback".to_string()),
+            Some("/api/users/:id".to_string())
+        ]
+    );
+}
@@ -0,0 +1,292 @@
+mod common;
+
+use common::{attribute, span_attribute, Server, Telemetry};
+use opentelemetry::KeyValue;
+use std::borrow::Cow;
+use trillium::{Conn, Handler};
+use trillium_opentelemetry::{instrument_handler, instrument_handler_at, Trace};
+
+/// responds with `ok`, with a predictable handler name
+struct Named(&'static str);
+
+#[trillium::async_trait]
+impl Handler for Named {
+    async fn run(&self, conn: Conn) -> Conn {
+        conn.ok("ok")
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        self.0.into()
+    }
+}
+
+/// runs the wrapped handler, with a predictable handler name
+struct Wrapper<H>(&'static str, H);
+
+#[trillium::async_trait]
+impl<H: Handler> Handler for Wrapper<H> {
+    async fn init(&mut self, info: &mut trillium::Info) {
+        self.1.init(info).await
+    }
+
+    async fn run(&self, conn: Conn) -> Conn {
+        self.1.run(conn).await
+    }
+
+    async fn before_send(&self, conn: Conn) -> Conn {
+        self.1.before_send(conn).await
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        self.0.into()
+    }
+}
+
+#[test]
+fn child_spans() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()),
+        instrument_handler(Named("inner"), telemetry.tracer()),
+    ));
+
+    server.get("/");
+    let request = telemetry.span_named("GET");
+    let run = telemetry.span_named("inner::run");
+    let before_send = telemetry.span_named("inner::before_send");
+    assert_eq!(run.parent_span_id, request.span_context.span_id());
+    assert_eq!(before_send.parent_span_id, request.span_context.span_id());
+    assert!(request.events.is_empty());
+}
+
+#[test]
+fn flat_mode_records_events_instead_of_spans() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()),
+        instrument_handler(Named("inner"), telemetry.tracer()).flat_mode(),
+    ));
+
+    server.get("/");
+    assert_eq!(
+        telemetry
+            .spans()
+            .iter()
+            .map(|span| &*span.name)
+            .collect::<Vec<_>>(),
+        ["inner::init", "GET"]
+    );
+    let request = telemetry.span_named("GET");
+    assert_eq!(
+        request
+            .events
+            .iter()
+            .map(|event| &*event.name)
+            .collect::<Vec<_>>(),
+        ["inner::run", "inner::before_send"]
+    );
+}
+
+#[test]
+fn nested_depth() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()),
+        instrument_handler(
+            Wrapper(
+                "outer",
+                instrument_handler(Named("inner"), telemetry.tracer()),
+            ),
+            telemetry.tracer(),
+        ),
+    ));
+
+    server.get("/");
+    let outer = telemetry.span_named("outer::run");
+    let inner = telemetry.span_named("inner::run");
+    assert_eq!(inner.parent_span_id, outer.span_context.span_id());
+    assert_eq!(
+        span_attribute(&outer, "trillium.handler.depth").as_deref(),
+        Some("1")
+    );
+    assert_eq!(
+        span_attribute(&inner, "trillium.handler.depth").as_deref(),
+        Some("2")
+    );
+    assert_eq!(
+        span_attribute(
+            &telemetry.span_named("inner::before_send"),
+            "trillium.handler.depth"
+        )
+        .as_deref(),
+        Some("2")
+    );
+}
+
+#[test]
+fn flat_mode_nested_depth() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()),
+        instrument_handler(
+            Wrapper(
+                "outer",
+                instrument_handler(Named("inner"), telemetry.tracer()).flat_mode(),
+            ),
+            telemetry.tracer(),
+        )
+        .flat_mode(),
+    ));
+
+    server.get("/");
+    let depths = telemetry
+        .span_named("GET")
+        .events
+        .iter()
+        .map(|event| {
+            (
+                event.name.to_string(),
+                attribute(&event.attributes, "trillium.handler.depth").unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        depths,
+        [
+            ("outer::run".to_string(), "1".to_string()),
+            ("inner::run".to_string(), "2".to_string()),
+            ("outer::before_send".to_string(), "1".to_string()),
+            ("inner::before_send".to_string(), "2".to_string()),
+        ]
+    );
+}
+
+/// starts and ends an operation span, then responds with `ok`
+struct Operation;
+
+#[trillium::async_trait]
+impl Handler for Operation {
+    async fn run(&self, conn: Conn) -> Conn {
+        use opentelemetry::trace::TraceContextExt;
+        use trillium_opentelemetry::TraceConnExt;
+        conn.new_operation_span("operation").unwrap().span().end();
+        conn.ok("ok")
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        "inner".into()
+    }
+}
+
+fn subtree(telemetry: &Telemetry, flat: bool) -> impl Handler {
+    let mut inner = instrument_handler(Operation, telemetry.tracer())
+        .with_subtree_attributes(vec![KeyValue::new("app.module", "invoices")]);
+    if flat {
+        inner = inner.flat_mode();
+    }
+    let mut outer = instrument_handler(Wrapper("outer", inner), telemetry.tracer())
+        .with_subtree_attributes(vec![KeyValue::new("service.name", "billing")]);
+    if flat {
+        outer = outer.flat_mode();
+    }
+    (Trace::new(telemetry.tracer()), outer)
+}
+
+#[test]
+fn subtree_attributes() {
+    let telemetry = Telemetry::new();
+    let server = Server::new(subtree(&telemetry, false));
+
+    server.get("/");
+    let attributes = |name: &str| {
+        let span = telemetry.span_named(name);
+        (
+            span_attribute(&span, "service.name"),
+            span_attribute(&span, "app.module"),
+        )
+    };
+    let billing = Some("billing".to_string());
+    let invoices = Some("invoices".to_string());
+    assert_eq!(attributes("GET"), (None, None));
+    assert_eq!(attributes("outer::init"), (billing.clone(), None));
+    assert_eq!(attributes("inner::init"), (None, invoices.clone()));
+    assert_eq!(attributes("outer::run"), (billing.clone(), None));
+    assert_eq!(
+        attributes("inner::run"),
+        (billing.clone(), invoices.clone())
+    );
+    assert_eq!(attributes("operation"), (billing.clone(), invoices.clone()));
+    assert_eq!(attributes("inner::before_send"), (billing, invoices));
+}
+
+#[test]
+fn flat_mode_subtree_attributes() {
+    let telemetry = Telemetry::new();
+    let server = Server::new(subtree(&telemetry, true));
+
+    server.get("/");
+    let request = telemetry.span_named("GET");
+    let events = request
+        .events
+        .iter()
+        .map(|event| {
+            (
+                &*event.name,
+                attribute(&event.attributes, "service.name"),
+                attribute(&event.attributes, "app.module"),
+            )
+        })
+        .collect::<Vec<_>>();
+    let billing = Some("billing".to_string());
+    let invoices = Some("invoices".to_string());
+    assert_eq!(
+        events,
+        [
+            ("outer::run", billing.clone(), None),
+            ("inner::run", billing.clone(), invoices.clone()),
+            ("outer::before_send", billing.clone(), None),
+            ("inner::before_send", billing.clone(), invoices.clone()),
+        ]
+    );
+    assert_eq!(span_attribute(&request, "service.name"), None);
+
+    let operation = telemetry.span_named("operation");
+    assert_eq!(span_attribute(&operation, "service.name"), billing);
+    assert_eq!(span_attribute(&operation, "app.module"), invoices);
+}
+
+#[test]
+fn source_location() {
+    use std::panic::Location;
+    let telemetry = Telemetry::new();
+    let location = Location::caller();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()),
+        instrument_handler_at(
+            Wrapper(
+                "outer",
+                instrument_handler(Named("inner"), telemetry.tracer()),
+            ),
+            telemetry.tracer(),
+            location,
+        ),
+    ));
+
+    server.get("/");
+    for name in ["outer::init", "outer::run", "outer::before_send"] {
+        let span = telemetry.span_named(name);
+        assert_eq!(
+            span_attribute(&span, "code.filepath").as_deref(),
+            Some(file!())
+        );
+        assert_eq!(
+            span_attribute(&span, "code.lineno"),
+            Some(location.line().to_string())
+        );
+    }
+
+    // the location is not inherited
+    let inner = telemetry.span_named("inner::run");
+    assert_eq!(span_attribute(&inner, "code.filepath"), None);
+    assert_eq!(span_attribute(&inner, "code.lineno"), None);
+}
@@ -0,0 +1,1208 @@
+mod common;
+
+use common::{DelayedChunks, Server, Telemetry};
+use trillium_opentelemetry::Metrics;
+
+#[test]
+fn debug_shows_configuration() {
+    let telemetry = Telemetry::new();
+    let metrics = Metrics::new(telemetry.meter())
+        .with_scheme(|_| Some("https"))
+        .with_status_error_type(trillium::Status::TooManyRequests, "rate_limited")
+        .with_listener();
+    let debug = format!("{metrics:?}");
+    assert!(debug.contains(r#"scheme: "Some(..)""#), "{debug}");
+    assert!(debug.contains(r#"user_agent_parser: "None""#), "{debug}");
+    assert!(debug.contains("enable_listener: true"), "{debug}");
+    assert!(debug.contains(r#"(429, "rate_limited")"#), "{debug}");
+    assert!(debug.contains(r#"meter: "..""#), "{debug}");
+}
+
+#[test]
+fn host_dimension() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_host_dimension(|conn| {
+            let host = conn.inner().host()?;
+            let host = host.split_once(':').map_or(host, |(host, _port)| host);
+            Some(match host {
+                "example.com" => "example.com".into(),
+                "example.org" => "example.org".into(),
+                _ => "other".into(),
+            })
+        }),
+        "ok",
+    ));
+
+    server.get("/");
+    server.request("GET", "/", &[("Host", "example.org:8080")]);
+    server.request("GET", "/", &[("Host", "unknown.example")]);
+    server.request("GET", "/", &[("Host", "another.example")]);
+
+    let mut hosts = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| (point.attribute("server.address").unwrap(), point.count))
+        .collect::<Vec<_>>();
+    hosts.sort();
+    assert_eq!(
+        hosts,
+        [
+            ("example.com".to_string(), 1),
+            ("example.org".to_string(), 1),
+            ("other".to_string(), 2)
+        ]
+    );
+}
+
+#[test]
+fn handler_start_duration() {
+    use std::time::Duration;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        |conn: trillium::Conn| async move {
+            std::thread::sleep(Duration::from_millis(20));
+            conn
+        },
+        Metrics::new(telemetry.meter()).with_handler_start_duration(),
+        "ok",
+    ));
+
+    server.get("/");
+    let point = telemetry.point("trillium.handler.start.duration");
+    assert_eq!(point.unit, "s");
+    assert_eq!(point.count, 1);
+    assert!(point.value >= 0.02, "{point:?}");
+    assert_eq!(
+        point.attribute("http.request.method").as_deref(),
+        Some("GET")
+    );
+    assert_eq!(point.attribute("http.response.status_code"), None);
+}
+
+#[test]
+fn handler_start_duration_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Metrics::new(telemetry.meter()), "ok"));
+    server.get("/");
+    assert!(!telemetry.has_metric("trillium.handler.start.duration"));
+}
+
+#[test]
+fn resource_attributes_as_dimensions() {
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::Resource;
+    let resource = Resource::new([
+        KeyValue::new("service.name", "checkout"),
+        KeyValue::new("host.name", "web-1"),
+    ]);
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_resource_attributes_as_dimensions(&resource, ["service.name", "missing"]),
+        "ok",
+    ));
+
+    server.get("/");
+    let point = telemetry.point("http.server.request.duration");
+    assert_eq!(point.attribute("service.name").as_deref(), Some("checkout"));
+    assert_eq!(point.attribute("host.name"), None);
+    assert_eq!(point.attribute("missing"), None);
+}
+
+#[test]
+fn conn_meter_records_custom_instruments() {
+    use trillium_opentelemetry::MetricsConnExt;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()),
+        |conn: trillium::Conn| async move {
+            let meter = conn.metrics_meter().unwrap();
+            meter.u64_counter("app.widgets.created").build().add(2, &[]);
+            conn.ok("created")
+        },
+    ));
+
+    server.get("/");
+    server.get("/");
+    assert_eq!(telemetry.point("app.widgets.created").value, 4.0);
+}
+
+#[test]
+fn conn_meter_requires_metrics() {
+    use trillium_opentelemetry::MetricsConnExt;
+    let server = Server::new(|conn: trillium::Conn| async move {
+        let has_meter = conn.metrics_meter().is_some();
+        conn.ok(has_meter.to_string())
+    });
+    assert_eq!(server.get("/").body, "false");
+}
+
+#[test]
+fn without_protocol_version() {
+    use common::span_attribute;
+    use trillium_opentelemetry::Trace;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()),
+        Metrics::new(telemetry.meter()).without_protocol_version(),
+        "ok",
+    ));
+
+    server.get("/");
+    assert_eq!(
+        span_attribute(&telemetry.span(), "network.protocol.version").as_deref(),
+        Some("1.1")
+    );
+    for point in telemetry.points() {
+        assert_eq!(
+            point.attribute("network.protocol.version"),
+            None,
+            "{point:?}"
+        );
+    }
+}
+
+#[test]
+fn protocol_version() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Metrics::new(telemetry.meter()), "ok"));
+    server.get("/");
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("network.protocol.version")
+            .as_deref(),
+        Some("1.1")
+    );
+}
+
+#[test]
+fn histogram_selector() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_duration_histogram_variant("cached", vec![0.0001, 0.001, 0.01])
+            .with_duration_histogram_variant("database", vec![0.01, 0.1, 1.0])
+            .with_histogram_selector(|conn| match conn.path() {
+                "/cached" => Some("cached"),
+                "/database" => Some("database"),
+                "/unregistered" => Some("unregistered"),
+                _ => None,
+            }),
+        "ok",
+    ));
+
+    server.get("/cached");
+    server.get("/cached");
+    server.get("/database");
+    server.get("/");
+    server.get("/unregistered");
+
+    let cached = telemetry.point("http.server.request.duration.cached");
+    assert_eq!(cached.count, 2);
+    assert_eq!(cached.unit, "s");
+    assert_eq!(cached.bounds, [0.0001, 0.001, 0.01]);
+
+    let database = telemetry.point("http.server.request.duration.database");
+    assert_eq!(database.count, 1);
+    assert_eq!(database.bounds, [0.01, 0.1, 1.0]);
+
+    assert_eq!(telemetry.point("http.server.request.duration").count, 2);
+    assert!(!telemetry.has_metric("http.server.request.duration.unregistered"));
+}
+
+#[test]
+fn records_without_init() {
+    use trillium_testing::prelude::*;
+    let telemetry = Telemetry::new();
+    let metrics = Metrics::new(telemetry.meter()).with_duration_histogram_boundaries(vec![1.0]);
+    let conn = get("/").run(&(metrics, "ok"));
+    drop(conn);
+    assert_eq!(
+        telemetry.point("http.server.request.duration").bounds,
+        [1.0]
+    );
+}
+
+#[test]
+fn body_sizes() {
+    use trillium::Body;
+    use trillium_testing::futures_lite::io::Cursor;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()),
+        |mut conn: trillium::Conn| async move {
+            let request_body = conn.request_body_string().await.unwrap();
+            if conn.path() == "/chunked" {
+                conn.ok(Body::new_streaming(Cursor::new(request_body), None))
+            } else {
+                conn.ok(request_body)
+            }
+        },
+    ));
+
+    server.request_with_body("POST", "/", &[], "hello");
+    let request_size = telemetry.point("http.server.request.body.size");
+    assert_eq!((request_size.count, request_size.value), (1, 5.0));
+    let response_size = telemetry.point("http.server.response.body.size");
+    assert_eq!((response_size.count, response_size.value), (1, 5.0));
+
+    // neither chunked body has a known length, so neither is recorded
+    server.raw(
+        "POST /chunked HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\
+         Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+    );
+    assert_eq!(telemetry.point("http.server.request.duration").count, 2);
+    assert_eq!(telemetry.point("http.server.request.body.size").count, 1);
+    assert_eq!(telemetry.point("http.server.response.body.size").count, 1);
+}
+
+#[test]
+fn static_route() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_router_route()
+            .with_static_route("/assets/")
+            .with_static_route("/images"),
+        trillium_router::router().get("/assets/manifest", "ok"),
+        "static file",
+    ));
+
+    for path in [
+        "/assets/app.js",
+        "/assets/css/app.css",
+        "/assets",
+        "/images/logo.png",
+        "/assets/manifest",
+        "/assetsother",
+    ] {
+        server.get(path);
+    }
+
+    let mut routes = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| (point.attribute("http.route"), point.count))
+        .collect::<Vec<_>>();
+    routes.sort();
+    assert_eq!(
+        routes,
+        [
+            (None, 1),
+            (Some("/assets/*".to_string()), 3),
+            (Some("/assets/manifest".to_string()), 1),
+            (Some("/images/*".to_string()), 1),
+        ]
+    );
+}
+
+#[test]
+fn instruments_share_attributes() {
+    use opentelemetry::KeyValue;
+    use std::sync::{Arc, Mutex};
+    let observations = Arc::new(Mutex::new(Vec::<(String, Vec<KeyValue>)>::new()));
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_request_counter()
+            .with_ttfb_histogram()
+            .with_observer({
+                let observations = Arc::clone(&observations);
+                move |observation| {
+                    observations.lock().unwrap().push((
+                        observation.name.to_string(),
+                        observation.attributes.to_vec(),
+                    ))
+                }
+            }),
+        |mut conn: trillium::Conn| async move {
+            let body = conn.request_body_string().await.unwrap();
+            conn.ok(body)
+        },
+    ));
+
+    server.request_with_body("POST", "/", &[], "hello");
+
+    let sorted = |attributes: &[KeyValue]| {
+        let mut attributes = attributes.to_vec();
+        attributes.sort_by(|a, b| a.key.cmp(&b.key));
+        attributes
+    };
+
+    let points = telemetry.points();
+    let attributes = sorted(&points[0].attributes);
+    let mut names = vec![];
+    for point in &points {
+        if point.name != "http.server.active_requests" {
+            assert_eq!(sorted(&point.attributes), attributes, "{}", point.name);
+            names.push(&*point.name);
+        }
+    }
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "http.server.request.body.size",
+            "http.server.request.count",
+            "http.server.request.duration",
+            "http.server.response.body.size",
+            "http.server.response.start.duration",
+        ]
+    );
+
+    let observations = observations.lock().unwrap();
+    let mut observed_names = vec![];
+    for (name, observed) in &*observations {
+        if name != "http.server.active_requests" {
+            assert_eq!(sorted(observed), attributes, "{name}");
+            observed_names.push(&**name);
+        }
+    }
+    observed_names.sort();
+    assert_eq!(observed_names, names);
+}
+
+#[test]
+fn module() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_module(|conn| {
+            let module = conn.path().trim_start_matches('/').split('/').next()?;
+            match module {
+                "billing" | "auth" => Some(module.to_string().into()),
+                _ => None,
+            }
+        }),
+        "ok",
+    ));
+
+    for path in ["/billing/invoices", "/billing", "/auth/login", "/other"] {
+        server.get(path);
+    }
+
+    let mut modules = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| (point.attribute("code.namespace"), point.count))
+        .collect::<Vec<_>>();
+    modules.sort();
+    assert_eq!(
+        modules,
+        [
+            (None, 1),
+            (Some("auth".to_string()), 1),
+            (Some("billing".to_string()), 2)
+        ]
+    );
+}
+
+#[test]
+fn cardinality_limit() {
+    let telemetry = Telemetry::new();
+    let metrics = Metrics::new(telemetry.meter())
+        .with_route(|conn| Some(conn.path().to_string().into()))
+        .with_cardinality_limit("http.route", 2);
+    let first = Server::new((metrics.clone(), "ok"));
+    let second = Server::new((metrics, "ok"));
+
+    first.get("/a");
+    first.get("/b");
+    first.get("/c");
+    second.get("/d");
+    second.get("/a");
+
+    let mut routes = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| (point.attribute("http.route").unwrap(), point.count))
+        .collect::<Vec<_>>();
+    routes.sort();
+    assert_eq!(
+        routes,
+        [
+            ("/a".to_string(), 2),
+            ("/b".to_string(), 1),
+            ("_OVERFLOW".to_string(), 2)
+        ]
+    );
+}
+
+#[test]
+fn router_route() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_router_route(),
+        trillium_router::router().get("/users/:id", "ok"),
+    ));
+
+    server.get("/users/1");
+    server.get("/users/2");
+    server.get("/unrouted");
+    let mut routes = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| (point.attribute("http.route"), point.count))
+        .collect::<Vec<_>>();
+    routes.sort();
+    assert_eq!(routes, [(None, 1), (Some("/users/:id".to_string()), 2)]);
+}
+
+#[test]
+fn method_class_dimension() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_method_class_dimension(),
+        "ok",
+    ));
+
+    for method in ["GET", "HEAD", "OPTIONS", "POST", "PUT", "DELETE"] {
+        server.request(method, "/", &[]);
+    }
+
+    let mut classes = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| {
+            (
+                point.attribute("http.request.method").unwrap(),
+                point.attribute("http.request.method_class").unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    classes.sort();
+    assert_eq!(
+        classes,
+        [
+            ("DELETE".to_string(), "write".to_string()),
+            ("GET".to_string(), "read".to_string()),
+            ("HEAD".to_string(), "read".to_string()),
+            ("OPTIONS".to_string(), "read".to_string()),
+            ("POST".to_string(), "write".to_string()),
+            ("PUT".to_string(), "write".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn method_class_dimension_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Metrics::new(telemetry.meter()), "ok"));
+    server.get("/");
+    assert_eq!(
+        telemetry
+            .point("http.server.request.duration")
+            .attribute("http.request.method_class"),
+        None
+    );
+}
+
+#[test]
+fn locale_dimension() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_locale_dimension(),
+        "ok",
+    ));
+
+    for accept_language in ["fr-CH, fr;q=0.9, en;q=0.8", "en;q=0.5, de", "*", "klingon"] {
+        server.request("GET", "/", &[("Accept-Language", accept_language)]);
+    }
+    server.get("/");
+
+    let mut locales = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| (point.attribute("trillium.locale"), point.count))
+        .collect::<Vec<_>>();
+    locales.sort();
+    assert_eq!(
+        locales,
+        [
+            (None, 1),
+            (Some("_OTHER".to_string()), 2),
+            (Some("de".to_string()), 1),
+            (Some("fr".to_string()), 1),
+        ]
+    );
+}
+
+#[test]
+fn api_version() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_api_version(|conn| {
+            match conn.path().split('/').nth(1)? {
+                version @ ("v1" | "v2") => Some(version.to_string().into()),
+                _ => None,
+            }
+        }),
+        "ok",
+    ));
+
+    for path in ["/v1/users", "/v2/users", "/v2/orders", "/v3/users", "/"] {
+        server.get(path);
+    }
+
+    let mut versions = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| (point.attribute("http.api.version"), point.count))
+        .collect::<Vec<_>>();
+    versions.sort();
+    assert_eq!(
+        versions,
+        [
+            (None, 2),
+            (Some("v1".to_string()), 1),
+            (Some("v2".to_string()), 2),
+        ]
+    );
+}
+
+#[test]
+fn counted_response_body() {
+    use trillium::Body;
+    use trillium_testing::futures_lite::{io::Cursor, AsyncReadExt};
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_counted_response_body(),
+        |conn: trillium::Conn| async move {
+            let chunks = Cursor::new("first chunk,")
+                .chain(Cursor::new("second chunk,"))
+                .chain(Cursor::new("third chunk"));
+            conn.ok(Body::new_streaming(chunks, None))
+        },
+    ));
+
+    let response = server.get("/");
+    assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+    assert!(response.body.contains("second chunk,"), "{}", response.body);
+    let response_size = telemetry.point("http.server.response.body.size");
+    assert_eq!(response_size.count, 1);
+    assert_eq!(
+        response_size.value,
+        "first chunk,second chunk,third chunk".len() as f64
+    );
+}
+
+#[test]
+fn head_response_body_size() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Metrics::new(telemetry.meter()), "would-be body"));
+
+    let response = server.request("HEAD", "/", &[]);
+    assert_eq!(response.header("content-length"), Some("13"));
+    assert_eq!(response.body, "");
+
+    let response_size = telemetry.point("http.server.response.body.size");
+    assert_eq!((response_size.count, response_size.value), (1, 0.0));
+    let duration = telemetry.point("http.server.request.duration");
+    assert_eq!(duration.count, 1);
+    assert_eq!(
+        duration.attribute("http.request.method").as_deref(),
+        Some("HEAD")
+    );
+    assert_eq!(
+        duration.attribute("http.response.status_code").as_deref(),
+        Some("200")
+    );
+}
+
+#[test]
+fn observer() {
+    use std::sync::{Arc, Mutex};
+    let observations = Arc::new(Mutex::new(Vec::new()));
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_observer({
+            let observations = Arc::clone(&observations);
+            move |observation| {
+                observations.lock().unwrap().push((
+                    observation.name.to_string(),
+                    observation.unit,
+                    observation.value,
+                    common::attribute(observation.attributes, "http.request.method"),
+                ))
+            }
+        }),
+        "hello",
+    ));
+
+    server.request_with_body("PUT", "/", &[], "abc");
+    let observations = observations.lock().unwrap().clone();
+    let duration = telemetry.point("http.server.request.duration").value;
+    let put = Some("PUT".to_string());
+    assert_eq!(
+        observations,
+        [
+            (
+                "http.server.active_requests".to_string(),
+                "{request}",
+                1.0,
+                put.clone()
+            ),
+            (
+                "http.server.request.duration".to_string(),
+                "s",
+                duration,
+                put.clone()
+            ),
+            (
+                "http.server.response.body.size".to_string(),
+                "By",
+                5.0,
+                put.clone()
+            ),
+            (
+                "http.server.request.body.size".to_string(),
+                "By",
+                3.0,
+                put.clone()
+            ),
+            (
+                "http.server.active_requests".to_string(),
+                "{request}",
+                -1.0,
+                put
+            ),
+        ]
+    );
+}
+
+#[test]
+fn with_methods() {
+    use trillium::Method;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_methods([Method::Get])
+            .with_methods([Method::Post, Method::Delete]),
+        "ok",
+    ));
+
+    for method in ["GET", "POST", "PUT", "DELETE", "POST"] {
+        server.request(method, "/", &[]);
+    }
+
+    let mut methods = telemetry
+        .points()
+        .into_iter()
+        .map(|point| point.attribute("http.request.method").unwrap())
+        .collect::<Vec<_>>();
+    methods.sort();
+    methods.dedup();
+    assert_eq!(methods, ["DELETE", "POST"]);
+    assert_eq!(
+        telemetry
+            .points_named("http.server.request.duration")
+            .iter()
+            .map(|point| point.count)
+            .sum::<u64>(),
+        3
+    );
+}
+
+#[test]
+fn minimal_attributes() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_route(|conn| Some(conn.path().to_string().into()))
+            .with_minimal_attributes_for(|conn| conn.path().starts_with("/events")),
+        "ok",
+    ));
+
+    server.request_with_body("POST", "/events", &[], "event");
+    server.get("/other");
+
+    let keys = |method: &str| {
+        let mut keys = telemetry
+            .points_named("http.server.request.duration")
+            .into_iter()
+            .find(|point| point.attribute("http.request.method").as_deref() == Some(method))
+            .unwrap()
+            .attributes
+            .iter()
+            .map(|kv| kv.key.to_string())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+    assert_eq!(
+        keys("POST"),
+        ["http.request.method", "http.response.status_code"]
+    );
+    assert!(keys("GET").contains(&"http.route".to_string()));
+    assert!(keys("GET").contains(&"url.scheme".to_string()));
+
+    for point in telemetry.points() {
+        if point.attribute("http.request.method").as_deref() == Some("POST") {
+            assert!(
+                point.attributes.iter().all(|kv| matches!(
+                    kv.key.as_str(),
+                    "http.request.method" | "http.response.status_code"
+                )),
+                "{point:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn not_found_counter() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_router_route()
+            .with_not_found_counter(),
+        trillium_router::router()
+            .get("/users/:id", "ok")
+            .get("/deleted/:id", |conn: trillium::Conn| async move {
+                conn.with_status(404).halt()
+            }),
+    ));
+
+    server.get("/users/1");
+    server.get("/deleted/1");
+    server.get("/unrouted");
+    server.get("/unrouted");
+    server.request("POST", "/unrouted", &[]);
+    let mut not_found = telemetry
+        .points_named("http.server.not_found")
+        .into_iter()
+        .map(|point| {
+            assert_eq!(point.attributes.len(), 1);
+            (point.attribute("http.request.method").unwrap(), point.value)
+        })
+        .collect::<Vec<_>>();
+    not_found.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        not_found,
+        [("GET".to_string(), 2.0), ("POST".to_string(), 1.0)]
+    );
+}
+
+#[test]
+fn not_found_counter_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Metrics::new(telemetry.meter()), trillium_router::router()));
+
+    server.get("/unrouted");
+    assert!(telemetry.has_metric("http.server.request.duration"));
+    assert!(!telemetry.has_metric("http.server.not_found"));
+}
+
+#[test]
+fn ttfb_histogram() {
+    use std::time::Duration;
+    let delay = Duration::from_millis(50);
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_ttfb_histogram(),
+        move |conn: trillium::Conn| async move {
+            if conn.path() == "/empty" {
+                return conn.with_status(204);
+            }
+            conn.ok(trillium::Body::new_streaming(
+                DelayedChunks::new(["first,", "second,", "third"], delay),
+                None,
+            ))
+        },
+    ));
+
+    let response = server.get("/");
+    assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+    assert!(response.body.contains("second,"), "{}", response.body);
+    let ttfb = telemetry.point("http.server.response.start.duration");
+    let duration = telemetry.point("http.server.request.duration");
+    assert_eq!(ttfb.unit, "s");
+    assert_eq!(ttfb.count, 1);
+    assert!(ttfb.value > 0.0);
+    assert!(
+        ttfb.value + (delay * 2).as_secs_f64() <= duration.value,
+        "{} {}",
+        ttfb.value,
+        duration.value
+    );
+
+    server.get("/empty");
+    assert_eq!(
+        telemetry.point("http.server.response.start.duration").count,
+        1
+    );
+    assert_eq!(
+        telemetry.points_named("http.server.request.duration").len(),
+        2
+    );
+}
+
+#[test]
+fn ttfb_histogram_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Metrics::new(telemetry.meter()), "ok"));
+
+    server.get("/");
+    assert!(telemetry.has_metric("http.server.request.duration"));
+    assert!(!telemetry.has_metric("http.server.response.start.duration"));
+}
+
+#[test]
+fn body_sizes_closure() {
+    struct BodySizes(Option<u64>, Option<u64>);
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_body_sizes(|conn| {
+            conn.state()
+                .map_or((None, None), |BodySizes(req, res)| (*req, *res))
+        }),
+        |conn: trillium::Conn| async move {
+            match conn.path() {
+                "/accounted" => conn.with_state(BodySizes(Some(1000), Some(2000))).ok("ok"),
+                _ => conn.with_state(BodySizes(None, Some(3000))).ok("ok"),
+            }
+        },
+    ));
+
+    server.request_with_body("POST", "/accounted", &[], "request body");
+    let request_size = telemetry.point("http.server.request.body.size");
+    assert_eq!((request_size.count, request_size.value), (1, 1000.0));
+    let response_size = telemetry.point("http.server.response.body.size");
+    assert_eq!((response_size.count, response_size.value), (1, 2000.0));
+
+    server.request_with_body("POST", "/unaccounted", &[], "request body");
+    let request_size = telemetry.point("http.server.request.body.size");
+    assert_eq!((request_size.count, request_size.value), (1, 1000.0));
+    let response_size = telemetry.point("http.server.response.body.size");
+    assert_eq!((response_size.count, response_size.value), (2, 5000.0));
+}
+
+/// the request count exported after two requests, and the count exported after one more
+fn request_counts(temporality: opentelemetry_sdk::metrics::Temporality) -> [f64; 2] {
+    let telemetry = Telemetry::with_temporality(temporality);
+    let server = Server::new((Metrics::new(telemetry.meter()).with_request_counter(), "ok"));
+
+    server.get("/");
+    server.get("/");
+    let first = telemetry.point("http.server.request.count");
+    assert_eq!(first.unit, "{request}");
+    assert_eq!(
+        first.attribute("http.response.status_code").as_deref(),
+        Some("200")
+    );
+    server.get("/");
+    [
+        first.value,
+        telemetry.point("http.server.request.count").value,
+    ]
+}
+
+#[test]
+fn request_counter() {
+    use opentelemetry_sdk::metrics::Temporality;
+    assert_eq!(request_counts(Temporality::Cumulative), [2.0, 3.0]);
+    assert_eq!(request_counts(Temporality::Delta), [2.0, 1.0]);
+}
+
+#[test]
+fn register_eagerly() {
+    use trillium::Method;
+    let not_found = |telemetry: &Telemetry| {
+        let mut counts = telemetry
+            .points_named("http.server.not_found")
+            .into_iter()
+            .map(|point| (point.attribute("http.request.method").unwrap(), point.value))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| a.0.cmp(&b.0));
+        counts
+    };
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter())
+            .with_methods([Method::Get, Method::Post])
+            .with_not_found_counter()
+            .register_eagerly(),
+        trillium_router::router(),
+    ));
+
+    assert_eq!(
+        not_found(&telemetry),
+        [("GET".to_string(), 0.0), ("POST".to_string(), 0.0)]
+    );
+    for name in [
+        "http.server.request.duration",
+        "http.server.request.body.size",
+        "http.server.response.body.size",
+    ] {
+        let point = telemetry.point(name);
+        assert!(point.attributes.is_empty(), "{point:?}");
+        assert_eq!((point.count, point.value), (1, 0.0), "{point:?}");
+    }
+    let active_requests = telemetry.point("http.server.active_requests");
+    assert!(active_requests.attributes.is_empty());
+    assert_eq!(active_requests.value, 0.0);
+
+    server.get("/unrouted");
+    assert_eq!(
+        telemetry.points_named("http.server.request.duration").len(),
+        2
+    );
+    assert_eq!(
+        not_found(&telemetry),
+        [("GET".to_string(), 1.0), ("POST".to_string(), 0.0)]
+    );
+
+    let telemetry = Telemetry::new();
+    let _server = Server::new((
+        Metrics::new(telemetry.meter()).with_not_found_counter(),
+        trillium_router::router(),
+    ));
+    assert!(not_found(&telemetry).is_empty());
+    assert!(!telemetry.has_metric("http.server.request.duration"));
+}
+
+#[test]
+fn request_content_type() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()).with_request_content_type(),
+        "ok",
+    ));
+
+    for content_type in [
+        "Application/JSON; charset=utf-8",
+        "application/json",
+        "text/CSV",
+        "nonsense",
+        "x-custom/thing",
+    ] {
+        server.request_with_body("POST", "/", &[("content-type", content_type)], "body");
+    }
+    server.request_with_body("POST", "/", &[], "body");
+
+    let mut content_types = telemetry
+        .points_named("http.server.request.duration")
+        .into_iter()
+        .map(|point| {
+            (
+                point.attribute("trillium.request.content_type"),
+                point.count,
+            )
+        })
+        .collect::<Vec<_>>();
+    content_types.sort();
+    assert_eq!(
+        content_types,
+        [
+            (None, 1),
+            (Some("_OTHER".to_string()), 2),
+            (Some("application/json".to_string()), 2),
+            (Some("text/csv".to_string()), 1),
+        ]
+    );
+}
+
+#[test]
+fn active_requests() {
+    use std::sync::Arc;
+    let telemetry = Arc::new(Telemetry::new());
+    let in_flight = Arc::clone(&telemetry);
+    let server = Server::new((
+        |conn: trillium::Conn| async move {
+            if conn.path() == "/early" {
+                conn.ok("halted before metrics ran").halt()
+            } else {
+                conn
+            }
+        },
+        Metrics::new(telemetry.meter()),
+        move |conn: trillium::Conn| {
+            let point = in_flight.point("http.server.active_requests");
+            async move { conn.ok(format!("{}", point.value)) }
+        },
+    ));
+
+    assert_eq!(server.get("/").body, "1");
+    assert_eq!(server.get("/").body, "1");
+    server.get("/early");
+
+    let active_requests = telemetry.point("http.server.active_requests");
+    assert_eq!(active_requests.value, 0.0);
+    let mut keys = active_requests
+        .attributes
+        .iter()
+        .map(|kv| kv.key.as_str())
+        .collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, ["http.request.method", "url.scheme"]);
+    assert_eq!(telemetry.point("http.server.request.duration").count, 2);
+}
+
+#[test]
+fn duration_unit() {
+    use std::time::Duration;
+    use trillium_opentelemetry::DurationUnit;
+    let duration = |duration_unit| {
+        let telemetry = Telemetry::new();
+        let server = Server::new((
+            Metrics::new(telemetry.meter())
+                .with_duration_unit(duration_unit)
+                .with_duration_histogram_boundaries(vec![0.005, 0.01, 0.1]),
+            |conn: trillium::Conn| async move {
+                std::thread::sleep(Duration::from_millis(20));
+                conn.ok("ok")
+            },
+        ));
+        server.get("/");
+        telemetry.point("http.server.request.duration")
+    };
+
+    let seconds = duration(DurationUnit::Seconds);
+    assert_eq!(seconds.unit, "s");
+    assert_eq!(seconds.bounds, [0.005, 0.01, 0.1]);
+    assert!((0.02..1.0).contains(&seconds.value), "{}", seconds.value);
+
+    let milliseconds = duration(DurationUnit::Milliseconds);
+    assert_eq!(milliseconds.unit, "ms");
+    assert_eq!(milliseconds.bounds, [5.0, 10.0, 100.0]);
+    assert!(
+        (20.0..1000.0).contains(&milliseconds.value),
+        "{}",
+        milliseconds.value
+    );
+}
+
+#[test]
+fn without_body_size_histograms() {
+    let metric_names = |metrics: fn(Metrics) -> Metrics| {
+        let telemetry = Telemetry::new();
+        let server = Server::new((metrics(Metrics::new(telemetry.meter())), "hello"));
+        server.request_with_body("POST", "/", &[], "abc");
+        let mut names = telemetry
+            .points()
+            .into_iter()
+            .map(|point| point.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        metric_names(Metrics::without_request_size_histogram),
+        [
+            "http.server.active_requests",
+            "http.server.request.duration",
+            "http.server.response.body.size"
+        ]
+    );
+    assert_eq!(
+        metric_names(|metrics| metrics
+            .with_counted_response_body()
+            .without_response_size_histogram()),
+        [
+            "http.server.active_requests",
+            "http.server.request.body.size",
+            "http.server.request.duration"
+        ]
+    );
+    assert_eq!(
+        metric_names(|metrics| metrics
+            .without_request_size_histogram()
+            .without_response_size_histogram()),
+        [
+            "http.server.active_requests",
+            "http.server.request.duration"
+        ]
+    );
+}
+
+#[test]
+fn extra_attributes() {
+    use opentelemetry::KeyValue;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    struct Tenant(&'static str);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        |conn: trillium::Conn| async move {
+            let tenant = match conn.inner().host() {
+                Some("acme.example.com") => "acme",
+                _ => "other",
+            };
+            conn.with_state(Tenant(tenant))
+        },
+        Metrics::new(telemetry.meter()).with_extra_attributes({
+            let calls = Arc::clone(&calls);
+            move |conn| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                conn.state::<Tenant>()
+                    .map(|tenant| vec![KeyValue::new("app.tenant", tenant.0)])
+                    .unwrap_or_default()
+            }
+        }),
+        "ok",
+    ));
+
+    server.request_with_body("POST", "/", &[("host", "acme.example.com")], "abc");
+    server.request_with_body("POST", "/", &[("host", "example.org")], "abc");
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    for name in [
+        "http.server.request.duration",
+        "http.server.request.body.size",
+        "http.server.response.body.size",
+    ] {
+        let mut tenants = telemetry
+            .points_named(name)
+            .into_iter()
+            .map(|point| point.attribute("app.tenant"))
+            .collect::<Vec<_>>();
+        tenants.sort();
+        assert_eq!(
+            tenants,
+            [Some("acme".to_string()), Some("other".to_string())],
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn record_response_size() {
+    use trillium::Body;
+    use trillium_opentelemetry::MetricsConnExt;
+    use trillium_testing::futures_lite::io::Cursor;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Metrics::new(telemetry.meter()),
+        |mut conn: trillium::Conn| async move {
+            match conn.path() {
+                "/streamed" => {
+                    conn.record_response_size(1024);
+                    conn.ok(Body::new_streaming(Cursor::new("streamed"), None))
+                }
+                "/overridden" => {
+                    conn.record_response_size(2048);
+                    conn.ok("known length")
+                }
+                _ => conn.ok(Body::new_streaming(Cursor::new("streamed"), None)),
+            }
+        },
+    ));
+
+    server.get("/unrecorded");
+    assert!(telemetry
+        .points_named("http.server.response.body.size")
+        .is_empty());
+
+    server.get("/streamed");
+    let response_size = telemetry.point("http.server.response.body.size");
+    assert_eq!((response_size.count, response_size.value), (1, 1024.0));
+
+    server.get("/overridden");
+    let response_size = telemetry.point("http.server.response.body.size");
+    assert_eq!((response_size.count, response_size.value), (2, 3072.0));
+}
@@ -0,0 +1,22 @@
+use trillium::{Conn, KnownHeaderName};
+use trillium_opentelemetry::NoOp;
+use trillium_testing::prelude::*;
+
+#[test]
+fn passes_conns_through_untouched() {
+    let handler = (
+        |conn: Conn| async move { conn.with_response_header(KnownHeaderName::Server, "upstream") },
+        NoOp,
+        "ok",
+    );
+    assert_ok!(get("/").on(&handler), "ok", "server" => "upstream");
+
+    let conn = get("/").run(&(
+        |conn: Conn| async move { conn.with_state(7u8).with_status(201) },
+        NoOp,
+    ));
+    assert_eq!(conn.state::<u8>(), Some(&7));
+    assert_status!(&conn, 201);
+    assert!(!conn.is_halted());
+    assert!(conn.inner().response_body().is_none());
+}
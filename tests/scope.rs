@@ -0,0 +1,22 @@
+mod common;
+
+use common::{Server, Telemetry};
+use trillium_opentelemetry::Metrics;
+
+// this installs a global meter provider, so it is the only test in this binary
+#[test]
+fn string_constructed_metrics_carry_the_crate_scope() {
+    let telemetry = Telemetry::new();
+    opentelemetry::global::set_meter_provider(telemetry.meter_provider());
+    let server = Server::new((Metrics::from("my-app"), "ok"));
+
+    server.get("/");
+    let scopes = telemetry.scopes();
+    assert_eq!(scopes.len(), 1);
+    assert_eq!(scopes[0].name(), "my-app");
+    assert_eq!(scopes[0].version(), Some(env!("CARGO_PKG_VERSION")));
+    assert_eq!(
+        scopes[0].schema_url(),
+        Some("https://opentelemetry.io/schemas/1.29.0")
+    );
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::{Server, Telemetry};
+use trillium_opentelemetry::{testing::assert_http_server_span, Trace};
+
+#[test]
+fn trace_spans_are_compliant() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_router_route(),
+        trillium_router::router().post("/users/:id", "ok"),
+    ));
+
+    server.request_with_body("POST", "/users/1", &[], "{}");
+    server.get("/unrouted");
+    let spans = telemetry.spans();
+    assert_eq!(spans.len(), 2);
+    for span in &spans {
+        assert_http_server_span(span);
+    }
+}
+
+#[test]
+#[should_panic(expected = "to be a server span")]
+fn other_spans_are_not_compliant() {
+    use opentelemetry::trace::{Span as _, Tracer as _};
+    let telemetry = Telemetry::new();
+    telemetry.tracer().start("GET").end();
+    assert_http_server_span(&telemetry.span());
+}
+
+#[test]
+#[should_panic(expected = "expected a `http.request.method` attribute")]
+fn server_spans_require_http_attributes() {
+    use opentelemetry::trace::{Span as _, SpanKind, Tracer as _};
+    let telemetry = Telemetry::new();
+    let tracer = telemetry.tracer();
+    tracer
+        .span_builder("GET")
+        .with_kind(SpanKind::Server)
+        .start(&tracer)
+        .end();
+    assert_http_server_span(&telemetry.span());
+}
@@ -0,0 +1,1536 @@
+mod common;
+
+use common::{span_attribute, DelayedChunks, Server, Telemetry};
+use trillium::{Conn, KnownHeaderName, Status};
+use trillium_opentelemetry::Trace;
+
+fn redirect(conn: Conn) -> impl std::future::Future<Output = Conn> + Send {
+    let location = conn
+        .request_headers()
+        .get_str("X-Location")
+        .unwrap_or_default()
+        .to_string();
+    async move {
+        conn.with_status(Status::Found)
+            .with_response_header(KnownHeaderName::Location, location)
+            .halt()
+    }
+}
+
+fn cross_origin(
+    server: &Server<impl trillium::Handler>,
+    telemetry: &Telemetry,
+    location: &str,
+) -> Option<String> {
+    let span_count = telemetry.spans().len();
+    server.request("GET", "/", &[("X-Location", location)]);
+    let spans = telemetry.spans();
+    assert_eq!(spans.len(), span_count + 1);
+    span_attribute(spans.last().unwrap(), "http.response.redirect.cross_origin")
+}
+
+#[test]
+fn redirect_cross_origin() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_redirect_cross_origin(),
+        redirect,
+    ));
+
+    for same_origin in [
+        "/elsewhere",
+        "elsewhere",
+        "//example.com/elsewhere",
+        "http://example.com/elsewhere",
+        "http://EXAMPLE.com",
+        "http://example.com:80/elsewhere",
+        "http://user@example.com/elsewhere",
+    ] {
+        assert_eq!(
+            cross_origin(&server, &telemetry, same_origin).as_deref(),
+            Some("false"),
+            "{same_origin}"
+        );
+    }
+
+    for cross_origin_location in [
+        "https://example.com/elsewhere",
+        "http://example.org/elsewhere",
+        "//example.org/elsewhere",
+        "http://example.com:8080/elsewhere",
+        "http://example.com:443/elsewhere",
+    ] {
+        assert_eq!(
+            cross_origin(&server, &telemetry, cross_origin_location).as_deref(),
+            Some("true"),
+            "{cross_origin_location}"
+        );
+    }
+}
+
+#[test]
+fn redirect_cross_origin_default_https_port() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_scheme(|_| Some("https"))
+            .with_redirect_cross_origin(),
+        redirect,
+    ));
+
+    assert_eq!(
+        cross_origin(&server, &telemetry, "https://example.com:443/").as_deref(),
+        Some("false")
+    );
+    assert_eq!(
+        cross_origin(&server, &telemetry, "http://example.com/").as_deref(),
+        Some("true")
+    );
+}
+
+#[test]
+fn redirect_cross_origin_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), redirect));
+    assert_eq!(
+        cross_origin(&server, &telemetry, "https://example.org/"),
+        None
+    );
+}
+
+#[test]
+fn route_params() {
+    use trillium_router::{router, RouterConnExt};
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_route_params(|conn| {
+            conn.param("user_id")
+                .map(|user_id| ("user_id".into(), user_id.into()))
+                .into_iter()
+                .collect()
+        }),
+        router().get("/users/:user_id", "ok"),
+    ));
+
+    server.get("/users/123");
+    let span = telemetry.span();
+    assert_eq!(
+        span_attribute(&span, "url.path.param.user_id").as_deref(),
+        Some("123")
+    );
+
+    server.get("/other");
+    let spans = telemetry.spans();
+    assert!(span_attribute(&spans[1], "url.path.param.user_id").is_none());
+}
+
+#[test]
+fn clock() {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let end = start + Duration::from_secs(5);
+    let now = Arc::new(Mutex::new(start));
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_clock({
+            let now = Arc::clone(&now);
+            move || *now.lock().unwrap()
+        }),
+        move |conn: Conn| {
+            *now.lock().unwrap() = end;
+            async move { conn.ok("ok") }
+        },
+    ));
+
+    server.get("/");
+    let span = telemetry.span();
+    assert_eq!(span.end_time, end);
+    // the span starts when trillium started reading the request, slightly before the handler ran
+    assert!(span.start_time <= start);
+    assert!(span.start_time > start - Duration::from_secs(1));
+}
+
+#[test]
+fn debug_shows_configuration() {
+    let telemetry = Telemetry::new();
+    let trace = Trace::new(telemetry.tracer())
+        .with_route(|_| None)
+        .zipkin_compat();
+    let debug = format!("{trace:?}");
+    assert!(debug.contains(r#"route: "Some(..)""#), "{debug}");
+    assert!(debug.contains(r#"route_sampling: "None""#), "{debug}");
+    assert!(debug.contains("enable_zipkin_compat: true"), "{debug}");
+    assert!(debug.ends_with(".. }"), "{debug}");
+}
+
+struct Account {
+    plan: &'static str,
+}
+
+impl trillium_opentelemetry::StateAttributes for Account {
+    fn attributes(&self) -> Vec<opentelemetry::KeyValue> {
+        vec![opentelemetry::KeyValue::new("account.plan", self.plan)]
+    }
+}
+
+#[test]
+fn state_attributes() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_state_attributes::<Account>(),
+        |conn: Conn| async move {
+            if conn.path() == "/account" {
+                conn.with_state(Account { plan: "enterprise" }).ok("ok")
+            } else {
+                conn.ok("ok")
+            }
+        },
+    ));
+
+    server.get("/account");
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "account.plan").as_deref(),
+        Some("enterprise")
+    );
+    assert_eq!(span_attribute(&spans[1], "account.plan"), None);
+}
+
+#[test]
+fn url_query() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), "ok"));
+    server.get("/?token=secret");
+    assert_eq!(
+        span_attribute(&telemetry.span(), "url.query").as_deref(),
+        Some("token=secret")
+    );
+}
+
+#[test]
+fn without_url_query() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()).without_url_query(), "ok"));
+    server.get("/?token=secret");
+    server.get("/");
+    for span in telemetry.spans() {
+        assert_eq!(span_attribute(&span, "url.query"), None);
+        assert_eq!(span_attribute(&span, "url.path").as_deref(), Some("/"));
+    }
+}
+
+#[test]
+fn duration_attribute() {
+    use std::time::Duration;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_duration_attribute(),
+        |conn: Conn| async move {
+            std::thread::sleep(Duration::from_millis(20));
+            conn.ok("ok")
+        },
+    ));
+
+    server.get("/");
+    let span = telemetry.span();
+    let duration_ms: f64 = span_attribute(&span, "http.server.duration_ms")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let span_duration_ms = span
+        .end_time
+        .duration_since(span.start_time)
+        .unwrap()
+        .as_secs_f64()
+        * 1000.0;
+    assert!(duration_ms >= 20.0, "{duration_ms}");
+    assert!(
+        duration_ms <= span_duration_ms + 1.0,
+        "{duration_ms} {span_duration_ms}"
+    );
+    assert!(
+        span_duration_ms - duration_ms < 10.0,
+        "{duration_ms} {span_duration_ms}"
+    );
+}
+
+#[test]
+fn duration_attribute_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), "ok"));
+    server.get("/");
+    assert_eq!(
+        span_attribute(&telemetry.span(), "http.server.duration_ms"),
+        None
+    );
+}
+
+#[test]
+fn boolean_attribute() {
+    struct CacheHit;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_boolean_attribute("http.cache.hit", |conn| conn.state::<CacheHit>().is_some()),
+        |conn: Conn| async move {
+            if conn.path() == "/cached" {
+                conn.with_state(CacheHit).ok("cached")
+            } else {
+                conn.ok("ok")
+            }
+        },
+    ));
+
+    server.get("/cached");
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "http.cache.hit").as_deref(),
+        Some("true")
+    );
+    assert_eq!(
+        span_attribute(&spans[1], "http.cache.hit").as_deref(),
+        Some("false")
+    );
+}
+
+#[test]
+fn zipkin_compat() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_route(|conn| Some(conn.path().to_string().into()))
+            .zipkin_compat(),
+        "ok",
+    ));
+
+    server.get("/users");
+    let span = telemetry.span();
+    assert_eq!(span.name, "get /users");
+    assert_eq!(span_attribute(&span, "http.method").as_deref(), Some("GET"));
+    assert_eq!(
+        span_attribute(&span, "http.path").as_deref(),
+        Some("/users")
+    );
+    assert_eq!(
+        span_attribute(&span, "http.status_code").as_deref(),
+        Some("200")
+    );
+    assert_eq!(
+        span_attribute(&span, "http.request.method").as_deref(),
+        Some("GET")
+    );
+    assert_eq!(
+        span_attribute(&span, "http.response.status_code").as_deref(),
+        Some("200")
+    );
+}
+
+#[test]
+fn zipkin_compat_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_route(|conn| Some(conn.path().to_string().into())),
+        "ok",
+    ));
+
+    server.get("/users");
+    let span = telemetry.span();
+    assert_eq!(span.name, "GET /users");
+    assert_eq!(span_attribute(&span, "http.method"), None);
+    assert_eq!(span_attribute(&span, "http.status_code"), None);
+}
+
+#[test]
+fn tail_sampling() {
+    use std::time::Duration;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_tail_sampling(Duration::from_millis(20)),
+        |conn: Conn| async move {
+            match conn.path() {
+                "/slow" => {
+                    std::thread::sleep(Duration::from_millis(30));
+                    conn.ok("slow")
+                }
+                "/error" => conn.with_status(Status::InternalServerError),
+                _ => conn.ok("fast"),
+            }
+        },
+    ));
+
+    server.get("/fast");
+    assert!(telemetry.spans().is_empty());
+
+    server.get("/slow");
+    let span = telemetry.span();
+    assert_eq!(span_attribute(&span, "url.path").as_deref(), Some("/slow"));
+    assert!(span.end_time.duration_since(span.start_time).unwrap() >= Duration::from_millis(30));
+
+    server.get("/error");
+    let spans = telemetry.spans();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(
+        span_attribute(&spans[1], "url.path").as_deref(),
+        Some("/error")
+    );
+    assert_eq!(
+        span_attribute(&spans[1], "error.type").as_deref(),
+        Some("500")
+    );
+}
+
+#[test]
+fn correlation_id_header() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_correlation_id_header("x-request-id"),
+        "ok",
+    ));
+
+    let response = server.request("GET", "/", &[("X-Request-Id", "abc-123")]);
+    assert_eq!(response.header("x-request-id"), None);
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "trillium.correlation_id").as_deref(),
+        Some("abc-123")
+    );
+    assert_eq!(span_attribute(&spans[1], "trillium.correlation_id"), None);
+}
+
+#[test]
+fn correlation_id_generation() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_correlation_id_header("x-request-id")
+            .with_correlation_id_generation(),
+        "ok",
+    ));
+
+    let response = server.request("GET", "/", &[("X-Request-Id", "abc-123")]);
+    assert_eq!(response.header("x-request-id"), Some("abc-123"));
+
+    let response = server.get("/");
+    let spans = telemetry.spans();
+    let trace_id = spans[1].span_context.trace_id().to_string();
+    assert_eq!(response.header("x-request-id"), Some(&*trace_id));
+    assert_eq!(
+        span_attribute(&spans[0], "trillium.correlation_id").as_deref(),
+        Some("abc-123")
+    );
+    assert_eq!(
+        span_attribute(&spans[1], "trillium.correlation_id"),
+        Some(trace_id)
+    );
+}
+
+#[test]
+fn route_sampling() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_parent_trace_id_header("x-trace-id")
+            .with_route(|conn| match conn.path() {
+                "/checkout" | "/health" => Some(conn.path().to_string().into()),
+                _ => None,
+            })
+            .with_route_sampling(|route| match route {
+                "/checkout" => Some(1.0),
+                "/health" => Some(0.25),
+                _ => None,
+            }),
+        "ok",
+    ));
+
+    // a seeded xorshift, so the trace ids and therefore the sampling decisions are reproducible
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut trace_id = move || {
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        format!("{:016x}{:016x}", next(), next())
+    };
+
+    let requests = 400;
+    for _ in 0..requests {
+        for path in ["/checkout", "/health", "/"] {
+            server.request("GET", path, &[("X-Trace-Id", &trace_id())]);
+        }
+    }
+
+    let spans = telemetry.spans();
+    let sampled = |path: &str| {
+        spans
+            .iter()
+            .filter(|span| span_attribute(span, "url.path").as_deref() == Some(path))
+            .count()
+    };
+    assert_eq!(sampled("/checkout"), requests);
+    assert_eq!(sampled("/"), requests);
+    let health = sampled("/health");
+    assert!((60..140).contains(&health), "{health}");
+
+    // the decision is derived from the trace id, so it is the same for every request in a trace
+    for (trace_id, expected) in [
+        ("0123456789abcdef0000000000000000", 1),
+        ("0123456789abcdefffffffffffffffff", 0),
+    ] {
+        let count = telemetry.spans().len();
+        for _ in 0..3 {
+            server.request("GET", "/health", &[("X-Trace-Id", trace_id)]);
+        }
+        let spans = telemetry.spans();
+        assert_eq!(spans.len() - count, 3 * expected, "{trace_id}");
+        assert!(spans[count..]
+            .iter()
+            .all(|span| span.span_context.trace_id().to_string() == trace_id));
+    }
+}
+
+#[test]
+fn panic() {
+    use opentelemetry::trace::Status as SpanStatus;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use trillium_testing::prelude::*;
+    let telemetry = Telemetry::new();
+    let mut handler = (Trace::new(telemetry.tracer()), |conn: Conn| async move {
+        if conn.path() == "/panic" {
+            panic!("handler panic");
+        }
+        conn
+    });
+    init(&mut handler);
+
+    assert!(catch_unwind(AssertUnwindSafe(|| get("/panic").run(&handler))).is_err());
+    let span = telemetry.span();
+    assert_eq!(
+        span.status,
+        SpanStatus::Error {
+            description: "panic".into()
+        }
+    );
+    assert_eq!(
+        span_attribute(&span, "error.type").as_deref(),
+        Some("panic")
+    );
+}
+
+#[test]
+fn client_address() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_client_address(|conn| {
+            conn.request_headers()
+                .get_str("x-client-id")
+                .map(|client| client.to_string().into())
+        }),
+        "ok",
+    ));
+
+    server.request("GET", "/", &[("X-Client-Id", "client-7")]);
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "client.address").as_deref(),
+        Some("client-7")
+    );
+    assert_eq!(span_attribute(&spans[1], "client.address"), None);
+}
+
+#[test]
+fn client_address_defaults_to_peer_ip() {
+    use trillium_testing::prelude::*;
+    let telemetry = Telemetry::new();
+    let handler = (Trace::new(telemetry.tracer()), "ok");
+    get("/")
+        .with_peer_ip("10.1.2.3".parse().unwrap())
+        .with_request_header("x-client-id", "client-7")
+        .run(&handler);
+    assert_eq!(
+        span_attribute(&telemetry.span(), "client.address").as_deref(),
+        Some("10.1.2.3")
+    );
+}
+
+#[test]
+fn id_generator() {
+    use opentelemetry::trace::{SpanId, TraceId};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use trillium_opentelemetry::TraceConnExt;
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+    let span_id = SpanId::from_hex("00f067aa0ba902b7").unwrap();
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_id_generator(move || (trace_id, span_id)),
+        |conn: Conn| async move {
+            let mut headers = trillium::Headers::new();
+            conn.inject_into(&mut headers);
+            let traceparent = headers
+                .get_str("traceparent")
+                .unwrap_or_default()
+                .to_string();
+            conn.ok(traceparent)
+        },
+    ));
+
+    let response = server.get("/");
+    assert_eq!(
+        response.body,
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+    );
+    let span = telemetry.span();
+    assert_eq!(span.span_context.trace_id(), trace_id);
+    assert_eq!(span.span_context.span_id(), span_id);
+}
+
+#[test]
+fn late_route_event() {
+    use trillium_router::{router, RouterConnExt};
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_route(|conn| match conn.route() {
+                Some(route) => Some(route.to_string().into()),
+                // known before the router runs
+                None => (conn.path() == "/early").then_some("/early".into()),
+            })
+            .with_late_route_event(),
+        router().get("/users/:id", "ok").get("/early", "ok"),
+    ));
+
+    server.get("/users/1");
+    server.get("/early");
+    let spans = telemetry.spans();
+
+    let [late] = &spans[0].events.iter().collect::<Vec<_>>()[..] else {
+        panic!("expected one event, found {:?}", spans[0].events);
+    };
+    assert_eq!(late.name, "trillium.route.late_resolution");
+    assert_eq!(
+        common::attribute(&late.attributes, "http.route").as_deref(),
+        Some("/users/:id")
+    );
+    assert_eq!(spans[0].name, "GET /users/:id");
+
+    assert_eq!(spans[1].name, "GET /early");
+    assert!(spans[1].events.is_empty());
+}
+
+#[test]
+fn router_route() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_router_route(),
+        trillium_router::router().get("/users/:id", "ok"),
+    ));
+
+    server.get("/users/1");
+    server.get("/unrouted");
+    let spans = telemetry.spans();
+    assert_eq!(spans[0].name, "GET /users/:id");
+    assert_eq!(
+        span_attribute(&spans[0], "http.route").as_deref(),
+        Some("/users/:id")
+    );
+    assert_eq!(spans[1].name, "GET");
+    assert_eq!(span_attribute(&spans[1], "http.route"), None);
+}
+
+#[test]
+fn timeout_detection() {
+    use opentelemetry::trace::Status as SpanStatus;
+    struct TimedOut;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_error_type(|conn| {
+                let status = conn.status()?;
+                status.is_server_error().then_some("upstream".into())
+            })
+            .with_timeout_detection(|conn| conn.state::<TimedOut>().is_some()),
+        |conn: Conn| async move {
+            if conn.path() == "/slow" {
+                // as set by a timeout handler that stopped waiting on the rest of the chain
+                conn.with_state(TimedOut)
+                    .with_status(Status::ServiceUnavailable)
+                    .halt()
+            } else {
+                conn.ok("ok")
+            }
+        },
+    ));
+
+    server.get("/slow");
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "error.type").as_deref(),
+        Some("timeout")
+    );
+    assert!(matches!(spans[0].status, SpanStatus::Error { .. }));
+    assert_eq!(
+        span_attribute(&spans[0], "http.response.status_code").as_deref(),
+        Some("503")
+    );
+    assert_eq!(span_attribute(&spans[1], "error.type"), None);
+    assert_eq!(spans[1].status, SpanStatus::Unset);
+}
+
+#[test]
+fn operation_spans() {
+    use opentelemetry::trace::TraceContextExt;
+    use trillium_opentelemetry::TraceConnExt;
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), |conn: Conn| async move {
+        for item in ["first", "second"] {
+            let context = conn.new_operation_span(format!("process {item}")).unwrap();
+            context.span().end();
+        }
+        conn.ok("processed")
+    }));
+
+    server.get("/batch");
+    let request = telemetry.span_named("GET");
+    for name in ["process first", "process second"] {
+        let operation = telemetry.span_named(name);
+        assert_ne!(
+            operation.span_context.trace_id(),
+            request.span_context.trace_id()
+        );
+        assert_eq!(
+            operation.parent_span_id,
+            opentelemetry::trace::SpanId::INVALID
+        );
+        let links = &operation.links.links;
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].span_context, request.span_context);
+    }
+}
+
+#[test]
+fn operation_spans_require_trace() {
+    use trillium_opentelemetry::TraceConnExt;
+    let server = Server::new(|conn: Conn| async move {
+        let has_span = conn.new_operation_span("operation").is_some();
+        conn.ok(has_span.to_string())
+    });
+    assert_eq!(server.get("/").body, "false");
+}
+
+#[test]
+fn auth_scheme() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()).with_auth_scheme(), "ok"));
+
+    let cases = [
+        (Some("Bearer secret-token"), "bearer"),
+        (Some("basic c2VjcmV0OnNlY3JldA=="), "basic"),
+        (Some("secret-token"), "other"),
+        (Some("Custom secret-token"), "other"),
+        (None, "none"),
+    ];
+    for (authorization, _) in cases {
+        let headers = authorization
+            .map(|authorization| ("Authorization", authorization))
+            .into_iter()
+            .collect::<Vec<_>>();
+        server.request("GET", "/", &headers);
+    }
+
+    let spans = telemetry.spans();
+    for (span, (authorization, scheme)) in spans.iter().zip(cases) {
+        assert_eq!(
+            span_attribute(span, "http.request.auth.scheme").as_deref(),
+            Some(scheme),
+            "{authorization:?}"
+        );
+        for attribute in &span.attributes {
+            let value = attribute.value.to_string();
+            assert!(
+                !value.contains("secret") && !value.contains("c2VjcmV0"),
+                "{attribute:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn duration_attribute_granularity() {
+    use std::time::Duration;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_duration_attribute()
+            .with_duration_attribute_granularity(Duration::from_millis(10)),
+        |conn: Conn| async move {
+            if conn.path() == "/slow" {
+                std::thread::sleep(Duration::from_millis(25));
+            }
+            conn.ok("ok")
+        },
+    ));
+
+    server.get("/");
+    server.get("/slow");
+    let durations = telemetry
+        .spans()
+        .iter()
+        .map(|span| {
+            span_attribute(span, "http.server.duration_ms")
+                .unwrap()
+                .parse::<f64>()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert!(durations[1] >= 20.0, "{durations:?}");
+    for duration in durations {
+        assert_eq!(duration % 10.0, 0.0, "{duration}");
+    }
+}
+
+#[test]
+fn error_type_name() {
+    enum DatabaseError {
+        Timeout,
+    }
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_error_type_name::<DatabaseError>(),
+        |conn: Conn| async move {
+            if conn.path() == "/error" {
+                conn.with_state(DatabaseError::Timeout)
+                    .with_status(Status::ServiceUnavailable)
+            } else {
+                conn.ok("ok")
+            }
+        },
+    ));
+
+    server.get("/error");
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "error.type").as_deref(),
+        Some(std::any::type_name::<DatabaseError>())
+    );
+    assert!(std::any::type_name::<DatabaseError>().ends_with("DatabaseError"));
+    assert_eq!(span_attribute(&spans[1], "error.type"), None);
+}
+
+/// method, path, status and duration
+type LoggedFields = (String, String, u16, std::time::Duration);
+
+/// records the access log fields of the last request, as an access logger would
+struct AccessLogger<T> {
+    trace: Trace<T>,
+    fields: std::sync::Arc<std::sync::Mutex<Option<LoggedFields>>>,
+}
+
+#[trillium::async_trait]
+impl<T: Send + Sync + 'static> trillium::Handler for AccessLogger<T> {
+    async fn run(&self, conn: Conn) -> Conn {
+        conn
+    }
+
+    async fn before_send(&self, conn: Conn) -> Conn {
+        let fields = self.trace.access_log_fields(&conn);
+        *self.fields.lock().unwrap() = Some((
+            fields.method.to_string(),
+            fields.path.to_string(),
+            fields.status as u16,
+            fields.duration,
+        ));
+        conn
+    }
+}
+
+#[test]
+fn access_log_fields() {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let now = Arc::new(Mutex::new(start));
+    let fields = Arc::new(Mutex::new(None));
+    let telemetry = Telemetry::new();
+    let trace = Trace::new(telemetry.tracer()).with_clock({
+        let now = Arc::clone(&now);
+        move || *now.lock().unwrap()
+    });
+    let server = Server::new((
+        trace.clone(),
+        AccessLogger {
+            trace,
+            fields: Arc::clone(&fields),
+        },
+        move |conn: Conn| {
+            *now.lock().unwrap() += Duration::from_secs(5);
+            async move { conn.with_status(Status::Created).with_body("created") }
+        },
+    ));
+
+    server.request_with_body("POST", "/users?notify=true", &[], "{}");
+    let (method, path, status, duration) = fields.lock().unwrap().take().unwrap();
+    let span = telemetry.span();
+    assert_eq!(
+        span_attribute(&span, "http.request.method").as_deref(),
+        Some(&*method)
+    );
+    assert_eq!(span_attribute(&span, "url.path").as_deref(), Some(&*path));
+    assert_eq!(path, "/users");
+    assert_eq!(
+        span_attribute(&span, "http.response.status_code"),
+        Some(status.to_string())
+    );
+    assert_eq!(status, 201);
+    // the span starts when trillium started reading the request, slightly before the handler ran
+    assert!(duration >= Duration::from_secs(5));
+    assert_eq!(
+        span.end_time.duration_since(span.start_time).unwrap(),
+        duration
+    );
+}
+
+#[test]
+fn inject_into() {
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use trillium_opentelemetry::{instrument_handler, TraceConnExt};
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    /// makes a downstream request, responding with the headers it would send
+    struct Downstream;
+
+    #[trillium::async_trait]
+    impl trillium::Handler for Downstream {
+        async fn run(&self, conn: Conn) -> Conn {
+            let mut headers = trillium::Headers::new();
+            conn.inject_into(&mut headers);
+            let traceparent = headers
+                .get_str("traceparent")
+                .unwrap_or_default()
+                .to_string();
+            conn.ok(traceparent)
+        }
+
+        fn name(&self) -> std::borrow::Cow<'static, str> {
+            "downstream".into()
+        }
+    }
+
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()),
+        |conn: Conn| async move {
+            let mut headers = trillium::Headers::new();
+            conn.inject_into(&mut headers);
+            let traceparent = headers
+                .get_str("traceparent")
+                .unwrap_or_default()
+                .to_string();
+            conn.with_response_header("x-request-traceparent", traceparent)
+        },
+        instrument_handler(Downstream, telemetry.tracer()),
+    ));
+
+    let response = server.get("/");
+    let request = telemetry.span_named("GET");
+    let downstream = telemetry.span_named("downstream::run");
+    let traceparent = |span: &opentelemetry_sdk::export::trace::SpanData| {
+        format!(
+            "00-{}-{}-01",
+            span.span_context.trace_id(),
+            span.span_context.span_id()
+        )
+    };
+    assert_eq!(
+        response.header("x-request-traceparent"),
+        Some(&*traceparent(&request))
+    );
+    assert_eq!(response.body, traceparent(&downstream));
+}
+
+#[test]
+fn inject_into_requires_trace() {
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use trillium_opentelemetry::TraceConnExt;
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let server = Server::new(|conn: Conn| async move {
+        let mut headers = trillium::Headers::new();
+        conn.inject_into(&mut headers);
+        let injected = headers.iter().count();
+        conn.ok(injected.to_string())
+    });
+    assert_eq!(server.get("/").body, "0");
+}
+
+#[test]
+fn fallback_span_name() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_route(|conn| (conn.path() == "/routed").then_some("/routed".into()))
+            .with_fallback_span_name(|conn| {
+                let segment = conn.path().split('/').nth(1).unwrap_or_default();
+                format!("{} /{segment}", conn.method()).into()
+            }),
+        "ok",
+    ));
+
+    server.get("/users/123");
+    server.get("/routed");
+    server.get("/");
+    let names = telemetry
+        .spans()
+        .iter()
+        .map(|span| span.name.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["GET /users", "GET /routed", "GET /"]);
+}
+
+#[test]
+fn response_header_stats() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_response_header_stats(),
+        |conn: Conn| async move {
+            conn.with_response_header("x-one", "a")
+                .with_response_header("x-two", "bc")
+                .ok("ok")
+        },
+    ));
+
+    let response = server.get("/");
+    assert_eq!(response.header("x-two"), Some("bc"));
+    let span = telemetry.span();
+    // trillium's default `server` header, `x-one: a\r\n` and `x-two: bc\r\n`. `date` and
+    // `content-length` are added while sending
+    let server_header = response.header("server").unwrap();
+    assert_eq!(
+        span_attribute(&span, "http.response.header.count").as_deref(),
+        Some("3")
+    );
+    assert_eq!(
+        span_attribute(&span, "http.response.header.bytes"),
+        Some(("server: \r\n".len() + server_header.len() + 21).to_string())
+    );
+}
+
+#[test]
+fn parent_trace_id_header() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_parent_trace_id_header("x-trace-id"),
+        "ok",
+    ));
+
+    server.request(
+        "GET",
+        "/",
+        &[("X-Trace-Id", " 4bf92f3577b34da6a3ce929d0e0e4736 ")],
+    );
+    server.request("GET", "/", &[("X-Trace-Id", "not a trace id")]);
+    server.request(
+        "GET",
+        "/",
+        &[("X-Trace-Id", "00000000000000000000000000000000")],
+    );
+    server.get("/");
+
+    let spans = telemetry.spans();
+    assert_eq!(
+        spans[0].span_context.trace_id().to_string(),
+        "4bf92f3577b34da6a3ce929d0e0e4736"
+    );
+    assert_ne!(
+        spans[0].parent_span_id,
+        opentelemetry::trace::SpanId::INVALID
+    );
+    for span in &spans[1..] {
+        assert_ne!(
+            span.span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(span.parent_span_id, opentelemetry::trace::SpanId::INVALID);
+    }
+}
+
+#[test]
+fn is_sampled() {
+    use opentelemetry_sdk::trace::Sampler;
+    use trillium_opentelemetry::TraceConnExt;
+    for (sampler, sampled) in [(Sampler::AlwaysOn, "true"), (Sampler::AlwaysOff, "false")] {
+        let telemetry = Telemetry::with_sampler(sampler);
+        let server = Server::new((Trace::new(telemetry.tracer()), |conn: Conn| async move {
+            let sampled = conn.is_sampled().to_string();
+            conn.ok(sampled)
+        }));
+        assert_eq!(server.get("/").body, sampled);
+    }
+
+    let server = Server::new(|conn: Conn| async move {
+        let sampled = conn.is_sampled().to_string();
+        conn.ok(sampled)
+    });
+    assert_eq!(server.get("/").body, "false");
+}
+
+#[test]
+fn error_description() {
+    use opentelemetry::trace::Status as SpanStatus;
+    struct DatabaseError(String);
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_error_description(|conn| {
+            conn.state::<DatabaseError>()
+                .map(|DatabaseError(message)| message.clone().into())
+        }),
+        |conn: Conn| async move {
+            match conn.path() {
+                "/database" => conn
+                    .with_state(DatabaseError("connection refused".into()))
+                    .with_status(Status::InternalServerError),
+                _ => conn.with_status(Status::InternalServerError),
+            }
+        },
+    ));
+
+    server.get("/database");
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        spans[0].status,
+        SpanStatus::Error {
+            description: "connection refused".into()
+        }
+    );
+    assert_eq!(
+        spans[1].status,
+        SpanStatus::Error {
+            description: "".into()
+        }
+    );
+}
+
+#[test]
+fn upgrade_header() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_upgrade_header(),
+        |conn: Conn| async move { conn.with_status(Status::BadRequest).halt() },
+    ));
+
+    let response = server.request("GET", "/socket", &[("Upgrade", "WebSocket, h2c ,")]);
+    assert_eq!(response.status, 400);
+    server.get("/");
+    let spans = telemetry.spans();
+    assert_eq!(
+        span_attribute(&spans[0], "http.request.header.upgrade").as_deref(),
+        Some(r#"["websocket","h2c"]"#)
+    );
+    assert_eq!(
+        span_attribute(&spans[1], "http.request.header.upgrade"),
+        None
+    );
+}
+
+#[test]
+fn operation_name() {
+    use trillium::Method;
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_router_route()
+            .zipkin_compat()
+            .with_operation_name(|conn| match (conn.method(), conn.path()) {
+                (Method::Post, "/users") => Some("Users.create".into()),
+                _ => None,
+            }),
+        trillium_router::router()
+            .post("/users", "created")
+            .get("/users", "ok"),
+    ));
+
+    server.request("POST", "/users", &[]);
+    server.get("/users");
+    let names = telemetry
+        .spans()
+        .iter()
+        .map(|span| span.name.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Users.create", "get /users"]);
+    assert_eq!(
+        span_attribute(&telemetry.span_named("Users.create"), "http.route").as_deref(),
+        Some("/users")
+    );
+}
+
+#[test]
+fn datadog_compat() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer())
+            .with_router_route()
+            .datadog_compat(),
+        trillium_router::router().get("/users/:id", "ok"),
+    ));
+
+    server.get("/users/1");
+    server.get("/unrouted");
+    let spans = telemetry.spans();
+    let datadog = |span| {
+        (
+            span_attribute(span, "operation.name"),
+            span_attribute(span, "span.type"),
+            span_attribute(span, "resource.name"),
+        )
+    };
+    assert_eq!(
+        datadog(&spans[0]),
+        (
+            Some("http.server.request".to_string()),
+            Some("web".to_string()),
+            Some("GET /users/:id".to_string())
+        )
+    );
+    assert_eq!(
+        datadog(&spans[1]),
+        (
+            Some("http.server.request".to_string()),
+            Some("web".to_string()),
+            Some("GET".to_string())
+        )
+    );
+    assert_eq!(
+        span_attribute(&spans[0], "http.route").as_deref(),
+        Some("/users/:id")
+    );
+}
+
+#[test]
+fn datadog_compat_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), "ok"));
+
+    server.get("/");
+    let span = telemetry.span();
+    assert_eq!(span_attribute(&span, "operation.name"), None);
+    assert_eq!(span_attribute(&span, "resource.name"), None);
+}
+
+const SLOW_BODY_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// the duration of the request span for a response body that takes twice `SLOW_BODY_DELAY` to send
+fn slow_body_span_duration(end_before_body_send: bool) -> std::time::Duration {
+    let telemetry = Telemetry::new();
+    let mut trace = Trace::new(telemetry.tracer());
+    if end_before_body_send {
+        trace = trace.end_before_body_send();
+    }
+    let server = Server::new((trace, |conn: Conn| async move {
+        conn.ok(trillium::Body::new_streaming(
+            DelayedChunks::new(["first,", "second,", "third"], SLOW_BODY_DELAY),
+            None,
+        ))
+    }));
+
+    let response = server.get("/");
+    assert!(response.body.contains("third"), "{}", response.body);
+    let span = telemetry.span();
+    span.end_time.duration_since(span.start_time).unwrap()
+}
+
+#[test]
+fn span_ends_after_body_send() {
+    let duration = slow_body_span_duration(false);
+    assert!(duration >= SLOW_BODY_DELAY * 2, "{duration:?}");
+}
+
+#[test]
+fn end_before_body_send() {
+    let duration = slow_body_span_duration(true);
+    assert!(duration < SLOW_BODY_DELAY, "{duration:?}");
+}
+
+#[test]
+fn request_id() {
+    struct RequestId(String);
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_request_id(|conn| {
+            conn.state::<RequestId>()
+                .map(|RequestId(id)| id.clone().into())
+        }),
+        |conn: Conn| async move {
+            // set after the trace handler has run
+            match conn.path() {
+                "/with-id" => conn.with_state(RequestId("req-1".into())).ok("ok"),
+                _ => conn.ok("ok"),
+            }
+        },
+    ));
+
+    server.get("/with-id");
+    server.get("/without-id");
+    let request_ids = telemetry
+        .spans()
+        .iter()
+        .map(|span| span_attribute(span, "trillium.request.id"))
+        .collect::<Vec<_>>();
+    assert_eq!(request_ids, [Some("req-1".to_string()), None]);
+}
+
+#[test]
+fn keep_alive_requests_get_distinct_spans() {
+    use opentelemetry::trace::SpanId;
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), "ok"));
+
+    let response = server.raw(
+        "GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n\
+         GET /second HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(response.body.matches("ok").count(), 2, "{}", response.body);
+    let spans = telemetry.spans();
+    assert_eq!(
+        spans
+            .iter()
+            .map(|span| span_attribute(span, "url.path"))
+            .collect::<Vec<_>>(),
+        [Some("/first".to_string()), Some("/second".to_string())]
+    );
+    assert_ne!(
+        spans[0].span_context.span_id(),
+        spans[1].span_context.span_id()
+    );
+    assert_ne!(
+        spans[0].span_context.trace_id(),
+        spans[1].span_context.trace_id()
+    );
+    assert!(spans
+        .iter()
+        .all(|span| span.parent_span_id == SpanId::INVALID));
+}
+
+/// whether each request span is the child of, and in the same trace as, an unrelated span that
+/// is active in the current context. the second request continues a remote trace
+fn spans_built_in_ambient_context(
+    trace: fn(Trace<opentelemetry_sdk::trace::Tracer>) -> Trace<opentelemetry_sdk::trace::Tracer>,
+) -> Vec<(bool, bool)> {
+    use opentelemetry::{
+        trace::{Span, TraceContextExt, Tracer},
+        Context,
+    };
+    use trillium_testing::prelude::*;
+    let telemetry = Telemetry::new();
+    let handler = (
+        trace(Trace::new(telemetry.tracer()).with_parent_trace_id_header("x-trace-id")),
+        "ok",
+    );
+
+    let ambient = telemetry.tracer().start("ambient");
+    let ambient_context = ambient.span_context().clone();
+    let _guard = Context::current_with_span(ambient).attach();
+    // test conns run on this thread, where the ambient context is attached
+    get("/").run(&handler);
+    get("/")
+        .with_request_header("x-trace-id", "4bf92f3577b34da6a3ce929d0e0e4736")
+        .run(&handler);
+    telemetry
+        .spans()
+        .iter()
+        .map(|span| {
+            (
+                span.parent_span_id == ambient_context.span_id(),
+                span.span_context.trace_id() == ambient_context.trace_id(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn ambient_context_is_the_parent_by_default() {
+    assert_eq!(
+        spans_built_in_ambient_context(|trace| trace),
+        [(true, true), (false, false)]
+    );
+}
+
+#[test]
+fn root_context() {
+    assert_eq!(
+        spans_built_in_ambient_context(Trace::with_root_context),
+        [(false, false), (false, false)]
+    );
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn cpu_time() {
+    use std::time::{Duration, Instant};
+    let telemetry = Telemetry::new();
+    let server = Server::new((
+        Trace::new(telemetry.tracer()).with_cpu_time(),
+        |conn: Conn| async move {
+            let start = Instant::now();
+            let mut spins = 0u64;
+            while start.elapsed() < Duration::from_millis(50) {
+                spins = std::hint::black_box(spins.wrapping_add(1));
+            }
+            conn.ok("ok")
+        },
+    ));
+
+    server.get("/");
+    let cpu_time: f64 = span_attribute(&telemetry.span(), "process.cpu.time")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(cpu_time > 0.0, "{cpu_time}");
+}
+
+#[test]
+fn cpu_time_is_opt_in() {
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), "ok"));
+
+    server.get("/");
+    assert_eq!(span_attribute(&telemetry.span(), "process.cpu.time"), None);
+}
+
+#[test]
+fn max_attributes() {
+    const REQUIRED: [&str; 5] = [
+        "http.request.method",
+        "url.path",
+        "url.scheme",
+        "http.route",
+        "http.response.status_code",
+    ];
+    let attribute_keys = |max_attributes| {
+        let telemetry = Telemetry::new();
+        let server = Server::new((
+            Trace::new(telemetry.tracer())
+                .with_router_route()
+                .with_headers(["x-one", "x-two", "x-three"])
+                .with_max_attributes(max_attributes),
+            trillium_router::router().get("/users/:id", "ok"),
+        ));
+        server.request(
+            "GET",
+            "/users/1?query",
+            &[("x-one", "1"), ("x-two", "2"), ("x-three", "3")],
+        );
+        telemetry
+            .span()
+            .attributes
+            .iter()
+            .map(|kv| kv.key.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let unlimited = attribute_keys(usize::MAX);
+    assert!(unlimited.len() > REQUIRED.len() + 3, "{unlimited:?}");
+
+    let mut required_only = attribute_keys(0);
+    required_only.sort();
+    let mut required = REQUIRED.map(String::from).to_vec();
+    required.sort();
+    assert_eq!(required_only, required);
+
+    let limited = attribute_keys(REQUIRED.len() + 2);
+    assert_eq!(limited.len(), REQUIRED.len() + 2, "{limited:?}");
+    for key in REQUIRED {
+        assert!(limited.iter().any(|k| k == key), "{key} in {limited:?}");
+    }
+}
+
+#[test]
+fn span_status_override() {
+    use opentelemetry::trace::Status as SpanStatus;
+    use trillium_opentelemetry::SpanStatusOverride;
+    let telemetry = Telemetry::new();
+    let server = Server::new((Trace::new(telemetry.tracer()), |conn: Conn| async move {
+        match conn.path() {
+            "/degraded" => conn
+                .with_state(SpanStatusOverride(SpanStatus::error(
+                    "served stale results",
+                )))
+                .ok("stale results"),
+            "/expected-failure" => conn
+                .with_state(SpanStatusOverride(SpanStatus::Ok))
+                .with_status(500)
+                .halt(),
+            "/failure" => conn.with_status(500).halt(),
+            _ => conn.ok("ok"),
+        }
+    }));
+
+    for path in ["/degraded", "/expected-failure", "/failure", "/"] {
+        server.get(path);
+    }
+    let statuses = telemetry
+        .spans()
+        .into_iter()
+        .map(|span| span.status)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        [
+            SpanStatus::error("served stale results"),
+            SpanStatus::Ok,
+            SpanStatus::error(""),
+            SpanStatus::Unset,
+        ]
+    );
+}
+
+#[test]
+fn sorted_attributes() {
+    let attribute_keys = |sorted| {
+        let telemetry = Telemetry::new();
+        let mut trace = Trace::new(telemetry.tracer())
+            .with_route(|_| Some("/users/:id".into()))
+            .with_headers(["x-b", "x-a"]);
+        if sorted {
+            trace = trace.with_sorted_attributes();
+        }
+        let server = Server::new((trace, "ok"));
+        server.request(
+            "GET",
+            "/users/1?query",
+            &[("x-b", "b"), ("x-a", "a"), ("user-agent", "test")],
+        );
+        telemetry
+            .span()
+            .attributes
+            .iter()
+            .map(|kv| kv.key.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let unsorted = attribute_keys(false);
+    let sorted = attribute_keys(true);
+    assert!(!unsorted.is_sorted());
+
+    // the status code is recorded when the response is sent, after the sorted attributes that
+    // were known when the request started
+    let mut expected = unsorted;
+    expected.retain(|key| key != "http.response.status_code");
+    expected.sort();
+    expected.push("http.response.status_code".into());
+    assert_eq!(sorted, expected);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::{Server, Telemetry};
+use trillium_opentelemetry::exponential_size_histograms;
+
+#[test]
+fn size_histograms_are_exponential() {
+    let mut metrics = None;
+    let telemetry = Telemetry::with_meter_provider(|builder| {
+        let (meter_provider, size_metrics) = exponential_size_histograms(builder).unwrap();
+        metrics = Some(size_metrics);
+        meter_provider
+    });
+    let server = Server::new((metrics.unwrap(), |mut conn: trillium::Conn| async move {
+        let body = conn.request_body_string().await.unwrap();
+        conn.ok(body.repeat(1000))
+    }));
+
+    server.request_with_body("POST", "/", &[], "hello");
+
+    for (name, sum) in [
+        ("http.server.request.body.size", 5.0),
+        ("http.server.response.body.size", 5000.0),
+    ] {
+        let point = telemetry.point(name);
+        assert!(point.scale.is_some(), "{point:?}");
+        assert_eq!((point.count, point.value), (1, sum), "{point:?}");
+    }
+
+    let duration = telemetry.point("http.server.request.duration");
+    assert_eq!(duration.scale, None);
+    assert!(!duration.bounds.is_empty());
+
+    let scopes = telemetry.scopes();
+    assert_eq!(scopes.len(), 1);
+    assert_eq!(scopes[0].name(), "trillium-opentelemetry");
+    assert_eq!(scopes[0].version(), Some(env!("CARGO_PKG_VERSION")));
+    assert_eq!(
+        scopes[0].schema_url(),
+        Some("https://opentelemetry.io/schemas/1.29.0")
+    );
+}